@@ -1,66 +1,146 @@
+use crate::attributes::Attributes;
 use crate::compression::*;
-use crate::crypt::{decrypt, hash_string};
+use crate::crypt::{decrypt, detect_file_key, encrypt, hash_string};
+use crate::error::MpqError;
+use crate::het_bet::{read_bet_table, read_ext_table, BetEntry, HetTable, ID_BET, ID_HET};
+use crate::listfile::{parse_listfile, wildcard_match};
+use crate::manifest::{ArchiveInfo, ArchiveStats, FileEntry, Manifest};
+use crate::map_info::W3MapInfo;
+use crate::patch::PATCH_INFO_SIZE;
+use crate::signature::{self, Signature, WEAK_SIGNATURE_SIZE};
+use crate::wav;
+use crate::writer::{ArchiveWriter, KeptHash};
 use adler32::RollingAdler32;
 use byteorder::{ByteOrder, LittleEndian};
+use md5::{Digest, Md5};
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use rsa::RsaPublicKey;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fmt;
 use std::fs;
 use std::io::SeekFrom;
+use std::io::{self, Error, ErrorKind};
 use std::io::{prelude::*, Cursor};
-use std::io::{Error, ErrorKind};
 use std::mem;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 
 const HEADER_SIZE_V1: usize = 0x20;
-//const HEADER_SIZE_V2: usize = 0x2C;
-//const HEADER_SIZE_V3: usize = 0x44;
-//const HEADER_SIZE_V4: usize = 0xD0;
+const HEADER_SIZE_V2: usize = 0x2C;
+const HEADER_SIZE_V3: usize = 0x44;
+const HEADER_SIZE_V4: usize = 0xD0;
 const USER_HEADER_SIZE: usize = 16;
 
+// the md5 of a v4 header covers the header up to the md5 itself
+const HEADER_MD5_OFFSET: usize = 0xC0;
+const MD5_SIZE: usize = 16;
+
+// internal files that archivers usually leave out of the listfile
+const SPECIAL_FILES: [&str; 3] = ["(listfile)", "(attributes)", "(signature)"];
+
+// size of a hash or block table entry as stored
+const TABLE_ENTRY_SIZE: usize = 16;
+
+// block index of hash entries that were deleted, or never used and so end a probe
+const HASH_ENTRY_DELETED: u32 = 0xFFFFFFFE;
+const HASH_ENTRY_EMPTY: u32 = 0xFFFFFFFF;
+
+// largest sector size shift whose sector size still fits in a u32
+const MAX_SECTOR_SIZE_SHIFT: u16 = 22;
+
 const ID_MPQA: &[u8] = b"MPQ\x1A";
 const ID_MPQB: &[u8] = b"MPQ\x1B";
 
-const FILE_IMPLODE: u32 = 0x00000100; // implode method by pkware compression library
-const FILE_COMPRESS: u32 = 0x00000200; // compress methods by multiple methods
-const FILE_ENCRYPTED: u32 = 0x00010000; // file is encrypted
-const FILE_FIX_KEY: u32 = 0x00020000; // file decryption key is altered according to position of file in archive
-const FILE_PATCH_FILE: u32 = 0x00100000; // file is a patch file. file data begins with patchinfo struct
-const FILE_SINGLE_UNIT: u32 = 0x01000000; // file is stored as single unit
-const FILE_SECTOR_CRC: u32 = 0x04000000;
-const FILE_COMPRESS_MASK: u32 = 0x0000FF00;
+pub(crate) const FILE_IMPLODE: u32 = 0x00000100; // implode method by pkware compression library
+pub(crate) const FILE_COMPRESS: u32 = 0x00000200; // compress methods by multiple methods
+pub(crate) const FILE_ENCRYPTED: u32 = 0x00010000; // file is encrypted
+pub(crate) const FILE_FIX_KEY: u32 = 0x00020000; // file decryption key is altered according to position of file in archive
+pub(crate) const FILE_PATCH_FILE: u32 = 0x00100000; // file is a patch file. file data begins with patchinfo struct
+pub(crate) const FILE_SINGLE_UNIT: u32 = 0x01000000; // file is stored as single unit
+pub(crate) const FILE_DELETE_MARKER: u32 = 0x02000000; // file is a deletion marker, it hides the file in archives below a patch
+pub(crate) const FILE_SECTOR_CRC: u32 = 0x04000000;
+pub(crate) const FILE_EXISTS: u32 = 0x80000000; // set if file exists, reset when the file was deleted
+pub(crate) const FILE_COMPRESS_MASK: u32 = 0x0000FF00;
 
 #[derive(Debug)]
 struct Header {
     _magic: [u8; 4],
-    _header_size: u32,
-    _archive_size: u32,
-    _format_version: u16, // 0 = Original, 1 = Extended
+    header_size: u32,
+    archive_size: u32,
+    format_version: u16, // 0 = Original, 1 = Extended, 2 = v3, 3 = v4
     sector_size_shift: u16,
-    hash_table_offset: u32,
-    block_table_offset: u32,
+    hash_table_offset: u64,
+    block_table_offset: u64,
     hash_table_count: u32,
     block_table_count: u32,
     // Header v2
-    _extended_offset: u64,
-    _hash_table_offset_high: u16,
-    _block_table_offset_high: u16,
-    // ToDo: Header v3 and v4
+    extended_offset: u64,
+    // Header v3
+    bet_table_offset: u64,
+    het_table_offset: u64,
+    // Header v4, sizes of the tables as stored
+    hash_table_size: u64,
+    block_table_size: u64,
+    hi_block_table_size: u64,
+    het_table_size: u64,
+    bet_table_size: u64,
+    // md5 of the block, hash, hi-block, BET and HET tables as stored, then of the
+    // header up to this field
+    table_md5: [[u8; MD5_SIZE]; 6],
 }
 
 impl Header {
     pub fn new(src: &[u8; HEADER_SIZE_V1]) -> Header {
         Header {
             _magic: [src[0], src[1], src[2], src[3]],
-            _header_size: LittleEndian::read_u32(&src[0x04..]),
-            _archive_size: LittleEndian::read_u32(&src[0x08..]),
-            _format_version: LittleEndian::read_u16(&src[0x0C..]),
+            header_size: LittleEndian::read_u32(&src[0x04..]),
+            archive_size: LittleEndian::read_u32(&src[0x08..]),
+            format_version: LittleEndian::read_u16(&src[0x0C..]),
             sector_size_shift: LittleEndian::read_u16(&src[0x0E..]),
-            hash_table_offset: LittleEndian::read_u32(&src[0x10..]),
-            block_table_offset: LittleEndian::read_u32(&src[0x14..]),
+            hash_table_offset: u64::from(LittleEndian::read_u32(&src[0x10..])),
+            block_table_offset: u64::from(LittleEndian::read_u32(&src[0x14..])),
             hash_table_count: LittleEndian::read_u32(&src[0x18..]),
             block_table_count: LittleEndian::read_u32(&src[0x1C..]),
-            _extended_offset: 0,
-            _hash_table_offset_high: 0,
-            _block_table_offset_high: 0,
+            extended_offset: 0,
+            bet_table_offset: 0,
+            het_table_offset: 0,
+            hash_table_size: 0,
+            block_table_size: 0,
+            hi_block_table_size: 0,
+            het_table_size: 0,
+            bet_table_size: 0,
+            table_md5: [[0; MD5_SIZE]; 6],
+        }
+    }
+
+    // add the v2 fields that follow the v1 header, which hold the upper bits of
+    // offsets in archives larger than 4 GiB
+    fn read_v2(&mut self, src: &[u8; HEADER_SIZE_V2 - HEADER_SIZE_V1]) {
+        self.extended_offset = LittleEndian::read_u64(src);
+        self.hash_table_offset |= u64::from(LittleEndian::read_u16(&src[0x08..])) << 32;
+        self.block_table_offset |= u64::from(LittleEndian::read_u16(&src[0x0A..])) << 32;
+    }
+
+    // add the v3 fields, which locate the HET and BET tables
+    fn read_v3(&mut self, src: &[u8; HEADER_SIZE_V3 - HEADER_SIZE_V2]) {
+        self.bet_table_offset = LittleEndian::read_u64(&src[0x08..]);
+        self.het_table_offset = LittleEndian::read_u64(&src[0x10..]);
+    }
+
+    // add the v4 fields, the stored size of each table and their md5s
+    fn read_v4(&mut self, src: &[u8; HEADER_SIZE_V4 - HEADER_SIZE_V3]) {
+        self.hash_table_size = LittleEndian::read_u64(&src[0x00..]);
+        self.block_table_size = LittleEndian::read_u64(&src[0x08..]);
+        self.hi_block_table_size = LittleEndian::read_u64(&src[0x10..]);
+        self.het_table_size = LittleEndian::read_u64(&src[0x18..]);
+        self.bet_table_size = LittleEndian::read_u64(&src[0x20..]);
+
+        for (i, md5) in self.table_md5.iter_mut().enumerate() {
+            md5.copy_from_slice(&src[0x2C + i * MD5_SIZE..][..MD5_SIZE]);
         }
     }
 }
@@ -70,7 +150,7 @@ struct UserDataHeader {
     _magic: [u8; 4],
     user_data_size: u32,
     header_offset: u32,
-    _user_data_header_size: u32,
+    _user_dataheader_size: u32,
 }
 
 impl UserDataHeader {
@@ -79,7 +159,7 @@ impl UserDataHeader {
             _magic: [src[0], src[1], src[2], src[3]],
             user_data_size: LittleEndian::read_u32(&src[0x4..]),
             header_offset: LittleEndian::read_u32(&src[0x8..]),
-            _user_data_header_size: LittleEndian::read_u32(&src[0xC..]),
+            _user_dataheader_size: LittleEndian::read_u32(&src[0xC..]),
         }
     }
 }
@@ -91,7 +171,7 @@ struct Hash {
     /// file name hash part B
     hash_b: u32,
     /// language of file using windows LANGID type
-    _locale: u16,
+    locale: u16,
     /// platform file is used for
     _platform: u16,
     /// index into the block table of file
@@ -103,7 +183,7 @@ impl Hash {
         Hash {
             hash_a: LittleEndian::read_u32(src),
             hash_b: LittleEndian::read_u32(&src[4..]),
-            _locale: LittleEndian::read_u16(&src[8..]),
+            locale: LittleEndian::read_u16(&src[8..]),
             _platform: LittleEndian::read_u16(&src[10..]),
             block_index: LittleEndian::read_u32(&src[12..]),
         }
@@ -113,7 +193,7 @@ impl Hash {
 #[derive(Debug, Clone)]
 struct Block {
     /// offset of the beginning of the file data, relative to the beginning of the archive
-    offset: u32,
+    offset: u64,
     /// compressed file size
     packed_size: u32,
     /// uncompressed file size
@@ -125,397 +205,4737 @@ struct Block {
 impl Block {
     pub fn new(src: &[u8]) -> Block {
         Block {
-            offset: LittleEndian::read_u32(src),
+            offset: u64::from(LittleEndian::read_u32(src)),
             packed_size: LittleEndian::read_u32(&src[0x4..]),
             unpacked_size: LittleEndian::read_u32(&src[0x8..]),
             flags: LittleEndian::read_u32(&src[0xC..]),
         }
     }
+
+    // single unit files with FILE_SECTOR_CRC have one checksum after their data
+    fn has_appended_checksum(&self) -> bool {
+        self.flags & FILE_SINGLE_UNIT != 0
+            && self.flags & FILE_COMPRESS_MASK != 0
+            && self.flags & FILE_SECTOR_CRC != 0
+            && self.packed_size >= 4
+    }
+
+    // BET entries may hold sizes too large for the block table
+    fn from_bet(entry: &BetEntry) -> Result<Block, MpqError> {
+        let size =
+            |v: u64| u32::try_from(v).map_err(|_| MpqError::Corrupt("BET file size too large"));
+
+        Ok(Block {
+            offset: entry.offset,
+            packed_size: size(entry.packed_size)?,
+            unpacked_size: size(entry.unpacked_size)?,
+            flags: entry.flags,
+        })
+    }
 }
 
-pub struct Archive {
-    file: Cursor<Vec<u8>>,
+pub struct Archive<T = Vec<u8>> {
+    file: Cursor<T>,
     header: Header,
     user_data_header: Option<UserDataHeader>,
+    user_data_offset: u64,
     hash_table: Vec<Hash>,
+    // position in hash_table of each (hash_a, hash_b, locale)
+    hash_index: HashMap<(u32, u32, u16), usize>,
+    // position in hash_table of the first entry of each block
+    block_hashes: HashMap<u32, usize>,
     block_table: Vec<Block>,
     sector_size: u32,
     offset: u64,
+    // checked against single unit files as they are read, see set_verify
+    attributes: Option<Attributes>,
+    // locale preferred when a file exists in several
+    locale: u16,
+    // names supplied with apply_listfile and the block each resolved to
+    applied_names: Vec<(u32, String)>,
+    // HET table of v3 and v4 archives and the BET name hash of every block
+    het_table: Option<HetTable>,
+    bet_hashes: Vec<u64>,
+    // see set_decompressor
+    decompressors: Decompressors,
+    // see is_protected
+    protected: bool,
+}
+
+/// Settings for opening an [`Archive`]
+///
+/// Created by [`Archive::options`], the defaults match [`Archive::open`].
+#[derive(Debug, Clone, Default)]
+pub struct OpenOptions {
+    verify: bool,
+    base_offset: Option<u64>,
+    locale: u16,
+    sector_size: Option<u32>,
+}
+
+impl OpenOptions {
+    // verify single unit files against (attributes), see Archive::set_verify
+    pub fn verify(mut self, verify: bool) -> OpenOptions {
+        self.verify = verify;
+        self
+    }
+
+    // offset of the archive header instead of searching for it
+    pub fn base_offset(mut self, offset: u64) -> OpenOptions {
+        self.base_offset = Some(offset);
+        self
+    }
+
+    // locale to prefer over the neutral locale when opening files
+    pub fn default_locale(mut self, locale: u16) -> OpenOptions {
+        self.locale = locale;
+        self
+    }
+
+    // sector size to use instead of the one given by the header's sector size shift,
+    // for archives whose shift is wrong
+    pub fn sector_size(mut self, sector_size: u32) -> OpenOptions {
+        self.sector_size = Some(sector_size);
+        self
+    }
+
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Archive, MpqError> {
+        self.load(fs::read(path)?)
+    }
+
+    pub fn load<T: AsRef<[u8]>>(&self, buf: T) -> Result<Archive<T>, MpqError> {
+        let offset = match self.base_offset {
+            Some(v) => v,
+            None => Archive::<T>::find_header(buf.as_ref()).ok_or(MpqError::InvalidMagic)?,
+        };
+        let mut archive = Archive::load_with(buf, offset, self.sector_size)?;
+
+        archive.locale = self.locale;
+        archive.set_verify(self.verify)?;
+
+        Ok(archive)
+    }
 }
 
 impl Archive {
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Archive, Error> {
-        let mut file = fs::File::open(&path).expect("no file found");
-        let metadata = fs::metadata(&path).expect("unable to read metadata");
-        let mut buf = vec![0; metadata.len() as usize];
-        file.read_exact(&mut buf).expect("buffer overflow");
+    pub fn options() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Archive, MpqError> {
+        Self::load(fs::read(path)?)
+    }
+
+    // open archive whose header is at a known offset, e.g. embedded in an executable
+    pub fn open_at<P: AsRef<Path>>(path: P, offset: u64) -> Result<Archive, MpqError> {
+        Self::load_at(fs::read(path)?, offset)
+    }
+}
+
+impl<'a> Archive<&'a [u8]> {
+    // read archive straight from memory the caller already holds, without copying
+    pub fn from_slice(buf: &'a [u8]) -> Result<Archive<&'a [u8]>, MpqError> {
         Self::load(buf)
     }
+}
+
+#[cfg(feature = "mmap")]
+impl Archive<Mmap> {
+    // map archive into memory instead of reading it, sectors are then decompressed
+    // straight from the mapping
+    pub fn open_mmap<P: AsRef<Path>>(path: P) -> Result<Archive<Mmap>, MpqError> {
+        let file = fs::File::open(path)?;
+
+        // safety: the mapping is only read, the file must not be modified while mapped
+        let map = unsafe { Mmap::map(&file)? };
+
+        Self::load(map)
+    }
+}
+
+impl<T: AsRef<[u8]>> Archive<T> {
+    pub fn load(buf: T) -> Result<Self, MpqError> {
+        match Self::find_header(buf.as_ref()) {
+            Some(offset) => Self::load_at(buf, offset),
+            None => Err(MpqError::InvalidMagic),
+        }
+    }
+
+    // archives are normally aligned to 512 bytes, fall back to scanning every byte
+    // for archives embedded in map or executable files
+    fn find_header(buf: &[u8]) -> Option<u64> {
+        let last = buf.len().checked_sub(HEADER_SIZE_V1)?;
+        let is_header = |offset: &usize| {
+            let magic = &buf[*offset..*offset + ID_MPQA.len()];
+            magic == ID_MPQA || magic == ID_MPQB
+        };
+
+        (0..=last)
+            .step_by(0x200)
+            .find(is_header)
+            .or_else(|| (0..=last).find(is_header))
+            .map(|offset| offset as u64)
+    }
 
-    pub fn load(buf: Vec<u8>) -> Result<Archive, Error> {
+    pub fn load_at(buf: T, offset: u64) -> Result<Self, MpqError> {
+        Self::load_with(buf, offset, None)
+    }
+
+    // sector_size overrides the header's sector size shift, which is then not
+    // checked
+    fn load_with(buf: T, mut offset: u64, sector_size: Option<u32>) -> Result<Self, MpqError> {
         let mut buffer: [u8; HEADER_SIZE_V1] = [0; HEADER_SIZE_V1];
-        let mut offset: u64 = 0;
         let mut user_data_header = None;
+        let mut user_data_offset: u64 = 0;
         let mut file = Cursor::new(buf);
 
-        loop {
+        file.seek(SeekFrom::Start(offset))?;
+
+        file.read_exact(&mut buffer)?;
+
+        if buffer.starts_with(ID_MPQB) {
+            let header = UserDataHeader::new(&buffer);
+
+            user_data_offset = offset;
+            offset += u64::from(header.header_offset);
+
             file.seek(SeekFrom::Start(offset))?;
 
             file.read_exact(&mut buffer)?;
 
-            if buffer.starts_with(ID_MPQA) {
-                break;
-            }
+            user_data_header = Some(header);
+        }
 
-            if buffer.starts_with(ID_MPQB) {
-                let header = UserDataHeader::new(&buffer);
+        if !buffer.starts_with(ID_MPQA) {
+            return Err(MpqError::InvalidMagic);
+        }
 
-                offset += u64::from(header.header_offset);
+        let mut header = Header::new(&buffer);
 
-                file.seek(SeekFrom::Start(offset))?;
+        if header.format_version >= 1 && header.header_size as usize >= HEADER_SIZE_V2 {
+            let mut extended = [0; HEADER_SIZE_V2 - HEADER_SIZE_V1];
 
-                file.read_exact(&mut buffer)?;
+            file.read_exact(&mut extended)?;
+            header.read_v2(&extended);
+        }
 
-                if !buffer.starts_with(ID_MPQA) {
-                    return Err(Error::new(
-                        ErrorKind::InvalidData,
-                        "Not a valid MPQ archive",
-                    ));
-                }
+        if header.format_version >= 2 && header.header_size as usize >= HEADER_SIZE_V3 {
+            let mut extended = [0; HEADER_SIZE_V3 - HEADER_SIZE_V2];
 
-                user_data_header = Some(header);
+            file.read_exact(&mut extended)?;
+            header.read_v3(&extended);
+        }
 
-                break;
-            }
+        if header.format_version >= 3 && header.header_size as usize >= HEADER_SIZE_V4 {
+            let mut extended = [0; HEADER_SIZE_V4 - HEADER_SIZE_V3];
 
-            offset += 0x200;
+            file.read_exact(&mut extended)?;
+            header.read_v4(&extended);
         }
 
-        let header = Header::new(&buffer);
+        validate_header(&header)?;
+
+        let sector_size = match sector_size {
+            Some(v) => v,
+            None if header.sector_size_shift > MAX_SECTOR_SIZE_SHIFT => {
+                return Err(MpqError::Corrupt("Sector size too large"));
+            }
+            None => 512 << header.sector_size_shift,
+        };
+
+        // protectors claim tables larger than the archive, only the entries present
+        // are read. lookups treat hash entries that are missing as free
+        let available = file.get_ref().as_ref().len() as u64 - offset;
+        let present = |table_offset: u64, count: u32| {
+            (available.saturating_sub(table_offset) / TABLE_ENTRY_SIZE as u64).min(u64::from(count))
+                as usize
+        };
+        let hash_present = present(header.hash_table_offset, header.hash_table_count);
+        let block_present = present(header.block_table_offset, header.block_table_count);
+        let mut protected = hash_present < header.hash_table_count as usize
+            || block_present < header.block_table_count as usize;
+
+        // protectors also overlap the tables with each other
+        let hash_end = header.hash_table_offset + (hash_present * TABLE_ENTRY_SIZE) as u64;
+        let block_end = header.block_table_offset + (block_present * TABLE_ENTRY_SIZE) as u64;
+
+        if hash_present > 0
+            && block_present > 0
+            && header.hash_table_offset < block_end
+            && header.block_table_offset < hash_end
+        {
+            protected = true;
+        }
 
         // read hash table
-        let mut hash_buff: Vec<u8> =
-            vec![0; (header.hash_table_count as usize) * mem::size_of::<Hash>()];
-        let mut hash_table: Vec<Hash> = Vec::with_capacity(header.hash_table_count as usize);
+        let mut hash_buff: Vec<u8> = vec![0; hash_present * TABLE_ENTRY_SIZE];
+        let mut hash_table: Vec<Hash> = Vec::with_capacity(hash_present);
 
-        file.seek(SeekFrom::Start(
-            u64::from(header.hash_table_offset) + offset,
-        ))?;
+        file.seek(SeekFrom::Start(header.hash_table_offset + offset))?;
 
         file.read_exact(&mut hash_buff)?;
 
         decrypt(&mut hash_buff, hash_string("(hash table)", 0x300));
 
-        for x in 0..header.hash_table_count {
-            hash_table.push(Hash::new(&hash_buff[x as usize * mem::size_of::<Hash>()..]));
+        for x in 0..hash_present {
+            hash_table.push(Hash::new(&hash_buff[x * TABLE_ENTRY_SIZE..]));
         }
 
         // read block table
-        let mut block_buff: Vec<u8> =
-            vec![0; (header.block_table_count as usize) * mem::size_of::<Block>()];
-        let mut block_table: Vec<Block> = Vec::with_capacity(header.block_table_count as usize);
+        let mut block_buff: Vec<u8> = vec![0; block_present * TABLE_ENTRY_SIZE];
+        let mut block_table: Vec<Block> = Vec::with_capacity(block_present);
 
-        file.seek(SeekFrom::Start(
-            u64::from(header.block_table_offset) + offset,
-        ))?;
+        file.seek(SeekFrom::Start(header.block_table_offset + offset))?;
 
         file.read_exact(&mut block_buff)?;
 
         decrypt(&mut block_buff, hash_string("(block table)", 0x300));
 
-        for x in 0..header.block_table_count {
-            block_table.push(Block::new(
-                &block_buff[x as usize * mem::size_of::<Block>()..],
-            ));
+        for x in 0..block_present {
+            block_table.push(Block::new(&block_buff[x * TABLE_ENTRY_SIZE..]));
+        }
+
+        // v2 archives store the upper 16 bits of each file offset separately
+        if header.extended_offset != 0 {
+            let mut hi_block_buff: Vec<u8> = vec![0; block_table.len() * 2];
+
+            file.seek(SeekFrom::Start(
+                header.extended_offset.saturating_add(offset),
+            ))?;
+            file.read_exact(&mut hi_block_buff)?;
+
+            for (block, hi) in block_table.iter_mut().zip(hi_block_buff.chunks_exact(2)) {
+                block.offset |= u64::from(LittleEndian::read_u16(hi)) << 32;
+            }
+        }
+
+        // v3 and v4 archives may locate files through the HET and BET tables alone,
+        // the BET table then replaces the block table
+        let mut het_table = None;
+        let mut bet_hashes = Vec::new();
+
+        if header.het_table_offset != 0 && header.bet_table_offset != 0 {
+            let buf = file.get_ref().as_ref();
+            let table = |table_offset: u64| {
+                table_offset
+                    .checked_add(offset)
+                    .and_then(|start| usize::try_from(start).ok())
+                    .and_then(|start| buf.get(start..))
+                    .ok_or(MpqError::Corrupt(
+                        "Extended table extends past the end of the archive",
+                    ))
+            };
+
+            let het = read_ext_table(
+                table(header.het_table_offset)?,
+                header.het_table_size,
+                ID_HET,
+                hash_string("(hash table)", 0x300),
+            )?;
+            let bet = read_ext_table(
+                table(header.bet_table_offset)?,
+                header.bet_table_size,
+                ID_BET,
+                hash_string("(block table)", 0x300),
+            )?;
+            let entries = read_bet_table(&bet)?;
+
+            block_table = entries
+                .iter()
+                .map(Block::from_bet)
+                .collect::<Result<_, _>>()?;
+            bet_hashes = entries.iter().map(|e| e.name_hash).collect();
+            het_table = Some(HetTable::new(&het)?);
         }
 
-        let sector_size = 512 << header.sector_size_shift;
+        let hash_index = index_hash_table(&hash_table);
+        let block_hashes = index_blocks(&hash_table);
 
         Ok(Archive {
             file,
             header,
             user_data_header,
+            user_data_offset,
             hash_table,
+            hash_index,
+            block_hashes,
             block_table,
             sector_size,
             offset,
+            attributes: None,
+            locale: 0,
+            applied_names: Vec::new(),
+            het_table,
+            bet_hashes,
+            decompressors: Decompressors::new(),
+            protected,
         })
     }
 
-    pub fn open_file(&mut self, filename: &str) -> Result<File, Error> {
-        let start_index =
-            (hash_string(filename, 0x0) & (self.header.hash_table_count - 1)) as usize;
-        let mut hash;
+    pub fn open_file(&mut self, filename: &str) -> Result<File, MpqError> {
+        let hash = match self.find_hash(filename) {
+            Some(v) => v,
+            None => return Err(MpqError::FileNotFound(String::from(filename))),
+        };
+        // a corrupt hash entry may point past the block table
+        let block = match self.block_table.get(hash.block_index as usize) {
+            Some(v) if v.flags & FILE_DELETE_MARKER != 0 => {
+                return Err(MpqError::FileNotFound(String::from(filename)))
+            }
+            Some(v) => v.clone(),
+            None => return Err(MpqError::Corrupt("Block index out of range")),
+        };
+        let file_key = file_key(filename, &block)?;
+
+        self.open_block(filename, hash, block, Some(file_key))
+    }
+
+    // whether filename is in the hash table, without opening it
+    pub fn contains(&self, filename: &str) -> bool {
+        self.find_hash(filename).is_some() && !self.is_deleted(filename)
+    }
+
+    // whether filename resolves to a deletion marker, which patch archives use to
+    // remove a file from the archives below them in a chain
+    pub(crate) fn is_deleted(&self, filename: &str) -> bool {
+        self.find_hash(filename)
+            .and_then(|hash| self.block_table.get(hash.block_index as usize))
+            .is_some_and(|block| block.flags & FILE_DELETE_MARKER != 0)
+    }
+
+    // whether the archive has a (listfile), see list_files
+    pub fn has_listfile(&self) -> bool {
+        self.contains("(listfile)")
+    }
+
+    // whether the archive has an (attributes) file, see read_attributes
+    pub fn has_attributes(&self) -> bool {
+        self.contains("(attributes)")
+    }
 
+    // hash table entry of filename, preferring the default locale and then the
+    // neutral one. names found in the HET table have an entry made up for them
+    fn find_hash(&self, filename: &str) -> Option<Hash> {
         let hash_a = hash_string(filename, 0x100);
         let hash_b = hash_string(filename, 0x200);
-        let mut file_key = 0;
-
-        for i in start_index..self.hash_table.len() {
-            hash = &self.hash_table[i];
-
-            if hash.hash_a == hash_a && hash.hash_b == hash_b {
-                let block = &self.block_table[hash.block_index as usize];
-                let mut sector_offsets: Vec<u32> = Vec::new();
-                let mut sector_checksums: Vec<u32> = Vec::new();
-
-                // file if encrypted, generate decryption key
-                if block.flags & FILE_ENCRYPTED != 0 {
-                    match filename.split(&['\\', '/'][..]).last() {
-                        Some(basename) => file_key = hash_string(basename, 0x300),
-                        None => {
-                            return Err(Error::new(
-                                ErrorKind::Other,
-                                "Unable to extract filename from path",
-                            ));
-                        }
-                    }
 
-                    // fix decryption key
-                    if block.flags & FILE_FIX_KEY != 0 {
-                        file_key = (file_key + (block.offset)) ^ block.unpacked_size;
-                    }
-                }
+        if let Some(het_table) = &self.het_table {
+            if let Some(block_index) = het_table.find(filename, &self.bet_hashes) {
+                return Some(Hash {
+                    hash_a,
+                    hash_b,
+                    locale: 0,
+                    _platform: 0,
+                    block_index,
+                });
+            }
+        }
 
-                // block split into sectors, read sector offsets
-                if block.flags & FILE_SINGLE_UNIT == 0 {
-                    // FixMe: handle empty files, packed and unpacked size should be 0
+        for &locale in &[self.locale, 0] {
+            if let Some(&i) = self.hash_index.get(&(hash_a, hash_b, locale)) {
+                return Some(self.hash_table[i].clone());
+            }
+        }
 
-                    if block.unpacked_size == 0 || self.sector_size == 0 {
-                        return Err(Error::new(ErrorKind::UnexpectedEof, filename));
-                    }
+        // archives with only a HET table have an empty hash table
+        if self.hash_table.is_empty() {
+            return None;
+        }
 
-                    let num_sectors = ((block.unpacked_size - 1) / self.sector_size) + 1;
+        // other locales are found by probing the table from the name's start index,
+        // wrapping around its end, up to an entry that was never used
+        let count = self.header.hash_table_count as usize;
+        let start = hash_string(filename, 0x0) as usize & count.wrapping_sub(1);
 
-                    let mut sector_buff: Vec<u8> = vec![0; ((num_sectors as usize) + 1) * 4];
+        for i in (0..count).map(|i| (start + i) & (count - 1)) {
+            // entries past a truncated table are free
+            let hash = match self.hash_table.get(i) {
+                Some(v) if v.block_index != HASH_ENTRY_EMPTY => v,
+                _ => break,
+            };
 
-                    self.file
-                        .seek(SeekFrom::Start(u64::from(block.offset) + self.offset))?;
-                    self.file.read_exact(&mut sector_buff)?;
+            if hash.hash_a == hash_a
+                && hash.hash_b == hash_b
+                && hash.block_index < HASH_ENTRY_DELETED
+            {
+                return Some(hash.clone());
+            }
+        }
 
-                    if block.flags & FILE_ENCRYPTED != 0 {
-                        decrypt(&mut sector_buff, file_key - 1);
-                    }
+        None
+    }
 
-                    let mut x = 0;
-                    while x < sector_buff.len() - 3 {
-                        sector_offsets.push(LittleEndian::read_u32(&sector_buff[x..]));
-                        x += 4;
-                    }
+    // checksum of every sector from the len bytes at offset, which are compressed
+    // like a sector when smaller than the checksums. none are read if len is zero
+    fn read_sector_checksums(
+        &self,
+        offset: u64,
+        len: usize,
+        num_sectors: usize,
+    ) -> Result<Vec<u32>, MpqError> {
+        let expected_size = num_sectors * mem::size_of::<u32>();
 
-                    // load sector checksums
-                    if block.flags & FILE_COMPRESS != 0 && block.flags & FILE_SECTOR_CRC != 0 {
-                        let mut buff: Vec<u8> = vec![0; 4];
+        if len == 0 {
+            return Ok(Vec::new());
+        }
 
-                        self.file.read_exact(&mut buff)?;
+        if len > expected_size {
+            return Err(MpqError::Corrupt("Sector checksum table too large"));
+        }
 
-                        let last_offset = LittleEndian::read_u32(&buff);
-                        let checksum_offset = sector_offsets[num_sectors as usize];
-                        let sector_size = last_offset - checksum_offset;
-                        let expected_size = num_sectors * mem::size_of::<u32>() as u32;
+        let mut checksums = vec![0; expected_size];
+        let size = unpack(
+            Method::Compress,
+            self.slice(offset, len)?,
+            &mut checksums,
+            &mut Vec::new(),
+            &self.decompressors,
+        )?;
 
-                        // is checksum sector the expected size
-                        if sector_size == expected_size {
-                            self.file.seek(SeekFrom::Start(
-                                u64::from(block.offset) + u64::from(checksum_offset),
-                            ))?;
+        if size != expected_size {
+            return Err(MpqError::Corrupt("Sector checksum table too short"));
+        }
 
-                            for _ in 0..num_sectors {
-                                self.file.read_exact(&mut buff)?;
+        Ok(checksums
+            .chunks_exact(4)
+            .map(LittleEndian::read_u32)
+            .collect())
+    }
 
-                                sector_checksums.push(LittleEndian::read_u32(&buff));
-                            }
-                        }
-                    }
-                }
+    // open file by its position in the block table, the decryption key of encrypted
+    // files is recovered from the sector offset table
+    pub fn open_file_by_index(&mut self, block_index: u32) -> Result<File, MpqError> {
+        let block = match self.block_table.get(block_index as usize) {
+            Some(v) if v.flags & FILE_EXISTS != 0 && v.flags & FILE_DELETE_MARKER == 0 => v.clone(),
+            _ => return Err(MpqError::BlockNotFound(block_index)),
+        };
 
-                return Ok(File {
-                    _name: String::from(filename),
-                    _hash: hash.clone(),
-                    block: block.clone(),
-                    sector_offsets,
-                    sector_checksums,
-                    file_key,
-                });
+        let hash = match self.block_hash(block_index) {
+            Some(v) => v.clone(),
+            None => Hash {
+                hash_a: 0,
+                hash_b: 0,
+                locale: 0,
+                _platform: 0,
+                block_index,
+            },
+        };
+
+        // a name from apply_listfile also gives the decryption key
+        let applied = self
+            .applied_names
+            .iter()
+            .find(|(i, _)| *i == block_index)
+            .map(|(_, name)| name.clone());
+
+        if let Some(name) = applied {
+            match self.open_file(&name) {
+                Ok(file) if file.hash.block_index == block_index => return Ok(file),
+                _ => {}
+            }
+        }
+
+        let filename = format!("File{:08}.xxx", block_index);
+
+        self.open_block(&filename, hash, block, None)
+    }
+
+    // first hash table entry pointing at block_index
+    fn block_hash(&self, block_index: u32) -> Option<&Hash> {
+        self.block_hashes
+            .get(&block_index)
+            .map(|&i| &self.hash_table[i])
+    }
+
+    // open file by the hashes of its name, for tools that captured hashes rather
+    // than names. index is the unmasked HASH_TABLE_INDEX hash, the decryption key
+    // of encrypted files is recovered as by open_file_by_index
+    pub fn open_file_by_hash(
+        &mut self,
+        index: u32,
+        hash_a: u32,
+        hash_b: u32,
+        locale: u16,
+    ) -> Result<File, MpqError> {
+        let count = self.header.hash_table_count as usize;
+        let start = index as usize & count.wrapping_sub(1);
+
+        for i in (0..count).map(|i| (start + i) & (count - 1)) {
+            // entries past a truncated table are free
+            let hash = match self.hash_table.get(i) {
+                Some(v) if v.block_index != HASH_ENTRY_EMPTY => v,
+                _ => break,
+            };
+
+            if hash.hash_a == hash_a
+                && hash.hash_b == hash_b
+                && hash.locale == locale
+                && hash.block_index < HASH_ENTRY_DELETED
+            {
+                return self.open_file_by_index(hash.block_index);
             }
         }
 
-        Err(Error::new(ErrorKind::NotFound, filename))
+        Err(MpqError::FileNotFound(format!(
+            "{:08X}:{:08X}",
+            hash_a, hash_b
+        )))
+    }
+
+    // iterate every file that can be opened by block index, useful for archives
+    // without a listfile
+    pub fn files(&mut self) -> impl Iterator<Item = File> + '_ {
+        (0..self.block_table.len() as u32).filter_map(move |i| self.open_file_by_index(i).ok())
     }
 
-    pub fn read_user_data(&mut self) -> Result<Option<Vec<u8>>, Error> {
-        match self.user_data_header {
-            Some(ref header) => {
-                let mut buf: Vec<u8> = vec![0; header.user_data_size as usize];
+    // match candidate names against the hash table, returns the block index of
+    // each candidate found. useful for naming files of archives without a listfile
+    pub fn resolve_names(&mut self, candidates: &[&str]) -> Vec<(u32, String)> {
+        let mut resolved: Vec<(u32, String)> = Vec::new();
 
-                self.file.seek(SeekFrom::Start(USER_HEADER_SIZE as u64))?;
-                self.file.read_exact(&mut buf)?;
+        for &name in candidates {
+            let block_index = match self.find_hash(name) {
+                Some(hash) => hash.block_index,
+                None => continue,
+            };
 
-                Ok(Some(buf))
+            // names differing only in case resolve to the same block
+            if (block_index as usize) < self.block_table.len()
+                && !resolved.iter().any(|(i, _)| *i == block_index)
+            {
+                resolved.push((block_index, String::from(name)));
             }
-            None => Ok(None),
         }
+
+        resolved
     }
-}
 
-impl fmt::Debug for Archive {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{{\nfile: {:#?},\nheader: {:#?}\nsector_size:{}\n}}",
-            self.file, self.header, self.sector_size
-        )
+    fn open_block(
+        &mut self,
+        filename: &str,
+        hash: Hash,
+        mut block: Block,
+        file_key: Option<u32>,
+    ) -> Result<File, MpqError> {
+        let mut file_key = file_key;
+
+        // the data of a patch file follows its patch info, which gives its size
+        if block.flags & FILE_PATCH_FILE != 0 {
+            let info = self.slice(block.offset, PATCH_INFO_SIZE)?;
+            let len = LittleEndian::read_u32(info);
+
+            if (len as usize) < PATCH_INFO_SIZE || len > block.packed_size {
+                return Err(MpqError::Corrupt("Invalid patch info"));
+            }
+
+            block.offset += u64::from(len);
+            block.packed_size -= len;
+            block.unpacked_size = LittleEndian::read_u32(&info[0x08..]);
+        }
+        let mut sector_offsets: Vec<u32> = Vec::new();
+        let mut sector_checksums: Vec<u32> = Vec::new();
+
+        let encrypted = block.flags & FILE_ENCRYPTED != 0;
+
+        // an empty file has no sectors, whatever its offset table holds
+        if block.flags & FILE_SINGLE_UNIT == 0 && block.unpacked_size == 0 {
+            sector_offsets.push(0);
+        } else if block.flags & FILE_SINGLE_UNIT == 0 {
+            // block split into sectors, read sector offsets
+            if self.sector_size == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, filename).into());
+            }
+
+            let num_sectors = ((block.unpacked_size - 1) / self.sector_size) + 1;
+            let has_checksums =
+                block.flags & FILE_COMPRESS != 0 && block.flags & FILE_SECTOR_CRC != 0;
+
+            // checksum sector adds an extra entry to the offset table
+            let num_offsets = num_sectors as usize + if has_checksums { 2 } else { 1 };
+
+            // the table is bounds checked before it is copied, its size comes from
+            // the unpacked size the block claims
+            let mut sector_buff = self.slice(block.offset, num_offsets * 4)?.to_vec();
+
+            if encrypted {
+                let key = match file_key {
+                    Some(v) => v,
+                    // without a filename derive the key from the offset table, whose
+                    // first entry is the size of the table. a table without an entry
+                    // for the end of the checksums is one entry shorter
+                    None => match detect_file_key(
+                        &sector_buff,
+                        sector_buff.len() as u32,
+                        self.sector_size,
+                    )
+                    .or_else(|| {
+                        has_checksums
+                            .then(|| {
+                                detect_file_key(
+                                    &sector_buff,
+                                    sector_buff.len() as u32 - 4,
+                                    self.sector_size,
+                                )
+                            })
+                            .flatten()
+                    }) {
+                        Some(v) => v,
+                        None => return Err(MpqError::UnknownFileKey),
+                    },
+                };
+
+                decrypt(&mut sector_buff, key.wrapping_sub(1));
+
+                file_key = Some(key);
+            }
+
+            // some writers append the checksums without an offset table entry for
+            // their end, the table is then one entry shorter as its first offset
+            // tells. the remaining word belongs to the first sector
+            let table_size = num_sectors as usize * 4 + 4;
+
+            if has_checksums && LittleEndian::read_u32(&sector_buff) as usize == table_size {
+                sector_buff.truncate(table_size);
+            }
+
+            let listed_checksums = has_checksums && sector_buff.len() > table_size;
+
+            let mut x = 0;
+            while x < sector_buff.len() - 3 {
+                sector_offsets.push(LittleEndian::read_u32(&sector_buff[x..]));
+                x += 4;
+            }
+
+            // sectors of uncompressed files are located without the table
+            if block.flags & FILE_COMPRESS_MASK != 0 {
+                validate_sector_offsets(&sector_offsets, block.packed_size)?;
+            }
+
+            // load sector checksums, which run from the end of the last sector to
+            // the listed end or else the end of the block
+            if has_checksums {
+                let checksum_end = if listed_checksums {
+                    sector_offsets.pop().unwrap()
+                } else {
+                    block.packed_size
+                };
+                let checksum_offset = sector_offsets[num_sectors as usize];
+
+                sector_checksums = self.read_sector_checksums(
+                    block.offset + u64::from(checksum_offset),
+                    checksum_end.saturating_sub(checksum_offset) as usize,
+                    num_sectors as usize,
+                )?;
+            }
+        } else if encrypted && file_key.is_none() {
+            return Err(MpqError::UnknownFileKey);
+        } else if block.has_appended_checksum() {
+            let end = block.offset + u64::from(block.packed_size);
+
+            sector_checksums.push(LittleEndian::read_u32(self.slice(end - 4, 4)?));
+        }
+
+        Ok(File {
+            name: String::from(filename),
+            hash,
+            block,
+            sector_offsets,
+            sector_checksums,
+            sector_size: self.sector_size,
+            file_key: file_key.unwrap_or(0),
+        })
     }
-}
 
-#[derive(Debug)]
-pub struct File {
-    _name: String,
-    _hash: Hash,
-    block: Block,
-    sector_offsets: Vec<u32>,
-    sector_checksums: Vec<u32>,
-    file_key: u32,
-}
+    // extract every listed file below dest, spreading whole files across threads
+    // workers. returns the number of files extracted, files that are missing or
+    // fail to extract are skipped
+    #[cfg(feature = "parallel")]
+    pub fn extract_all_parallel<P: AsRef<Path>>(
+        &mut self,
+        dest: P,
+        threads: usize,
+    ) -> Result<usize, MpqError>
+    where
+        T: Sync,
+    {
+        let files: Vec<File> = self.iter_files().filter_map(|file| file.ok()).collect();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .map_err(Error::other)?;
+        let archive: &Archive<T> = self;
+        let dest = dest.as_ref();
 
-impl File {
-    pub fn size(&self) -> u32 {
-        self.block.unpacked_size
+        let count = pool.install(|| {
+            files
+                .par_iter()
+                .filter(|file| {
+                    local_path(dest, &file.name)
+                        .and_then(|path| file.create(archive, path, false))
+                        .is_ok()
+                })
+                .count()
+        });
+
+        Ok(count)
     }
 
-    // read data from file
-    pub fn read(&self, archive: &mut Archive, buf: &mut [u8]) -> Result<usize, Error> {
-        if self.block.flags & FILE_PATCH_FILE != 0 {
-            Err(Error::new(ErrorKind::Other, "Patch file not supported"))
-        } else if self.block.flags & FILE_SINGLE_UNIT != 0 {
-            // file is single block file
-            self.read_single_unit_file(
-                self.block.packed_size as usize,
-                &mut archive.file,
-                archive.offset,
-                buf,
-            )
-        } else {
-            // read as sector based MPQ file
-            self.read_sector_file(archive, buf)
+    // names listed in (listfile) followed by those added with apply_listfile, empty
+    // if the archive has neither
+    pub fn list_files(&mut self) -> Result<Vec<String>, MpqError> {
+        // only a missing listfile lists nothing, a listfile that fails to read must
+        // not pass for an empty archive
+        let mut names = match self.open_file("(listfile)") {
+            Ok(file) => parse_listfile(&file.read_to_vec(self)?),
+            Err(MpqError::FileNotFound(_)) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        for (_, name) in &self.applied_names {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
         }
+
+        Ok(names)
     }
 
-    fn read_sector_file(&self, archive: &mut Archive, out: &mut [u8]) -> Result<usize, Error> {
-        let mut buff: Vec<u8> = vec![0; archive.sector_size as usize];
-        let mut read: usize = 0;
+    // names from list_files matching pattern, in which * stands for any run of
+    // characters and ? for one. matching ignores case
+    pub fn find(&mut self, pattern: &str) -> Vec<String> {
+        let mut names = self.list_files().unwrap_or_default();
 
-        if self.block.flags & FILE_COMPRESS_MASK != 0 {
-            for i in 0..self.sector_offsets.len() - 1 {
-                let sector_offset = self.sector_offsets[i];
-                let sector_size = self.sector_offsets[i + 1] - sector_offset;
+        names.retain(|name| wildcard_match(pattern, name));
+        names
+    }
 
-                let in_buf: &mut [u8] = &mut buff[0..sector_size as usize];
-                let out_buf: &mut [u8] = &mut out[read..];
+    // names from list_files that resolve in the hash table, so a stale listfile
+    // doesn't report files that are gone. a listfile that can't be read lists nothing
+    pub fn valid_files(&mut self) -> Vec<String> {
+        let mut names = self.list_files().unwrap_or_default();
 
-                archive.file.seek(SeekFrom::Start(
-                    u64::from(self.block.offset) + u64::from(sector_offset) + archive.offset,
-                ))?;
+        names.retain(|name| self.contains(name));
+        names
+    }
 
-                archive.file.read_exact(in_buf)?;
+    // open every file named by list_files as it is iterated, names that can't be
+    // opened are yielded as errors. a listfile that can't be read is a single error
+    pub fn iter_files(&mut self) -> impl Iterator<Item = Result<File, MpqError>> + '_ {
+        let (names, error) = match self.list_files() {
+            Ok(v) => (v, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
 
-                if self.block.flags & FILE_ENCRYPTED != 0 {
-                    decrypt(in_buf, self.file_key + i as u32);
-                }
+        error
+            .into_iter()
+            .map(Err)
+            .chain(names.into_iter().map(move |name| self.open_file(&name)))
+    }
+
+    // name files of an archive that lacks a listfile, or whose listfile is
+    // incomplete. names that aren't in the archive are ignored
+    pub fn apply_listfile(&mut self, names: &[String]) {
+        let candidates: Vec<&str> = names.iter().map(String::as_str).collect();
 
-                // checksum verification
-                if !self.sector_checksums.is_empty() && self.sector_checksums[i] != 0 {
-                    let mut adler = RollingAdler32::from_value(0);
+        for (block_index, name) in self.resolve_names(&candidates) {
+            if !self.applied_names.iter().any(|(i, _)| *i == block_index) {
+                self.applied_names.push((block_index, name));
+            }
+        }
+    }
 
-                    adler.update_buffer(in_buf);
+    // describe every block, naming those found in the listfile. a missing or
+    // unreadable listfile or attributes file leaves names and crcs empty
+    pub fn manifest(&mut self) -> Manifest {
+        let mut names = self.list_files().unwrap_or_default();
+        let attributes = self.read_attributes().ok().flatten().unwrap_or_default();
 
-                    if self.sector_checksums[i] != adler.hash() {
-                        return Err(Error::new(ErrorKind::Other, "Sector checksum error"));
-                    }
-                }
+        names.extend(SPECIAL_FILES.iter().map(|name| String::from(*name)));
 
-                if self.block.flags & FILE_COMPRESS != 0 {
-                    if in_buf.len() == archive.sector_size as usize || in_buf.len() == out_buf.len()
-                    {
-                        for (dst, src) in out_buf.iter_mut().zip(in_buf) {
-                            *dst = *src;
-                            read += 1;
-                        }
-                    } else {
-                        read += decompress(in_buf, out_buf)?;
-                    }
-                } else if self.block.flags & FILE_IMPLODE != 0 {
-                    if in_buf.len() == archive.sector_size as usize || in_buf.len() == out_buf.len()
-                    {
-                        for (dst, src) in out_buf.iter_mut().zip(in_buf) {
-                            *dst = *src;
-                            read += 1;
-                        }
-                    } else {
-                        read += explode(in_buf, out_buf)?;
-                    }
-                }
+        let mut manifest = Manifest {
+            archive_size: self.header.archive_size,
+            sector_size: self.sector_size,
+            files: Vec::new(),
+        };
+
+        for (index, block) in self.block_table.iter().enumerate() {
+            if block.flags & FILE_EXISTS == 0 {
+                continue;
             }
-        } else {
-            archive.file.seek(SeekFrom::Start(
-                u64::from(self.block.offset) + archive.offset,
-            ))?;
-            archive.file.read_exact(out)?;
 
-            read = out.len();
+            let locale = self.block_hash(index as u32).map_or(0, |h| h.locale);
+
+            manifest.files.push(FileEntry {
+                name: None,
+                block_index: index as u32,
+                offset: block.offset,
+                packed_size: block.packed_size,
+                unpacked_size: block.unpacked_size,
+                flags: block.flags,
+                locale,
+                crc32: attributes.crc32.get(index).cloned().filter(|&v| v != 0),
+                compression: Vec::new(),
+                ratio: if block.unpacked_size == 0 {
+                    1.0
+                } else {
+                    block.packed_size as f32 / block.unpacked_size as f32
+                },
+            });
         }
 
-        Ok(read)
+        let positions: HashMap<u32, usize> = manifest
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, e)| (e.block_index, i))
+            .collect();
+
+        for name in names {
+            let entry = self
+                .find_hash(&name)
+                .and_then(|hash| positions.get(&hash.block_index))
+                .map(|&i| &mut manifest.files[i]);
+
+            if let Some(entry) = entry.filter(|e| e.name.is_none()) {
+                entry.name = Some(name);
+            }
+        }
+
+        // the sector table of an encrypted file is only found with the name or by
+        // recovering the key
+        for entry in &mut manifest.files {
+            let named = entry
+                .name
+                .as_ref()
+                .and_then(|name| self.open_file(name).ok());
+            let file = match named {
+                Some(v) if v.block_index() == entry.block_index => Some(v),
+                _ => self.open_file_by_index(entry.block_index).ok(),
+            };
+
+            if let Some(file) = file {
+                entry.compression = file.compression(self);
+            }
+        }
+
+        manifest
     }
 
-    fn read_single_unit_file(
-        &self,
-        buff_size: usize,
-        file: &mut Cursor<Vec<u8>>,
-        offset: u64,
-        out_buf: &mut [u8],
-    ) -> Result<usize, Error> {
-        let mut in_buff: Vec<u8> = vec![0; buff_size];
+    pub fn info(&self) -> ArchiveInfo {
+        ArchiveInfo {
+            format_version: self.header.format_version,
+            header_size: self.header.header_size,
+            offset: self.offset,
+            archive_size: self.header.archive_size,
+            sector_size: self.sector_size,
+            hash_table_offset: self.header.hash_table_offset,
+            hash_table_count: self.header.hash_table_count,
+            block_table_offset: self.header.block_table_offset,
+            block_table_count: self.header.block_table_count,
+            user_data_size: self.user_data_header.as_ref().map(|h| h.user_data_size),
+        }
+    }
+
+    // bytes spanned by the archive from its header to the end of whichever table or
+    // file comes last. the header's archive_size is only 32 bits and v1 archives
+    // don't always count the tables following the file data in it
+    pub fn computed_size(&self) -> u64 {
+        let header = &self.header;
+        let mut spans = vec![
+            (0, u64::from(header.header_size)),
+            (
+                header.hash_table_offset,
+                u64::from(header.hash_table_count) * 16,
+            ),
+            (
+                header.block_table_offset,
+                u64::from(header.block_table_count) * 16,
+            ),
+        ];
 
-        file.seek(SeekFrom::Start(u64::from(self.block.offset) + offset))?;
+        if header.extended_offset != 0 {
+            spans.push((
+                header.extended_offset,
+                u64::from(header.block_table_count) * 2,
+            ));
+        }
 
-        file.read_exact(&mut in_buff)?;
+        // v3 headers don't give the size of the HET and BET tables, it follows
+        // from their own header instead
+        for &(offset, size) in &[
+            (header.het_table_offset, header.het_table_size),
+            (header.bet_table_offset, header.bet_table_size),
+        ] {
+            if offset == 0 {
+                continue;
+            }
 
-        if self.block.flags & FILE_ENCRYPTED != 0 {
-            decrypt(&mut in_buff, self.file_key);
+            let size = match (size, self.slice(offset, 12)) {
+                (0, Ok(ext)) => 12 + u64::from(LittleEndian::read_u32(&ext[8..])),
+                (v, _) => v,
+            };
+
+            spans.push((offset, size));
         }
 
-        if self.block.flags & FILE_COMPRESS != 0 && out_buf.len() > in_buff.len() {
-            decompress(&mut in_buff, out_buf)
-        } else if self.block.flags & FILE_IMPLODE != 0 {
-            explode(&mut in_buff, out_buf)
-        } else {
-            for (dst, src) in out_buf.iter_mut().zip(&in_buff) {
-                *dst = *src
+        for block in &self.block_table {
+            if block.flags & FILE_EXISTS != 0 {
+                spans.push((block.offset, u64::from(block.packed_size)));
+            }
+        }
+
+        // tables of protected archives may claim more than there is
+        let available = self.file.get_ref().as_ref().len() as u64 - self.offset;
+
+        spans
+            .iter()
+            .filter(|&&(_, len)| len != 0)
+            .map(|&(offset, len)| offset.saturating_add(len))
+            .max()
+            .unwrap_or(0)
+            .min(available)
+    }
+
+    // check the tables of a v4 archive against the md5s in its header, computed over
+    // the tables as stored. a zeroed md5 wasn't computed by the writer and is
+    // skipped, as are earlier versions which have none
+    pub fn verify_tables(&self) -> Result<(), MpqError> {
+        let header = &self.header;
+
+        if header.format_version < 3 || (header.header_size as usize) < HEADER_SIZE_V4 {
+            return Ok(());
+        }
+
+        let tables = [
+            (
+                "block table",
+                header.block_table_offset,
+                header.block_table_size,
+            ),
+            (
+                "hash table",
+                header.hash_table_offset,
+                header.hash_table_size,
+            ),
+            (
+                "hi-block table",
+                header.extended_offset,
+                header.hi_block_table_size,
+            ),
+            ("BET table", header.bet_table_offset, header.bet_table_size),
+            ("HET table", header.het_table_offset, header.het_table_size),
+            ("header", 0, HEADER_MD5_OFFSET as u64),
+        ];
+
+        for (&(name, offset, size), md5) in tables.iter().zip(&header.table_md5) {
+            if *md5 == [0; MD5_SIZE] || size == 0 {
+                continue;
             }
 
-            Ok(self.block.unpacked_size as usize)
+            let len = usize::try_from(size).map_err(|_| MpqError::Corrupt("Table too large"))?;
+
+            if Md5::digest(self.slice(offset, len)?)[..] != md5[..] {
+                return Err(MpqError::TableMd5Mismatch(name));
+            }
         }
+
+        Ok(())
     }
 
-    // extract file from archive to the local filesystem
-    pub fn extract<P: AsRef<Path>>(&self, archive: &mut Archive, path: P) -> Result<usize, Error> {
-        let mut buf: Vec<u8> = vec![0; self.size() as usize];
+    // write the archive on its own to dest, without any user data or host file it
+    // is embedded in. returns the number of bytes written
+    pub fn carve<P: AsRef<Path>>(&mut self, dest: P) -> Result<u64, MpqError> {
+        let size = self.computed_size();
+        let len = usize::try_from(size).map_err(|_| MpqError::Corrupt("Archive too large"))?;
 
-        self.read(archive, &mut buf)?;
+        // table and block offsets are relative to the header, so the copy opens as is
+        fs::write(dest, self.slice(0, len)?)?;
 
-        fs::create_dir_all(path.as_ref().parent().unwrap())?;
+        Ok(size)
+    }
 
-        if path.as_ref().exists() {
-            return Err(Error::new(ErrorKind::AlreadyExists, "File already exists"));
+    // rewrite the archive to dest with its live blocks back to back. blocks are
+    // copied as stored, keeping their compression, encryption and flags, only
+    // FILE_FIX_KEY files are re-encrypted for their new offset. every hash table
+    // entry that finds a file is kept at its position with its locale, others are
+    // marked deleted so probing still reaches the entries past them. deletion
+    // markers, the files they hide, (attributes) and (signature) which describe the
+    // old layout are dropped. returns the number of blocks copied
+    pub fn compact<P: AsRef<Path>>(&mut self, dest: P) -> Result<usize, MpqError> {
+        // sector offset tables of the copied blocks are laid out for this size
+        if self.sector_size < 512 || !self.sector_size.is_power_of_two() {
+            return Err(MpqError::Corrupt("Invalid sector size"));
         }
 
-        let mut file = fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(&path)
-            .unwrap();
+        let mut names = self.list_files()?;
+        names.extend(["(listfile)", "(attributes)", "(signature)"].map(String::from));
+
+        // name of each block, from the entries of every locale that name it
+        let by_hash: HashMap<(u32, u32), &String> = names
+            .iter()
+            .map(|name| ((hash_string(name, 0x100), hash_string(name, 0x200)), name))
+            .collect();
+        let mut block_names: HashMap<u32, String> = HashMap::new();
 
-        file.write(&buf)
+        for name in &names {
+            if let Some(hash) = self.find_hash(name) {
+                block_names
+                    .entry(hash.block_index)
+                    .or_insert_with(|| name.clone());
+            }
+        }
+
+        for hash in &self.hash_table {
+            if let Some(&name) = by_hash.get(&(hash.hash_a, hash.hash_b)) {
+                block_names
+                    .entry(hash.block_index)
+                    .or_insert_with(|| name.clone());
+            }
+        }
+
+        let live = |i: u32| {
+            let dropped = block_names.get(&i).is_some_and(|name| {
+                ["(ATTRIBUTES)", "(SIGNATURE)"].contains(&name.to_uppercase().as_str())
+            });
+
+            !dropped
+                && self.block_table.get(i as usize).is_some_and(|block| {
+                    block.flags & FILE_EXISTS != 0 && block.flags & FILE_DELETE_MARKER == 0
+                })
+        };
+
+        // entries probing finds a live file at, shadowed duplicates are dead
+        let kept_slots: HashSet<usize> = self
+            .hash_table
+            .iter()
+            .enumerate()
+            .filter(|&(i, hash)| {
+                hash.block_index < HASH_ENTRY_DELETED
+                    && self
+                        .hash_index
+                        .get(&(hash.hash_a, hash.hash_b, hash.locale))
+                        == Some(&i)
+                    && live(hash.block_index)
+            })
+            .map(|(i, _)| i)
+            .collect();
+
+        // blocks no entry points at are kept as files() finds them by index
+        let blocks: Vec<u32> = (0..self.block_table.len() as u32)
+            .filter(|&i| {
+                live(i)
+                    && (!self.block_hashes.contains_key(&i)
+                        || kept_slots
+                            .iter()
+                            .any(|&slot| self.hash_table[slot].block_index == i))
+            })
+            .collect();
+
+        // without a hash table to keep files are found by name alone
+        let het_only = self.hash_table.is_empty();
+        let count = self.header.hash_table_count as usize;
+
+        if !het_only && (!count.is_power_of_two() || count < self.hash_table.len()) {
+            return Err(MpqError::Corrupt("Hash table size is not a power of two"));
+        }
+
+        // everything that can fail is checked before dest is created, which keys
+        // moved FILE_FIX_KEY files need is one of them
+        let mut fixed = HashMap::new();
+
+        for &i in &blocks {
+            let block = self.block_table[i as usize].clone();
+            let name = block_names.get(&i).map(String::as_str);
+
+            if het_only && name.is_none() {
+                return Err(MpqError::Corrupt("Block has no name to find it by"));
+            }
+
+            self.slice(block.offset, block.packed_size as usize)?;
+
+            if block.flags & FILE_ENCRYPTED != 0 && block.flags & FILE_FIX_KEY != 0 {
+                let file = match name {
+                    Some(name) => {
+                        let hash = Hash {
+                            hash_a: 0,
+                            hash_b: 0,
+                            locale: 0,
+                            _platform: 0,
+                            block_index: i,
+                        };
+                        let key = file_key(name, &block)?;
+
+                        self.open_block(name, hash, block.clone(), Some(key))?
+                    }
+                    None => self.open_file_by_index(i)?,
+                };
+                let end = block.offset + u64::from(block.packed_size);
+
+                if file
+                    .sectors()
+                    .iter()
+                    .any(|sector| sector.offset + u64::from(sector.packed_size) > end)
+                {
+                    return Err(MpqError::Corrupt("Sector past the end of its block"));
+                }
+
+                fixed.insert(i, file);
+            }
+        }
+
+        let mut writer = ArchiveWriter::create(dest)?;
+        let mut new_index = HashMap::new();
+
+        writer.set_sector_size_shift((self.sector_size.trailing_zeros() - 9) as u16);
+
+        for &i in &blocks {
+            let block = &self.block_table[i as usize];
+            let name = block_names.get(&i).map(String::as_str);
+            let mut data = self
+                .slice(block.offset, block.packed_size as usize)?
+                .to_vec();
+            let offset = writer.next_offset()?;
+
+            if let Some(file) = fixed.get(&i) {
+                rekey(file, block, &mut data, offset);
+            }
+
+            let index = writer.add_raw(name, het_only, &data, block.unpacked_size, block.flags)?;
+            new_index.insert(i, index);
+        }
+
+        if !het_only {
+            let kept = self
+                .hash_table
+                .iter()
+                .enumerate()
+                .filter(|(_, hash)| hash.block_index != HASH_ENTRY_EMPTY)
+                .map(|(slot, hash)| KeptHash {
+                    slot,
+                    hash_a: hash.hash_a,
+                    hash_b: hash.hash_b,
+                    locale: hash.locale,
+                    platform: hash._platform,
+                    block_index: match kept_slots.contains(&slot) {
+                        true => new_index[&hash.block_index],
+                        false => HASH_ENTRY_DELETED,
+                    },
+                })
+                .collect();
+
+            writer.keep_hash_table(count, kept);
+        }
+
+        writer.finish()?;
+
+        Ok(blocks.len())
+    }
+
+    // count files and their sizes, see manifest for how files are named
+    pub fn stats(&mut self) -> ArchiveStats {
+        let manifest = self.manifest();
+        let mut stats = ArchiveStats {
+            block_count: self.block_table.len() as u32,
+            files: manifest.files.len() as u32,
+            ..ArchiveStats::default()
+        };
+
+        for entry in &manifest.files {
+            if entry.name.is_some() {
+                stats.named += 1;
+            }
+
+            if entry.flags & FILE_ENCRYPTED != 0 {
+                stats.encrypted += 1;
+            }
+
+            if entry.flags & FILE_COMPRESS_MASK != 0 {
+                stats.compressed += 1;
+            }
+
+            stats.packed_size += u64::from(entry.packed_size);
+            stats.unpacked_size += u64::from(entry.unpacked_size);
+        }
+
+        stats
+    }
+
+    // open and extract a file in one call, see File::extract
+    pub fn extract_file<P: AsRef<Path>>(&mut self, name: &str, dest: P) -> Result<usize, MpqError> {
+        let file = self.open_file(name)?;
+
+        file.extract(self, dest)
+    }
+
+    pub fn read_attributes(&mut self) -> Result<Option<Attributes>, MpqError> {
+        let file = match self.open_file("(attributes)") {
+            Ok(v) => v,
+            Err(MpqError::FileNotFound(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let buf = file.read_to_vec(self)?;
+
+        Attributes::new(&buf, self.block_table.len()).map(Some)
+    }
+
+    // check single unit files against the md5 in (attributes) whenever they are
+    // read, they have no sector checksums of their own. does nothing for archives
+    // without an (attributes) file
+    pub fn set_verify(&mut self, verify: bool) -> Result<(), MpqError> {
+        self.attributes = None;
+
+        if verify {
+            self.attributes = self.read_attributes()?;
+        }
+
+        Ok(())
+    }
+
+    // decompress sectors whose compression mask byte is mask with f instead of the
+    // built in methods, f is given the data following the mask byte
+    pub fn set_decompressor(&mut self, mask: u8, f: Decompressor) {
+        self.decompressors.insert(mask, f);
+    }
+
+    // verify the weak digital signature stored in (signature)
+    pub fn verify_signature(&mut self) -> Result<Signature, MpqError> {
+        self.verify_weak_signature(&signature::blizzard_weak_key())
+    }
+
+    pub(crate) fn verify_weak_signature(
+        &mut self,
+        key: &RsaPublicKey,
+    ) -> Result<Signature, MpqError> {
+        let file = match self.open_file("(signature)") {
+            Ok(v) => v,
+            Err(MpqError::FileNotFound(_)) => return Ok(Signature::Unsigned),
+            Err(e) => return Err(e),
+        };
+
+        let buf = file.read_to_vec(self)?;
+
+        if buf.len() < 8 + WEAK_SIGNATURE_SIZE {
+            return Err(MpqError::Corrupt("Signature file too short"));
+        }
+
+        let start = self.offset as usize;
+        let end = start + self.header.archive_size as usize;
+        let exclude_start = file.block.offset as usize;
+        let exclude_end = exclude_start + file.block.packed_size as usize;
+        let data = self.file.get_ref().as_ref();
+
+        if end > data.len() || exclude_end > end - start {
+            return Err(MpqError::Corrupt("Archive size exceeds file length"));
+        }
+
+        Ok(signature::verify_weak(
+            key,
+            &data[start..end],
+            (exclude_start, exclude_end),
+            &buf[8..8 + WEAK_SIGNATURE_SIZE],
+        ))
+    }
+
+    // borrow len bytes at offset, relative to the start of the archive
+    fn slice(&self, offset: u64, len: usize) -> Result<&[u8], MpqError> {
+        let data = self.file.get_ref().as_ref();
+        let range = self
+            .offset
+            .checked_add(offset)
+            .and_then(|start| usize::try_from(start).ok())
+            .and_then(|start| Some(start..start.checked_add(len)?));
+
+        match range.and_then(|range| data.get(range)) {
+            Some(v) => Ok(v),
+            None => Err(Error::from(ErrorKind::UnexpectedEof).into()),
+        }
+    }
+
+    // whether the hash or block table was found to extend past the end of the
+    // archive or to overlap the other, tricks protectors use against editors.
+    // tables are read as far as they go, files may still fail to open
+    pub fn is_protected(&self) -> bool {
+        self.protected
+    }
+
+    // whether the archive follows a MPQ\x1B user data header
+    pub fn has_user_data(&self) -> bool {
+        self.user_data_header.is_some()
+    }
+
+    // size of the user data region, zero without a user data header
+    pub fn user_data_size(&self) -> u32 {
+        self.user_data_header
+            .as_ref()
+            .map_or(0, |header| header.user_data_size)
+    }
+
+    // the user_data_size bytes following the user data header, which must end
+    // before the archive header
+    pub fn read_user_data(&mut self) -> Result<Option<Vec<u8>>, MpqError> {
+        let header = match self.user_data_header {
+            Some(ref v) => v,
+            None => return Ok(None),
+        };
+
+        let start = self.user_data_offset as usize + USER_HEADER_SIZE;
+        let end = start + header.user_data_size as usize;
+
+        if end as u64 > self.offset {
+            return Err(MpqError::Corrupt("User data overlaps the archive header"));
+        }
+
+        Ok(Some(self.file.get_ref().as_ref()[start..end].to_vec()))
+    }
+
+    // parse the header Warcraft III maps store in front of the archive, None if
+    // there isn't one. see read_user_data for raw user data
+    pub fn read_w3_map_info(&self) -> Result<Option<W3MapInfo>, MpqError> {
+        let start = match self.user_data_header {
+            Some(_) => self.user_data_offset,
+            None => self.offset,
+        };
+
+        W3MapInfo::new(&self.file.get_ref().as_ref()[..start as usize])
+    }
+}
+
+// check the header against the size of the data following it before any table
+// is allocated
+fn validate_header(header: &Header) -> Result<(), MpqError> {
+    if header.format_version > 3 {
+        return Err(MpqError::UnsupportedFormatVersion(header.format_version));
+    }
+
+    // v3 and v4 archives may have no hash table at all
+    if header.hash_table_count != 0 && !header.hash_table_count.is_power_of_two() {
+        return Err(MpqError::Corrupt("Hash table size is not a power of two"));
+    }
+
+    Ok(())
+}
+
+// sector offsets must not decrease and must stay within the packed file
+fn validate_sector_offsets(offsets: &[u32], packed_size: u32) -> Result<(), MpqError> {
+    if offsets.windows(2).any(|w| w[0] > w[1]) {
+        return Err(MpqError::Corrupt("Sector offsets out of order"));
+    }
+
+    match offsets.last() {
+        Some(&last) if last > packed_size => {
+            Err(MpqError::Corrupt("Sector offset past the end of the file"))
+        }
+        _ => Ok(()),
+    }
+}
+
+// map every used hash table entry to its position, keeping the first of duplicates
+fn index_hash_table(hash_table: &[Hash]) -> HashMap<(u32, u32, u16), usize> {
+    let mut index = HashMap::with_capacity(hash_table.len());
+
+    for (i, hash) in hash_table.iter().enumerate() {
+        if hash.block_index < HASH_ENTRY_DELETED {
+            index
+                .entry((hash.hash_a, hash.hash_b, hash.locale))
+                .or_insert(i);
+        }
+    }
+
+    index
+}
+
+// decryption key of the file filename names stored in block, 0 if it isn't encrypted
+fn file_key(filename: &str, block: &Block) -> Result<u32, MpqError> {
+    if block.flags & FILE_ENCRYPTED == 0 {
+        return Ok(0);
+    }
+
+    // the key is derived from the basename, an empty one gives a wrong key
+    let mut key = match filename.split(&['\\', '/'][..]).next_back() {
+        Some(basename) if !basename.is_empty() => hash_string(basename, 0x300),
+        _ => return Err(MpqError::InvalidFileName(String::from(filename))),
+    };
+
+    // fix decryption key, block offsets are relative to the header so the key
+    // doesn't depend on where the archive is embedded
+    if block.flags & FILE_FIX_KEY != 0 {
+        key = (key.wrapping_add(block.offset as u32)) ^ block.unpacked_size;
+    }
+
+    Ok(key)
+}
+
+// re-encrypt data, the stored block of the FILE_FIX_KEY file file, for the key it
+// has once stored at new_offset. the sectors were checked to lie within data
+fn rekey(file: &File, block: &Block, data: &mut [u8], new_offset: u32) {
+    let unpacked_size = block.unpacked_size;
+    let key = (file.file_key ^ unpacked_size)
+        .wrapping_sub(block.offset as u32)
+        .wrapping_add(new_offset)
+        ^ unpacked_size;
+    let mut rekey = |offset: u64, len: u32, old_key: u32, new_key: u32| {
+        let start = (offset - block.offset) as usize;
+        let region = &mut data[start..start + len as usize];
+
+        decrypt(region, old_key);
+        encrypt(region, new_key);
+    };
+
+    if block.flags & FILE_SINGLE_UNIT == 0 && block.flags & FILE_COMPRESS_MASK != 0 {
+        rekey(
+            file.block.offset,
+            file.sector_offsets[0],
+            file.file_key.wrapping_sub(1),
+            key.wrapping_sub(1),
+        );
+    }
+
+    for (i, sector) in file.sectors().iter().enumerate() {
+        let new_key = if block.flags & FILE_SINGLE_UNIT != 0 {
+            key
+        } else {
+            key.wrapping_add(i as u32)
+        };
+
+        rekey(
+            sector.offset,
+            sector.packed_size,
+            file.sector_key(i),
+            new_key,
+        );
+    }
+}
+
+// map every block to the position of the first hash table entry pointing at it
+fn index_blocks(hash_table: &[Hash]) -> HashMap<u32, usize> {
+    let mut index = HashMap::new();
+
+    for (i, hash) in hash_table.iter().enumerate() {
+        if hash.block_index < HASH_ENTRY_DELETED {
+            index.entry(hash.block_index).or_insert(i);
+        }
+    }
+
+    index
+}
+
+impl<T> fmt::Debug for Archive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{{\noffset: {},\nheader: {:#?}\nsector_size:{}\n}}",
+            self.offset, self.header, self.sector_size
+        )
+    }
+}
+
+impl<T> fmt::Display for Archive<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let files = self
+            .block_table
+            .iter()
+            .filter(|block| block.flags & FILE_EXISTS != 0)
+            .count();
+
+        write!(
+            f,
+            "MPQ v{}, {} files, {} byte sectors, {} bytes",
+            self.header.format_version + 1,
+            files,
+            self.sector_size,
+            self.header.archive_size
+        )
+    }
+}
+
+// buffers reused between the sectors of a file, holding the decrypted sector
+// and the intermediate output of chained decompression
+#[derive(Default)]
+struct SectorBuffers {
+    sector: Vec<u8>,
+    scratch: Vec<u8>,
+}
+
+// sectors of a file as borrowed from the archive, along with the decompressors
+// registered on it
+#[derive(Clone, Copy)]
+struct Region<'a> {
+    data: &'a [u8],
+    decompressors: &'a Decompressors,
+}
+
+#[derive(Debug, Clone)]
+pub struct File {
+    name: String,
+    hash: Hash,
+    block: Block,
+    sector_offsets: Vec<u32>,
+    sector_checksums: Vec<u32>,
+    sector_size: u32,
+    file_key: u32,
+}
+
+// the alternate form {:#} is a row of the CLI's long listing: size, packed size,
+// ratio, flags and name in columns
+impl fmt::Display for File {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ratio = if self.size() == 0 {
+            100.0
+        } else {
+            self.packed_size() as f64 * 100.0 / self.size() as f64
+        };
+
+        let flags: String = [
+            (self.is_compressed(), 'c'),
+            (self.is_encrypted(), 'e'),
+            (self.is_single_unit(), 's'),
+        ]
+        .iter()
+        .map(|&(set, c)| if set { c } else { '-' })
+        .collect();
+
+        if f.alternate() {
+            return write!(
+                f,
+                "{:>10} {:>10} {:>5.1}% {:5} {}",
+                self.size(),
+                self.packed_size(),
+                ratio,
+                flags,
+                self.name
+            );
+        }
+
+        write!(
+            f,
+            "{}: {} bytes, {} packed ({:.1}%), {}",
+            self.name,
+            self.size(),
+            self.packed_size(),
+            ratio,
+            flags
+        )
+    }
+}
+
+/// Location of one sector of a [`File`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectorInfo {
+    /// offset of the sector data, relative to the beginning of the archive
+    pub offset: u64,
+    /// size of the sector as stored in the archive
+    pub packed_size: u32,
+    /// size of the sector once decompressed
+    pub unpacked_size: u32,
+}
+
+impl File {
+    // name the file was opened with, a placeholder if opened by block index
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // index of the file's entry in the block table
+    pub fn block_index(&self) -> u32 {
+        self.hash.block_index
+    }
+
+    // hash_a and hash_b of the hash table entry the file was found by
+    pub fn name_hash(&self) -> (u32, u32) {
+        (self.hash.hash_a, self.hash.hash_b)
+    }
+
+    pub fn size(&self) -> u64 {
+        u64::from(self.block.unpacked_size)
+    }
+
+    // size of the file as stored in the archive
+    pub fn packed_size(&self) -> u64 {
+        u64::from(self.block.packed_size)
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.block.flags & FILE_COMPRESS_MASK != 0
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.block.flags & FILE_ENCRYPTED != 0
+    }
+
+    pub fn is_single_unit(&self) -> bool {
+        self.block.flags & FILE_SINGLE_UNIT != 0
+    }
+
+    // whether the file holds a patch for the same file in a lower archive of a chain
+    pub fn is_patch_file(&self) -> bool {
+        self.block.flags & FILE_PATCH_FILE != 0
+    }
+
+    // compression of every sector, FILE_COMPRESS takes precedence over FILE_IMPLODE
+    // should a block have both
+    fn method(&self) -> Method {
+        if self.block.flags & FILE_COMPRESS != 0 {
+            Method::Compress
+        } else if self.block.flags & FILE_IMPLODE != 0 {
+            Method::Implode
+        } else {
+            Method::Stored
+        }
+    }
+
+    // layout of the file's sectors, single unit files are one sector
+    pub fn sectors(&self) -> Vec<SectorInfo> {
+        let offset = self.block.offset;
+        let size = self.block.unpacked_size;
+
+        if self.block.flags & FILE_SINGLE_UNIT != 0 {
+            let checksum_size = if self.block.has_appended_checksum() {
+                4
+            } else {
+                0
+            };
+
+            return vec![SectorInfo {
+                offset,
+                packed_size: self.block.packed_size - checksum_size,
+                unpacked_size: size,
+            }];
+        }
+
+        let unpacked_size = |i: u32| {
+            size.saturating_sub(i * self.sector_size)
+                .min(self.sector_size)
+        };
+
+        // uncompressed sectors are stored back to back without an offset table
+        if self.block.flags & FILE_COMPRESS_MASK == 0 {
+            let count = if size == 0 {
+                0
+            } else {
+                (size - 1) / self.sector_size + 1
+            };
+
+            return (0..count)
+                .map(|i| SectorInfo {
+                    offset: offset + u64::from(i * self.sector_size),
+                    packed_size: unpacked_size(i),
+                    unpacked_size: unpacked_size(i),
+                })
+                .collect();
+        }
+
+        self.sector_offsets
+            .windows(2)
+            .zip(0..)
+            .map(|(pair, i)| SectorInfo {
+                offset: offset + u64::from(pair[0]),
+                packed_size: pair[1] - pair[0],
+                unpacked_size: unpacked_size(i),
+            })
+            .collect()
+    }
+
+    // bytes of sector index as stored, decrypted but still compressed. single unit
+    // files are one sector
+    pub fn read_raw_sector<T: AsRef<[u8]>>(
+        &self,
+        archive: &mut Archive<T>,
+        index: usize,
+    ) -> Result<Vec<u8>, MpqError> {
+        let sector = match self.sectors().get(index) {
+            Some(v) => *v,
+            None => return Err(MpqError::SectorNotFound(index)),
+        };
+        let mut buf = archive
+            .slice(sector.offset, sector.packed_size as usize)?
+            .to_vec();
+
+        if self.block.flags & FILE_ENCRYPTED != 0 {
+            decrypt(&mut buf, self.sector_key(index));
+        }
+
+        Ok(buf)
+    }
+
+    fn sector_key(&self, index: usize) -> u32 {
+        if self.block.flags & FILE_SINGLE_UNIT != 0 {
+            self.file_key
+        } else {
+            self.file_key.wrapping_add(index as u32)
+        }
+    }
+
+    // methods the first packed sector was compressed with, taken from its mask
+    // byte. empty if every sector is stored
+    pub(crate) fn compression<T: AsRef<[u8]>>(
+        &self,
+        archive: &Archive<T>,
+    ) -> Vec<CompressionMethod> {
+        let method = self.method();
+
+        if method == Method::Stored {
+            return Vec::new();
+        }
+
+        let (index, sector) = match self
+            .sectors()
+            .into_iter()
+            .enumerate()
+            .find(|(_, s)| s.packed_size < s.unpacked_size)
+        {
+            Some(v) => v,
+            None => return Vec::new(),
+        };
+
+        // imploded sectors have no mask byte
+        if method == Method::Implode {
+            return vec![CompressionMethod::Implode];
+        }
+
+        self.sector_mask(archive, index, sector)
+            .map_or_else(Vec::new, CompressionMethod::from_mask)
+    }
+
+    // mask byte leading a packed sector
+    fn sector_mask<T: AsRef<[u8]>>(
+        &self,
+        archive: &Archive<T>,
+        index: usize,
+        sector: SectorInfo,
+    ) -> Option<u8> {
+        // a whole u32 is needed to decrypt the mask byte
+        let len = sector.packed_size.min(4) as usize;
+        let mut head = archive.slice(sector.offset, len).ok()?.to_vec();
+
+        if self.block.flags & FILE_ENCRYPTED != 0 {
+            decrypt(&mut head, self.sector_key(index));
+        }
+
+        head.first().copied()
+    }
+
+    // channel count of a wave file from the adpcm method its sectors are packed
+    // with, the first sector holding the header is packed without it
+    fn adpcm_channels<T: AsRef<[u8]>>(&self, archive: &Archive<T>) -> Option<u16> {
+        if self.block.flags & FILE_COMPRESS == 0 {
+            return None;
+        }
+
+        self.sectors()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, s)| s.packed_size < s.unpacked_size)
+            .find_map(|(i, sector)| {
+                let methods = CompressionMethod::from_mask(self.sector_mask(archive, i, sector)?);
+
+                if methods.contains(&CompressionMethod::AdpcmStereo) {
+                    Some(2)
+                } else if methods.contains(&CompressionMethod::AdpcmMono) {
+                    Some(1)
+                } else {
+                    None
+                }
+            })
+    }
+
+    // verify file contents against the crc32 and md5 stored in (attributes)
+    pub fn verify<T: AsRef<[u8]>>(
+        &self,
+        archive: &mut Archive<T>,
+        attributes: &Attributes,
+    ) -> Result<(), MpqError> {
+        let buf = self.read_to_vec(archive)?;
+
+        let index = self.hash.block_index as usize;
+
+        // a zero value means the archiver did not compute the digest
+        if let Some(&crc32) = attributes.crc32.get(index) {
+            if crc32 != 0 && crc32 != crc32fast::hash(&buf) {
+                return Err(MpqError::Crc32Mismatch);
+            }
+        }
+
+        if let Some(md5) = attributes.md5.get(index) {
+            if *md5 != [0; 16] && md5[..] != Md5::digest(&buf)[..] {
+                return Err(MpqError::Md5Mismatch);
+            }
+        }
+
+        Ok(())
+    }
+
+    // decode the whole file without keeping it, sectors are checked and decompressed
+    // one at a time into a single scratch sector. fails on the first bad sector
+    pub fn check<T: AsRef<[u8]>>(&self, archive: &mut Archive<T>) -> Result<(), MpqError> {
+        self.write_to(archive, &mut io::sink())?;
+
+        Ok(())
+    }
+
+    // crc32 of the decompressed contents, as stored in (attributes)
+    pub fn crc32<T: AsRef<[u8]>>(&self, archive: &mut Archive<T>) -> Result<u32, MpqError> {
+        let mut hasher = Crc32Writer(crc32fast::Hasher::new());
+
+        self.extract_to(archive, &mut hasher)?;
+
+        Ok(hasher.0.finalize())
+    }
+
+    // read data from file into buf, which must hold at least size() bytes. returns
+    // the number of bytes decoded, always equal to size()
+    pub fn read<T: AsRef<[u8]>>(
+        &self,
+        archive: &mut Archive<T>,
+        buf: &mut [u8],
+    ) -> Result<usize, MpqError> {
+        self.read_shared(archive, buf)
+    }
+
+    // read only borrows the archive's buffer, so files may be read from several
+    // threads at once
+    fn read_shared<T: AsRef<[u8]>>(
+        &self,
+        archive: &Archive<T>,
+        buf: &mut [u8],
+    ) -> Result<usize, MpqError> {
+        if self.block.flags & FILE_PATCH_FILE != 0 {
+            return Err(MpqError::PatchFileUnsupported);
+        }
+
+        self.read_data(archive, buf)
+    }
+
+    // the PTCH data of a patch file, which is applied to the file it patches
+    pub(crate) fn read_patch<T: AsRef<[u8]>>(
+        &self,
+        archive: &mut Archive<T>,
+    ) -> Result<Vec<u8>, MpqError> {
+        let mut buf: Vec<u8> = vec![0; self.size() as usize];
+
+        self.read_data(archive, &mut buf)?;
+
+        Ok(buf)
+    }
+
+    fn read_data<T: AsRef<[u8]>>(
+        &self,
+        archive: &Archive<T>,
+        buf: &mut [u8],
+    ) -> Result<usize, MpqError> {
+        let read = if self.block.flags & FILE_SINGLE_UNIT != 0 {
+            // file is single block file
+            self.read_single_unit_file(archive, buf)?
+        } else {
+            // read as sector based MPQ file
+            self.read_sector_file(archive, buf)?
+        };
+
+        // a short or corrupt stream may decode to fewer bytes than the block claims
+        let expected = self.block.unpacked_size as usize;
+
+        if read != expected {
+            return Err(MpqError::SizeMismatch(expected, read));
+        }
+
+        Ok(read)
+    }
+
+    // read the whole file into a new buffer, trimmed to the bytes actually read
+    pub fn read_to_vec<T: AsRef<[u8]>>(
+        &self,
+        archive: &mut Archive<T>,
+    ) -> Result<Vec<u8>, MpqError> {
+        let mut buf: Vec<u8> = vec![0; self.size() as usize];
+        let len = self.read(archive, &mut buf)?;
+
+        buf.truncate(len);
+
+        Ok(buf)
+    }
+
+    // read the whole file without trusting its unpacked size, which some protectors
+    // falsify. each sector may decode to a full sector whatever the size claims and
+    // the output grows as sectors are decoded, reading fails if it would exceed
+    // max_size bytes
+    pub fn read_to_end<T: AsRef<[u8]>>(
+        &self,
+        archive: &mut Archive<T>,
+        max_size: usize,
+    ) -> Result<Vec<u8>, MpqError> {
+        let method = self.method();
+
+        if self.block.flags & FILE_PATCH_FILE != 0 {
+            return Err(MpqError::PatchFileUnsupported);
+        }
+
+        if self.block.flags & FILE_SINGLE_UNIT != 0 {
+            let in_buff = self.single_unit_data(archive)?;
+
+            // a file compressed to its own size was stored
+            if method == Method::Stored || in_buff.len() == self.block.unpacked_size as usize {
+                if in_buff.len() > max_size {
+                    return Err(MpqError::SizeMismatch(max_size, in_buff.len()));
+                }
+
+                return Ok(in_buff.into_owned());
+            }
+
+            // the claimed size isn't trusted for the buffer either, it starts from
+            // the packed size and doubles whenever the data decodes past its end
+            let mut capacity = (in_buff.len() * 2)
+                .max(archive.sector_size as usize)
+                .min(max_size);
+
+            loop {
+                let mut out = vec![0; capacity];
+
+                match unpack(
+                    method,
+                    &in_buff,
+                    &mut out,
+                    &mut Vec::new(),
+                    &archive.decompressors,
+                ) {
+                    Ok(read) => {
+                        out.truncate(read);
+
+                        return Ok(out);
+                    }
+                    Err(e) if is_output_overflow(&e) && capacity < max_size => {
+                        capacity = capacity.saturating_mul(2).min(max_size);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        if self.block.flags & FILE_COMPRESS_MASK == 0 {
+            let len = self.block.packed_size as usize;
+
+            if len > max_size {
+                return Err(MpqError::SizeMismatch(max_size, len));
+            }
+
+            return Ok(archive.slice(self.block.offset, len)?.to_vec());
+        }
+
+        let sector_size = archive.sector_size as usize;
+        let region = self.sector_region(archive)?;
+        let mut buffers = SectorBuffers::default();
+        let mut out = Vec::new();
+
+        for i in 0..self.sector_offsets.len() - 1 {
+            let sector_len = self.sector_offsets[i + 1] - self.sector_offsets[i];
+            let claimed = (self.block.unpacked_size as usize)
+                .saturating_sub(i * sector_size)
+                .min(sector_size);
+
+            // a sector the size the block claims is stored, otherwise it may decode
+            // to anything up to a full sector
+            let capacity = if sector_len as usize == claimed {
+                claimed
+            } else {
+                sector_size
+            };
+            let start = out.len();
+
+            out.resize(start + capacity.min(max_size - start), 0);
+
+            let read = self.decode_sector(i, region, &mut out[start..], &mut buffers)?;
+
+            out.truncate(start + read);
+        }
+
+        Ok(out)
+    }
+
+    // read the whole file, decoding sectors that fail their checksum anyway instead
+    // of giving up. returns the data together with the indices of the bad sectors,
+    // bad sectors that can't be decoded are left zeroed
+    pub fn read_lossy<T: AsRef<[u8]>>(
+        &self,
+        archive: &mut Archive<T>,
+    ) -> Result<(Vec<u8>, Vec<usize>), MpqError> {
+        if self.sector_checksums.is_empty() {
+            return Ok((self.read_to_vec(archive)?, Vec::new()));
+        }
+
+        let unchecked = File {
+            sector_checksums: Vec::new(),
+            ..self.clone()
+        };
+
+        if self.block.flags & FILE_SINGLE_UNIT != 0 {
+            return match self.read_to_vec(archive) {
+                Ok(buf) => Ok((buf, Vec::new())),
+                Err(MpqError::SectorChecksumMismatch(_)) => {
+                    let buf = unchecked
+                        .read_to_vec(archive)
+                        .unwrap_or_else(|_| vec![0; self.size() as usize]);
+
+                    Ok((buf, vec![0]))
+                }
+                Err(e) => Err(e),
+            };
+        }
+
+        let sector_size = archive.sector_size as usize;
+        let region = self.sector_region(archive)?;
+        let mut buffers = SectorBuffers::default();
+        let mut buf: Vec<u8> = vec![0; self.size() as usize];
+        let mut bad_sectors = Vec::new();
+
+        for i in 0..self.sector_offsets.len() - 1 {
+            let out = &mut buf[i * sector_size..];
+
+            match self.read_sector(i, region, sector_size, out, &mut buffers) {
+                Ok(_) => {}
+                Err(MpqError::SectorChecksumMismatch(_)) => {
+                    bad_sectors.push(i);
+
+                    if unchecked
+                        .read_sector(i, region, sector_size, out, &mut buffers)
+                        .is_err()
+                    {
+                        let len = out.len().min(sector_size);
+                        out[..len].iter_mut().for_each(|b| *b = 0);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok((buf, bad_sectors))
+    }
+
+    // read out.len() bytes starting at offset, only decoding the sectors that cover
+    // the range. returns fewer bytes if the range extends past the end of the file
+    pub fn read_range<T: AsRef<[u8]>>(
+        &self,
+        archive: &mut Archive<T>,
+        offset: u64,
+        out: &mut [u8],
+    ) -> Result<usize, MpqError> {
+        let size = u64::from(self.block.unpacked_size);
+
+        if offset >= size || out.is_empty() {
+            return Ok(0);
+        }
+
+        let len = (out.len() as u64).min(size - offset) as usize;
+        let offset = offset as usize;
+        let out = &mut out[..len];
+
+        if self.block.flags & (FILE_SINGLE_UNIT | FILE_PATCH_FILE) != 0 {
+            // single unit files can only be decoded as a whole
+            let buf = self.read_to_vec(archive)?;
+
+            out.copy_from_slice(&buf[offset..offset + len]);
+        } else if self.block.flags & FILE_COMPRESS_MASK == 0 {
+            out.copy_from_slice(archive.slice(self.block.offset + offset as u64, len)?);
+        } else {
+            let sector_size = archive.sector_size as usize;
+            let region = self.sector_region(archive)?;
+            let mut buffers = SectorBuffers::default();
+            let mut sector: Vec<u8> = vec![0; sector_size];
+            let mut pos = 0;
+
+            for i in offset / sector_size..=(offset + len - 1) / sector_size {
+                let read = self.read_sector(i, region, sector_size, &mut sector, &mut buffers)?;
+                let start = if pos == 0 { offset % sector_size } else { 0 };
+                let count = read.saturating_sub(start).min(len - pos);
+
+                out[pos..pos + count].copy_from_slice(&sector[start..start + count]);
+                pos += count;
+            }
+        }
+
+        Ok(len)
+    }
+
+    // decompress sectors concurrently, each sector is written to its own slice of buf
+    #[cfg(feature = "parallel")]
+    pub fn read_parallel<T: AsRef<[u8]> + Sync>(
+        &self,
+        archive: &mut Archive<T>,
+        buf: &mut [u8],
+    ) -> Result<usize, MpqError> {
+        if self.block.flags & (FILE_PATCH_FILE | FILE_SINGLE_UNIT) != 0
+            || self.block.flags & FILE_COMPRESS_MASK == 0
+        {
+            return self.read(archive, buf);
+        }
+
+        let sector_size = archive.sector_size as usize;
+        let region = self.sector_region(archive)?;
+
+        let read = buf
+            .par_chunks_mut(sector_size)
+            .take(self.sector_offsets.len() - 1)
+            .enumerate()
+            .map_init(SectorBuffers::default, |buffers, (i, out_buf)| {
+                self.read_sector(i, region, sector_size, out_buf, buffers)
+            })
+            .sum::<Result<usize, MpqError>>()?;
+
+        // short sectors leave the buffer short of the size the block claims, as read
+        // checks
+        let expected = self.block.unpacked_size as usize;
+
+        if read != expected {
+            return Err(MpqError::SizeMismatch(expected, read));
+        }
+
+        Ok(read)
+    }
+
+    fn read_sector_file<T: AsRef<[u8]>>(
+        &self,
+        archive: &Archive<T>,
+        out: &mut [u8],
+    ) -> Result<usize, MpqError> {
+        let mut read: usize = 0;
+
+        if self.block.flags & FILE_COMPRESS_MASK != 0 {
+            let sector_size = archive.sector_size as usize;
+            let region = self.sector_region(archive)?;
+            let mut buffers = SectorBuffers::default();
+
+            for i in 0..self.sector_offsets.len() - 1 {
+                read += self.read_sector(i, region, sector_size, &mut out[read..], &mut buffers)?;
+            }
+        } else {
+            read = (self.block.unpacked_size as usize).min(out.len());
+
+            out[..read].copy_from_slice(archive.slice(self.block.offset, read)?);
+
+            // stored sectors are still encrypted one by one
+            if self.block.flags & FILE_ENCRYPTED != 0 {
+                for (i, sector) in out[..read]
+                    .chunks_mut(archive.sector_size as usize)
+                    .enumerate()
+                {
+                    decrypt(sector, self.file_key.wrapping_add(i as u32));
+                }
+            }
+        }
+
+        Ok(read)
+    }
+
+    // borrow all sectors at once, they are only copied if they need decrypting
+    fn sector_region<'a, T: AsRef<[u8]>>(
+        &self,
+        archive: &'a Archive<T>,
+    ) -> Result<Region<'a>, MpqError> {
+        let first_offset = self.sector_offsets[0];
+        let last_offset = self.sector_offsets[self.sector_offsets.len() - 1];
+
+        Ok(Region {
+            data: archive.slice(
+                self.block.offset + u64::from(first_offset),
+                last_offset.saturating_sub(first_offset) as usize,
+            )?,
+            decompressors: &archive.decompressors,
+        })
+    }
+
+    // decrypt, verify and decompress sector i of region into out_buf
+    fn read_sector(
+        &self,
+        i: usize,
+        region: Region,
+        sector_size: usize,
+        out_buf: &mut [u8],
+        buffers: &mut SectorBuffers,
+    ) -> Result<usize, MpqError> {
+        // every sector but the last holds sector_size bytes of the file
+        let unpacked_len = (self.block.unpacked_size as usize)
+            .saturating_sub(i * sector_size)
+            .min(sector_size)
+            .min(out_buf.len());
+
+        let read = self.decode_sector(i, region, &mut out_buf[..unpacked_len], buffers)?;
+
+        if read != unpacked_len {
+            return Err(MpqError::SizeMismatch(unpacked_len, read));
+        }
+
+        Ok(read)
+    }
+
+    // checksum verification, sectors are usually checked with adler32 but some
+    // archivers store a crc32 instead
+    fn check_sector(&self, i: usize, in_buf: &[u8]) -> Result<(), MpqError> {
+        let checksum = match self.sector_checksums.get(i) {
+            Some(&v) if v != 0 => v,
+            _ => return Ok(()),
+        };
+        let mut adler = RollingAdler32::from_value(0);
+
+        adler.update_buffer(in_buf);
+
+        if checksum != adler.hash() && checksum != crc32fast::hash(in_buf) {
+            return Err(MpqError::SectorChecksumMismatch(i));
+        }
+
+        Ok(())
+    }
+
+    // decrypt, verify and decompress sector i of region into out_buf, returns the
+    // number of bytes it decoded to. a sector the size of out_buf is stored
+    fn decode_sector(
+        &self,
+        i: usize,
+        region: Region,
+        out_buf: &mut [u8],
+        buffers: &mut SectorBuffers,
+    ) -> Result<usize, MpqError> {
+        let first_offset = self.sector_offsets[0];
+        let sector_start = (self.sector_offsets[i] - first_offset) as usize;
+        let sector_end = (self.sector_offsets[i + 1] - first_offset) as usize;
+
+        let mut in_buf = match region.data.get(sector_start..sector_end) {
+            Some(v) => v,
+            None => return Err(MpqError::Corrupt("Sector offset out of range")),
+        };
+
+        if self.block.flags & FILE_ENCRYPTED != 0 {
+            buffers.sector.clear();
+            buffers.sector.extend_from_slice(in_buf);
+            decrypt(&mut buffers.sector, self.file_key.wrapping_add(i as u32));
+            in_buf = &buffers.sector;
+        }
+
+        self.check_sector(i, in_buf)?;
+
+        unpack(
+            self.method(),
+            in_buf,
+            out_buf,
+            &mut buffers.scratch,
+            region.decompressors,
+        )
+    }
+
+    // decrypted and verified data of a single unit file, without its checksum
+    fn single_unit_data<'a, T: AsRef<[u8]>>(
+        &self,
+        archive: &'a Archive<T>,
+    ) -> Result<Cow<'a, [u8]>, MpqError> {
+        let len = self.sectors()[0].packed_size as usize;
+        let mut in_buff = Cow::Borrowed(archive.slice(self.block.offset, len)?);
+
+        if self.block.flags & FILE_ENCRYPTED != 0 {
+            decrypt(in_buff.to_mut(), self.file_key);
+        }
+
+        self.check_sector(0, &in_buff)?;
+
+        Ok(in_buff)
+    }
+
+    fn read_single_unit_file<T: AsRef<[u8]>>(
+        &self,
+        archive: &Archive<T>,
+        out_buf: &mut [u8],
+    ) -> Result<usize, MpqError> {
+        let in_buff = self.single_unit_data(archive)?;
+
+        let unpacked_len = (self.block.unpacked_size as usize).min(out_buf.len());
+        let out_buf = &mut out_buf[..unpacked_len];
+
+        let read = unpack(
+            self.method(),
+            &in_buff,
+            out_buf,
+            &mut Vec::new(),
+            &archive.decompressors,
+        )?;
+
+        if let Some(attributes) = &archive.attributes {
+            match attributes.md5.get(self.hash.block_index as usize) {
+                Some(md5) if *md5 != [0; 16] && md5[..] != Md5::digest(&out_buf[..read])[..] => {
+                    return Err(MpqError::Md5Mismatch);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(read)
+    }
+
+    // extract file from archive to the local filesystem
+    pub fn extract<T: AsRef<[u8]>, P: AsRef<Path>>(
+        &self,
+        archive: &mut Archive<T>,
+        path: P,
+    ) -> Result<usize, MpqError> {
+        self.extract_with(archive, path, false)
+    }
+
+    // extract file, replacing an existing file at path if overwrite is set
+    pub fn extract_with<T: AsRef<[u8]>, P: AsRef<Path>>(
+        &self,
+        archive: &mut Archive<T>,
+        path: P,
+        overwrite: bool,
+    ) -> Result<usize, MpqError> {
+        self.create(archive, path, overwrite)
+    }
+
+    fn create<T: AsRef<[u8]>, P: AsRef<Path>>(
+        &self,
+        archive: &Archive<T>,
+        path: P,
+        overwrite: bool,
+    ) -> Result<usize, MpqError> {
+        let path = path.as_ref();
+
+        create_parent(path)?;
+
+        if !overwrite && path.exists() {
+            return Err(MpqError::AlreadyExists(path.display().to_string()));
+        }
+
+        // decode next to path and move it into place once every sector decoded, so a
+        // file that fails to decode leaves whatever path held alone
+        let mut temp = match path.file_name() {
+            Some(v) => v.to_os_string(),
+            None => return Err(Error::from(ErrorKind::InvalidInput).into()),
+        };
+        temp.push(".part");
+        let temp = path.with_file_name(temp);
+
+        let result = fs::File::create(&temp)
+            .map_err(MpqError::from)
+            .and_then(|mut file| self.write_to(archive, &mut file))
+            .and_then(|len| {
+                fs::rename(&temp, path)?;
+                Ok(len)
+            });
+
+        if result.is_err() {
+            let _ = fs::remove_file(&temp);
+        }
+
+        result
+    }
+
+    // extract like extract, then give the new file the modification time attributes
+    // stores for this file. the time is left alone if there is none
+    pub fn extract_with_times<T: AsRef<[u8]>, P: AsRef<Path>>(
+        &self,
+        archive: &mut Archive<T>,
+        path: P,
+        attributes: &Attributes,
+    ) -> Result<usize, MpqError> {
+        let len = self.extract(archive, path.as_ref())?;
+
+        if let Some(time) = attributes.modified(self.block_index()) {
+            fs::OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_modified(time)?;
+        }
+
+        Ok(len)
+    }
+
+    // write file contents to w, returns the number of bytes written
+    pub fn extract_to<T: AsRef<[u8]>, W: Write>(
+        &self,
+        archive: &mut Archive<T>,
+        w: &mut W,
+    ) -> Result<usize, MpqError> {
+        self.write_to(archive, w)
+    }
+
+    fn write_to<T: AsRef<[u8]>, W: Write>(
+        &self,
+        archive: &Archive<T>,
+        w: &mut W,
+    ) -> Result<usize, MpqError> {
+        if self.block.flags & (FILE_SINGLE_UNIT | FILE_PATCH_FILE) != 0
+            || self.block.flags & FILE_COMPRESS_MASK == 0
+        {
+            let mut buf: Vec<u8> = vec![0; self.size() as usize];
+            let len = self.read_shared(archive, &mut buf)?;
+
+            buf.truncate(len);
+
+            w.write_all(&buf)?;
+
+            return Ok(buf.len());
+        }
+
+        // compressed sectors are decoded and written one at a time
+        let sector_size = archive.sector_size as usize;
+        let region = self.sector_region(archive)?;
+        let mut buffers = SectorBuffers::default();
+        let mut sector: Vec<u8> = vec![0; sector_size];
+        let mut written = 0;
+
+        for i in 0..self.sector_offsets.len() - 1 {
+            let read = self.read_sector(i, region, sector_size, &mut sector, &mut buffers)?;
+
+            w.write_all(&sector[..read])?;
+            written += read;
+        }
+
+        let expected = self.block.unpacked_size as usize;
+
+        if written != expected {
+            return Err(MpqError::SizeMismatch(expected, written));
+        }
+
+        Ok(written)
+    }
+
+    // extract a WAVE file as plain PCM with a minimal RIFF header, keeping the sample
+    // rate, channel count and sample size of its fmt chunk
+    pub fn extract_wav<T: AsRef<[u8]>, P: AsRef<Path>>(
+        &self,
+        archive: &mut Archive<T>,
+        path: P,
+    ) -> Result<usize, MpqError> {
+        let buf = wav::to_wav(&self.read_to_vec(archive)?, self.adpcm_channels(archive))?;
+
+        create_parent(path.as_ref())?;
+
+        if path.as_ref().exists() {
+            return Err(MpqError::AlreadyExists(path.as_ref().display().to_string()));
+        }
+
+        fs::write(path, &buf)?;
+
+        Ok(buf.len())
+    }
+
+    // extract file below dest, converting the archive's backslash separated name
+    // into nested directories. see local_path for the names that are refused
+    pub fn extract_tree<T: AsRef<[u8]>, P: AsRef<Path>>(
+        &self,
+        archive: &mut Archive<T>,
+        dest: P,
+    ) -> Result<usize, MpqError> {
+        self.extract(archive, local_path(dest.as_ref(), &self.name)?)
+    }
+}
+
+// create the directory path is in, a bare file name or a root has none to create
+fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::create_dir_all(parent),
+        _ => Ok(()),
+    }
+}
+
+// sink that only hashes what is written to it
+struct Crc32Writer(crc32fast::Hasher);
+
+impl Write for Crc32Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// map an archive file name onto a path below dest, either separator starts a
+// directory. every part must be a plain name, so names climbing out of dest with
+// .. or naming a drive are refused
+pub(crate) fn local_path(dest: &Path, filename: &str) -> Result<PathBuf, MpqError> {
+    let mut path = dest.to_path_buf();
+
+    for part in filename.split(&['\\', '/'][..]) {
+        let mut components = Path::new(part).components();
+
+        match (components.next(), components.next()) {
+            (None, _) | (Some(Component::CurDir), None) => continue,
+            (Some(Component::Normal(_)), None) => path.push(part),
+            _ => return Err(MpqError::InvalidFileName(String::from(filename))),
+        }
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        Archive, Attributes, CompressionMethod, Method, MpqError, FILE_COMPRESS,
+        FILE_DELETE_MARKER, FILE_ENCRYPTED, FILE_FIX_KEY, FILE_IMPLODE, FILE_SECTOR_CRC,
+        FILE_SINGLE_UNIT, HASH_ENTRY_DELETED, HASH_ENTRY_EMPTY,
+    };
+    use crate::compression::decompress;
+    use crate::crypt::{hash_name, hash_string};
+    use crate::fixture::{self, Builder, ChecksumTable};
+    use crate::signature::Signature;
+    use byteorder::{ByteOrder, LittleEndian};
+    use md5::{Digest, Md5};
+    use rsa::{BigUint, Pkcs1v15Sign, RsaPrivateKey};
+    use std::fs;
+
+    const DATA: &[u8] = b"The quick brown fox jumps over the lazy dog";
+
+    fn attributes() -> Attributes {
+        let mut md5 = [0; 16];
+        md5.copy_from_slice(&Md5::digest(DATA));
+
+        Attributes {
+            crc32: vec![crc32fast::hash(DATA)],
+            timestamp: Vec::new(),
+            md5: vec![md5],
+        }
+    }
+
+    #[test]
+    fn verify() {
+        let buf = Builder::new().file_with("fox.txt", DATA, 0).build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("fox.txt").unwrap();
+
+        file.verify(&mut archive, &attributes()).unwrap();
+    }
+
+    #[test]
+    fn verify_corrupted() {
+        let mut buf = Builder::new().file_with("fox.txt", DATA, 0).build();
+        let pos = buf.windows(DATA.len()).position(|w| w == DATA).unwrap();
+        buf[pos] ^= 0xFF;
+
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("fox.txt").unwrap();
+        let err = file.verify(&mut archive, &attributes()).unwrap_err();
+
+        assert!(matches!(err, MpqError::Crc32Mismatch));
+    }
+
+    #[test]
+    fn read_single_unit_verify() {
+        // md5 for "fox.txt", "(attributes)" and "(listfile)"
+        let mut attributes = vec![0; 8 + 3 * 16];
+        LittleEndian::write_u32(&mut attributes[0..], 100);
+        LittleEndian::write_u32(&mut attributes[4..], 0x4);
+        attributes[8..24].copy_from_slice(&Md5::digest(DATA));
+
+        let mut buf = Builder::new()
+            .file_with("fox.txt", DATA, FILE_SINGLE_UNIT)
+            .file("(attributes)", &attributes)
+            .build();
+        let pos = buf.windows(DATA.len()).position(|w| w == DATA).unwrap();
+
+        let mut archive = Archive::load(buf.clone()).unwrap();
+        archive.set_verify(true).unwrap();
+        let file = archive.open_file("fox.txt").unwrap();
+
+        assert_eq!(DATA, &file.read_to_vec(&mut archive).unwrap()[..]);
+
+        buf[pos] ^= 0xFF;
+
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("fox.txt").unwrap();
+
+        assert!(file.read_to_vec(&mut archive).is_ok());
+
+        archive.set_verify(true).unwrap();
+
+        assert!(matches!(
+            file.read_to_vec(&mut archive),
+            Err(MpqError::Md5Mismatch)
+        ));
+    }
+
+    #[test]
+    fn crc32() {
+        let data: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+        // crc32 for "a.dat", "fox.txt", "(attributes)" and "(listfile)"
+        let mut attributes = vec![0; 8 + 4 * 4];
+        LittleEndian::write_u32(&mut attributes[0..], 100);
+        LittleEndian::write_u32(&mut attributes[4..], 0x1);
+        LittleEndian::write_u32(&mut attributes[8..], crc32fast::hash(&data));
+        LittleEndian::write_u32(&mut attributes[12..], crc32fast::hash(DATA));
+
+        let buf = Builder::new()
+            .file("a.dat", &data)
+            .file_with("fox.txt", DATA, FILE_SINGLE_UNIT | FILE_ENCRYPTED)
+            .file("(attributes)", &attributes)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let attributes = archive.read_attributes().unwrap().unwrap();
+
+        for (i, name) in ["a.dat", "fox.txt"].iter().enumerate() {
+            let file = archive.open_file(name).unwrap();
+
+            assert_eq!(attributes.crc32[i], file.crc32(&mut archive).unwrap());
+        }
+    }
+
+    fn test_key() -> RsaPrivateKey {
+        let hex = |s: &str| {
+            let bytes: Vec<u8> = (0..s.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+                .collect();
+            BigUint::from_bytes_be(&bytes)
+        };
+
+        RsaPrivateKey::from_components(
+            hex(
+                "f14871758ec2d502102c8b4a33d595690122833af2937295b90fd6d6a689512e\
+                 f4f96899a3b80d02dbaf3a8d965e0e1cd4eef0cb320fbd82cd92072da1c909c9",
+            ),
+            BigUint::from(0x10001u32),
+            hex(
+                "29afe582751eee6483dcde57fcaaab3cd0286159feed40bfe3907c02ec150f84\
+                 628666233bcf59c426a2c0dbaff6069def457a2bd7c79d30d29da7ee6a800a2d",
+            ),
+            vec![
+                hex("fb0cfbca55f262635841c1a6f6ebcd85711cd6406b046a3c3e30dc41e001bb3b"),
+                hex("f60a29ce327854c9df8f93ee5bf158a01e95469f43224621484f22d348cf96cb"),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn signed_archive(key: &RsaPrivateKey) -> Vec<u8> {
+        let mut buf = Builder::new()
+            .file_with("fox.txt", DATA, 0)
+            .file_with("(signature)", &[0; 72], 0)
+            .build();
+
+        let mut archive = Archive::load(buf.clone()).unwrap();
+        let offset = archive.open_file("(signature)").unwrap().block.offset as usize;
+
+        let digest = Md5::digest(&buf);
+        let mut signature = key.sign(Pkcs1v15Sign::new::<Md5>(), &digest).unwrap();
+        signature.reverse();
+
+        buf[offset + 8..offset + 72].copy_from_slice(&signature);
+        buf
+    }
+
+    #[test]
+    fn verify_signature() {
+        let key = test_key();
+        let mut archive = Archive::load(signed_archive(&key)).unwrap();
+
+        assert_eq!(
+            Signature::Valid,
+            archive.verify_weak_signature(&key.to_public_key()).unwrap()
+        );
+        assert_eq!(Signature::Invalid, archive.verify_signature().unwrap());
+    }
+
+    #[test]
+    fn verify_signature_tampered() {
+        let key = test_key();
+        let mut buf = signed_archive(&key);
+        let pos = buf.windows(DATA.len()).position(|w| w == DATA).unwrap();
+        buf[pos] ^= 0xFF;
+
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert_eq!(
+            Signature::Invalid,
+            archive.verify_weak_signature(&key.to_public_key()).unwrap()
+        );
+    }
+
+    #[test]
+    fn verify_unsigned() {
+        let buf = Builder::new().file("fox.txt", DATA).build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert_eq!(Signature::Unsigned, archive.verify_signature().unwrap());
+    }
+
+    #[test]
+    fn read_user_data() {
+        let archive = Builder::new().file("fox.txt", DATA).build();
+        let buf = fixture::embed(0x400, b"user data payload", &archive);
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert!(archive.has_user_data());
+        assert_eq!(17, archive.user_data_size());
+        assert_eq!(
+            b"user data payload".to_vec(),
+            archive.read_user_data().unwrap().unwrap()
+        );
+
+        let info = archive.info();
+
+        assert_eq!(Some(17), info.user_data_size);
+        assert_eq!(0x600, info.offset);
+
+        let file = archive.open_file("fox.txt").unwrap();
+        let mut buf = vec![0; file.size() as usize];
+        file.read(&mut archive, &mut buf).unwrap();
+
+        assert_eq!(DATA, &buf[..]);
+
+        // a size reaching into the archive header
+        let mut buf = fixture::embed(0, b"user data payload", &Builder::new().build());
+        LittleEndian::write_u32(&mut buf[4..], 0x1F1);
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert!(matches!(
+            archive.read_user_data(),
+            Err(MpqError::Corrupt(_))
+        ));
+
+        let mut archive = Archive::load(Builder::new().build()).unwrap();
+
+        assert!(!archive.has_user_data());
+        assert_eq!(0, archive.user_data_size());
+        assert!(archive.read_user_data().unwrap().is_none());
+    }
+
+    #[test]
+    fn read_fix_key_embedded() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let archive = Builder::new()
+            .sector_size_shift(0)
+            .file_with(
+                "single.dat",
+                &data,
+                FILE_SINGLE_UNIT | FILE_ENCRYPTED | FILE_FIX_KEY,
+            )
+            .file_with(
+                "Units\\sectors.dat",
+                &data,
+                FILE_COMPRESS | FILE_ENCRYPTED | FILE_FIX_KEY,
+            )
+            .file_with(
+                "packed.dat",
+                &data,
+                FILE_SINGLE_UNIT | FILE_COMPRESS | FILE_ENCRYPTED | FILE_FIX_KEY,
+            )
+            .build();
+        let buf = fixture::embed(0x600, b"user data", &archive);
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert_ne!(0, archive.offset);
+
+        for name in &["single.dat", "Units\\sectors.dat", "packed.dat"] {
+            let file = archive.open_file(name).unwrap();
+
+            assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+        }
+    }
+
+    #[test]
+    fn open_options() {
+        let mut buf = vec![0xCC; 0x123];
+        buf.extend(
+            Builder::new()
+                .file("fox.txt", b"neutral")
+                .file_with_locale("fox.txt", b"german", FILE_COMPRESS, 0x407)
+                .file_with_locale("only.txt", b"french", FILE_COMPRESS, 0x40C)
+                .build(),
+        );
+
+        let mut archive = Archive::options().load(buf.clone()).unwrap();
+        let file = archive.open_file("fox.txt").unwrap();
+
+        assert_eq!(b"neutral", &file.read_to_vec(&mut archive).unwrap()[..]);
+        assert!(archive.attributes.is_none());
+
+        let mut archive = Archive::options()
+            .base_offset(0x123)
+            .default_locale(0x407)
+            .verify(true)
+            .load(buf.clone())
+            .unwrap();
+        let file = archive.open_file("fox.txt").unwrap();
+
+        assert_eq!(b"german", &file.read_to_vec(&mut archive).unwrap()[..]);
+
+        // other locales are still found when neither is present
+        let file = archive.open_file("only.txt").unwrap();
+
+        assert_eq!(b"french", &file.read_to_vec(&mut archive).unwrap()[..]);
+
+        assert!(Archive::options().base_offset(0x100).load(buf).is_err());
+    }
+
+    #[test]
+    fn find_hash_wraps() {
+        let buf = Builder::new()
+            .file_with_locale("b.txt", b"french", FILE_COMPRESS, 0x40C)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let count = archive.hash_table.len();
+        let start = hash_string("b.txt", 0) as usize & (count - 1);
+        let from = archive
+            .hash_table
+            .iter()
+            .position(|hash| hash.locale == 0x40C)
+            .unwrap();
+        let entry = archive.hash_table[from].clone();
+
+        // every other entry deleted, the file's in the one the probe reaches last
+        // after wrapping around the end of the table
+        for hash in &mut archive.hash_table {
+            hash.block_index = HASH_ENTRY_DELETED;
+        }
+
+        assert_ne!(0, start);
+        archive.hash_table[start - 1] = entry;
+
+        let file = archive.open_file("b.txt").unwrap();
+
+        assert_eq!(b"french", &file.read_to_vec(&mut archive).unwrap()[..]);
+
+        // a miss stops after a full cycle, or at an entry that was never used
+        assert!(archive.open_file("missing.txt").is_err());
+
+        archive.hash_table[start].block_index = HASH_ENTRY_EMPTY;
+
+        assert!(archive.open_file("b.txt").is_err());
+    }
+
+    #[test]
+    fn open_options_sector_size() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 13) as u8).collect();
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .file_with("a.dat", &data, FILE_COMPRESS | FILE_SECTOR_CRC)
+            .build();
+
+        for &shift in &[3, 40] {
+            let mut corrupt = buf.clone();
+            LittleEndian::write_u16(&mut corrupt[0x0E..], shift);
+
+            // the wrong sector size shows when opening the file or reading it
+            if let Ok(mut archive) = Archive::load(corrupt.clone()) {
+                if let Ok(file) = archive.open_file("a.dat") {
+                    assert!(file.read_to_vec(&mut archive).is_err());
+                }
+            }
+
+            let mut archive = Archive::options().sector_size(512).load(corrupt).unwrap();
+            let file = archive.open_file("a.dat").unwrap();
+
+            assert_eq!(6, file.sectors().len());
+            assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+        }
+    }
+
+    #[test]
+    fn computed_size() {
+        let builders = [
+            Builder::new().file("a.txt", DATA),
+            Builder::new().hi_block_table(&[]).file("a.txt", DATA),
+            Builder::new().het_bet(false).file("a.txt", DATA),
+            Builder::new().het_bet(true).file("a.txt", DATA),
+        ];
+
+        for builder in &builders {
+            let buf = builder.build();
+            let len = buf.len() as u64;
+
+            assert_eq!(len, Archive::load(buf.clone()).unwrap().computed_size());
+
+            // the header's archive size doesn't matter
+            let mut buf = fixture::embed(0x200, b"user data", &buf);
+            buf.extend_from_slice(&[0; 0x100]);
+            let offset = buf.len() - 0x100 - len as usize;
+            LittleEndian::write_u32(&mut buf[offset + 0x08..], 0x20);
+
+            assert_eq!(len, Archive::load(buf).unwrap().computed_size());
+        }
+    }
+
+    #[test]
+    fn verify_tables() {
+        let buf = Builder::new().het_bet(true).file("a.txt", DATA).build();
+
+        Archive::load(buf.clone()).unwrap().verify_tables().unwrap();
+        Archive::load(Builder::new().file("a.txt", DATA).build())
+            .unwrap()
+            .verify_tables()
+            .unwrap();
+
+        // the md5 stored for the HET table, then the archive size the header's covers
+        for &(offset, table) in &[(0xB0, "HET table"), (0x08, "header")] {
+            let mut corrupt = buf.clone();
+            corrupt[offset] ^= 0xFF;
+
+            assert!(matches!(
+                Archive::load(corrupt).unwrap().verify_tables(),
+                Err(MpqError::TableMd5Mismatch(name)) if name == table
+            ));
+        }
+    }
+
+    #[test]
+    fn carve() {
+        let dir = fixture::temp_dir("carve");
+        let archive = Builder::new()
+            .file("a.txt", DATA)
+            .file_with("b.txt", DATA, FILE_COMPRESS | FILE_ENCRYPTED | FILE_FIX_KEY)
+            .build();
+        let mut buf = fixture::embed(0x400, b"user data", &archive);
+        buf.extend_from_slice(b"trailing host data");
+
+        let mut embedded = Archive::load(buf).unwrap();
+
+        assert_eq!(
+            archive.len() as u64,
+            embedded.carve(dir.join("carved.mpq")).unwrap()
+        );
+        assert_eq!(archive, fs::read(dir.join("carved.mpq")).unwrap());
+
+        let mut carved = Archive::open(dir.join("carved.mpq")).unwrap();
+
+        assert!(!carved.has_user_data());
+
+        for name in &["a.txt", "b.txt"] {
+            let file = carved.open_file(name).unwrap();
+
+            assert_eq!(DATA, &file.read_to_vec(&mut carved).unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn load_unaligned() {
+        let mut buf = vec![0xCC; 0x123];
+        buf.extend(Builder::new().file("fox.txt", DATA).build());
+
+        let mut archive = Archive::load(buf.clone()).unwrap();
+        assert_eq!(0x123, archive.offset);
+        assert!(archive.open_file("fox.txt").is_ok());
+
+        let mut archive = Archive::load_at(buf, 0x123).unwrap();
+        assert!(archive.open_file("fox.txt").is_ok());
+    }
+
+    #[test]
+    fn extract_tree() {
+        let dest = fixture::temp_dir("extract-tree");
+        let buf = Builder::new()
+            .file("Units\\Human\\Footman.mdx", DATA)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("Units\\Human\\Footman.mdx").unwrap();
+
+        file.extract_tree(&mut archive, &dest).unwrap();
+
+        let path = dest.join("Units").join("Human").join("Footman.mdx");
+        assert_eq!(DATA, &fs::read(path).unwrap()[..]);
+    }
+
+    #[test]
+    fn extract_with_times() {
+        let dest = fixture::temp_dir("extract-with-times");
+        let buf = Builder::new().file("a.txt", DATA).build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("a.txt").unwrap();
+        let attributes = Attributes {
+            timestamp: vec![0x01D9_0000_1234_5678],
+            ..Attributes::default()
+        };
+
+        file.extract_with_times(&mut archive, dest.join("a.txt"), &attributes)
+            .unwrap();
+
+        let modified = fs::metadata(dest.join("a.txt"))
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        assert_eq!(attributes.modified(0).unwrap(), modified);
+        assert_eq!(DATA, &fs::read(dest.join("a.txt")).unwrap()[..]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn extract_tree_backslashes() {
+        let dest = fixture::temp_dir("extract-tree-backslashes");
+        let buf = Builder::new().file("Units\\Footman.mdx", DATA).build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("Units\\Footman.mdx").unwrap();
+
+        file.extract_tree(&mut archive, &dest).unwrap();
+
+        assert!(dest.join("Units").is_dir());
+        assert!(!dest.join("Units\\Footman.mdx").exists());
+        assert_eq!(DATA, &fs::read(dest.join("Units/Footman.mdx")).unwrap()[..]);
+    }
+
+    #[test]
+    fn extract_tree_parent_dir() {
+        let dest = fixture::temp_dir("extract-tree-parent").join("out");
+        let buf = Builder::new().file("..\\..\\evil", DATA).build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("..\\..\\evil").unwrap();
+
+        assert!(matches!(
+            file.extract_tree(&mut archive, &dest),
+            Err(MpqError::InvalidFileName(ref name)) if name == "..\\..\\evil"
+        ));
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn open_file_by_index_encrypted() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 7) as u8).collect();
+        let buf = Builder::new()
+            .listfile(false)
+            .sector_size_shift(0)
+            .file_with("secret.dat", &data, FILE_COMPRESS | FILE_ENCRYPTED)
+            .file_with(
+                "fixed.dat",
+                &data,
+                FILE_COMPRESS | FILE_ENCRYPTED | FILE_FIX_KEY,
+            )
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        for index in 0..2 {
+            let file = archive.open_file_by_index(index).unwrap();
+            let mut buf = vec![0; file.size() as usize];
+            file.read(&mut archive, &mut buf).unwrap();
+
+            assert_eq!(data, buf);
+        }
+    }
+
+    #[test]
+    fn open_file_by_hash() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let buf = Builder::new()
+            .file("a.txt", DATA)
+            .file_with("dir\\b.dat", &data, FILE_COMPRESS | FILE_ENCRYPTED)
+            .file_with_locale("c.txt", DATA, FILE_COMPRESS, 0x407)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        for &name in &["a.txt", "dir\\b.dat"] {
+            let (hash_a, hash_b, index) = hash_name(name);
+            let by_hash = archive.open_file_by_hash(index, hash_a, hash_b, 0).unwrap();
+            let by_name = archive.open_file(name).unwrap();
+
+            assert_eq!(
+                by_name.read_to_vec(&mut archive).unwrap(),
+                by_hash.read_to_vec(&mut archive).unwrap()
+            );
+        }
+
+        let (hash_a, hash_b, index) = hash_name("c.txt");
+        assert!(archive
+            .open_file_by_hash(index, hash_a, hash_b, 0x407)
+            .is_ok());
+        assert!(matches!(
+            archive.open_file_by_hash(index, hash_a, hash_b, 0),
+            Err(MpqError::FileNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn open_file_by_index() {
+        let buf = Builder::new()
+            .file("a.txt", b"first")
+            .file("fox.txt", DATA)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        let by_name = archive.open_file("fox.txt").unwrap();
+        let by_index = archive.open_file_by_index(1).unwrap();
+        let mut expected = vec![0; by_name.size() as usize];
+        let mut buf = vec![0; by_index.size() as usize];
+
+        by_name.read(&mut archive, &mut expected).unwrap();
+        by_index.read(&mut archive, &mut buf).unwrap();
+
+        assert_eq!(expected, buf);
+        assert_eq!("File00000001.xxx", by_index.name());
+        assert!(archive.open_file_by_index(3).is_err());
+    }
+
+    #[test]
+    fn error_variants() {
+        assert!(matches!(
+            Archive::load(vec![0; 0x400]),
+            Err(MpqError::InvalidMagic)
+        ));
+
+        let data: Vec<u8> = (0..2000u32).map(|i| (i % 13) as u8).collect();
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .file_with("a.dat", &data, FILE_COMPRESS | FILE_SECTOR_CRC)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        match archive.open_file("missing.dat") {
+            Err(MpqError::FileNotFound(name)) => assert_eq!("missing.dat", name),
+            _ => panic!("expected FileNotFound"),
+        }
+
+        // corrupt the second sector
+        let file = archive.open_file("a.dat").unwrap();
+        let pos = file.block.offset as usize + file.sector_offsets[1] as usize + 4;
+        archive.file.get_mut()[pos] ^= 0xFF;
+
+        let mut buf = vec![0; file.size() as usize];
+
+        assert!(matches!(
+            file.read(&mut archive, &mut buf),
+            Err(MpqError::SectorChecksumMismatch(1))
+        ));
+    }
+
+    #[test]
+    fn sector_checksum_layouts() {
+        let data: Vec<u8> = (0..2000u32).map(|i| (i % 13) as u8).collect();
+
+        for &layout in &[
+            ChecksumTable::Listed,
+            ChecksumTable::Appended,
+            ChecksumTable::Compressed,
+        ] {
+            let buf = Builder::new()
+                .listfile(false)
+                .sector_size_shift(0)
+                .checksum_table(layout)
+                .file_with("a.dat", &data, FILE_COMPRESS | FILE_SECTOR_CRC)
+                .file_with(
+                    "b.dat",
+                    &data,
+                    FILE_COMPRESS | FILE_ENCRYPTED | FILE_SECTOR_CRC,
+                )
+                .build();
+
+            // checksums are located relative to the archive, not the data
+            let mut archive = Archive::load(fixture::embed(0x200, b"", &buf)).unwrap();
+
+            for name in &["a.dat", "b.dat"] {
+                let file = archive.open_file(name).unwrap();
+
+                assert_eq!(4, file.sector_checksums.len());
+                assert_eq!(5, file.sector_offsets.len());
+                assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+            }
+
+            // the key is recovered from either size of offset table
+            let file = archive.open_file_by_index(1).unwrap();
+            assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+
+            // corrupt the third sector
+            let file = archive.open_file("b.dat").unwrap();
+            let pos = archive.offset as usize
+                + file.block.offset as usize
+                + file.sector_offsets[2] as usize
+                + 4;
+            archive.file.get_mut().as_mut_slice()[pos] ^= 0xFF;
+
+            assert!(matches!(
+                file.check(&mut archive),
+                Err(MpqError::SectorChecksumMismatch(2))
+            ));
+        }
+    }
+
+    #[test]
+    fn sector_checksum_table_too_large() {
+        let data: Vec<u8> = (0..2000u32).map(|i| (i % 13) as u8).collect();
+        let mut buf = Builder::new()
+            .listfile(false)
+            .sector_size_shift(0)
+            .file_with("a.dat", &data, FILE_COMPRESS | FILE_SECTOR_CRC)
+            .build();
+
+        // move the end of the checksums a sector past where they end
+        let table = 0x20;
+        let end = LittleEndian::read_u32(&buf[table + 5 * 4..]);
+        LittleEndian::write_u32(&mut buf[table + 5 * 4..], end + 4);
+
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert!(matches!(
+            archive.open_file("a.dat"),
+            Err(MpqError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn check() {
+        let data: Vec<u8> = (0..2000u32).map(|i| (i % 13) as u8).collect();
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .file_with("a.dat", &data, FILE_COMPRESS | FILE_SECTOR_CRC)
+            .file_with(
+                "b.dat",
+                &data,
+                FILE_COMPRESS | FILE_ENCRYPTED | FILE_SECTOR_CRC,
+            )
+            .file_with("c.dat", &data, FILE_COMPRESS | FILE_SINGLE_UNIT)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        for name in &["a.dat", "b.dat", "c.dat"] {
+            let file = archive.open_file(name).unwrap();
+
+            file.check(&mut archive).unwrap();
+        }
+
+        // corrupt the third sector
+        let file = archive.open_file("b.dat").unwrap();
+        let pos = file.block.offset as usize + file.sector_offsets[2] as usize + 4;
+        archive.file.get_mut()[pos] ^= 0xFF;
+
+        assert!(matches!(
+            file.check(&mut archive),
+            Err(MpqError::SectorChecksumMismatch(2))
+        ));
+    }
+
+    #[test]
+    fn read_single_unit_checksum() {
+        let data: Vec<u8> = (0..2000u32).map(|i| (i % 13) as u8).collect();
+        let buf = Builder::new()
+            .file_with(
+                "a.dat",
+                &data,
+                FILE_COMPRESS | FILE_ENCRYPTED | FILE_SINGLE_UNIT | FILE_SECTOR_CRC,
+            )
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("a.dat").unwrap();
+
+        assert_eq!(1, file.sector_checksums.len());
+        assert_eq!(file.block.packed_size - 4, file.sectors()[0].packed_size);
+        assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+        assert_eq!(data, file.read_to_end(&mut archive, data.len()).unwrap());
+        file.check(&mut archive).unwrap();
+
+        // corrupt the data, the checksum after it no longer matches
+        let pos = file.block.offset as usize + 8;
+        archive.file.get_mut()[pos] ^= 0xFF;
+
+        assert!(matches!(
+            file.read_to_vec(&mut archive),
+            Err(MpqError::SectorChecksumMismatch(0))
+        ));
+        assert!(matches!(
+            file.check(&mut archive),
+            Err(MpqError::SectorChecksumMismatch(0))
+        ));
+        assert_eq!(vec![0], file.read_lossy(&mut archive).unwrap().1);
+    }
+
+    #[test]
+    fn read_crc32_sector_checksums() {
+        let data: Vec<u8> = (0..2000u32).map(|i| (i % 13) as u8).collect();
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .crc32_checksums(true)
+            .file_with("a.dat", &data, FILE_COMPRESS | FILE_SECTOR_CRC)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("a.dat").unwrap();
+
+        assert_eq!(4, file.sector_checksums.len());
+        assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+
+        let pos = file.block.offset as usize + file.sector_offsets[2] as usize + 4;
+        archive.file.get_mut()[pos] ^= 0xFF;
+
+        assert!(matches!(
+            file.read_to_vec(&mut archive),
+            Err(MpqError::SectorChecksumMismatch(2))
+        ));
+    }
+
+    #[test]
+    fn read_lossy() {
+        let data: Vec<u8> = (0..2000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .file_with("a.dat", &data, FILE_COMPRESS | FILE_SECTOR_CRC)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("a.dat").unwrap();
+        let checksums = file.block.offset as usize + file.sector_offsets[4] as usize;
+
+        assert_eq!(
+            (data.clone(), Vec::new()),
+            file.read_lossy(&mut archive).unwrap()
+        );
+
+        // a wrong checksum for intact data, checksums are read when opening
+        archive.file.get_mut()[checksums + 4] ^= 0xFF;
+        let file = archive.open_file("a.dat").unwrap();
+
+        assert!(file.read_to_vec(&mut archive).is_err());
+        assert_eq!(
+            (data.clone(), vec![1]),
+            file.read_lossy(&mut archive).unwrap()
+        );
+
+        // damaged data in the third sector
+        let pos = file.block.offset as usize + file.sector_offsets[2] as usize + 4;
+        archive.file.get_mut()[pos] ^= 0xFF;
+
+        let (buf, bad_sectors) = file.read_lossy(&mut archive).unwrap();
+
+        assert_eq!(vec![1, 2], bad_sectors);
+        assert_eq!(data[..1024], buf[..1024]);
+        assert_eq!(data[1536..], buf[1536..]);
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn read_sector_compressed_to_sector_size() {
+        // the last sector holds 500 incompressible bytes, which zlib grows to
+        // exactly one sector
+        let mut seed: u32 = 1;
+        let data: Vec<u8> = (0..1012)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .always_compress(true)
+            .file("random.bin", &data)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("random.bin").unwrap();
+        let mut buf = vec![0; file.size() as usize];
+
+        assert_eq!(512, file.sector_offsets[2] - file.sector_offsets[1]);
+        assert_eq!(data.len(), file.read(&mut archive, &mut buf).unwrap());
+        assert_eq!(data, buf);
+    }
+
+    #[test]
+    fn list_files() {
+        let buf = Builder::new()
+            .listfile(false)
+            .file("a.txt", b"a")
+            .file("(listfile)", b"a.txt\rUnits\\b.txt\r\n")
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert_eq!(vec!["a.txt", "Units\\b.txt"], archive.list_files().unwrap());
+    }
+
+    #[test]
+    fn iter_files() {
+        let buf = Builder::new()
+            .listfile(false)
+            .file("a.txt", DATA)
+            .file("b.txt", DATA)
+            .file("(listfile)", b"a.txt\r\nmissing.txt\r\nb.txt")
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        let results: Vec<_> = archive.iter_files().collect();
+
+        assert_eq!(3, results.len());
+        assert_eq!(2, results.iter().filter(|r| r.is_ok()).count());
+        assert!(matches!(&results[1], Err(MpqError::FileNotFound(name)) if name == "missing.txt"));
+
+        for file in results.into_iter().flatten() {
+            assert_eq!(DATA, &file.read_to_vec(&mut archive).unwrap()[..]);
+        }
+    }
+
+    #[test]
+    fn resolve_names() {
+        let buf = Builder::new()
+            .listfile(false)
+            .file("a.txt", b"a")
+            .file("Units\\b.txt", b"b")
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert!(archive.list_files().unwrap().is_empty());
+
+        let names =
+            archive.resolve_names(&["missing.txt", "UNITS\\B.TXT", "Units\\b.txt", "a.txt"]);
+
+        assert_eq!(
+            vec![
+                (1, String::from("UNITS\\B.TXT")),
+                (0, String::from("a.txt"))
+            ],
+            names
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let buf = Builder::new()
+            .listfile(false)
+            .file("a.txt", b"hello")
+            .file_with("b.txt", DATA, FILE_ENCRYPTED | FILE_SINGLE_UNIT)
+            .file_with("c.txt", DATA, FILE_COMPRESS | FILE_ENCRYPTED)
+            .file_with("d.txt", DATA, 0)
+            .file("(listfile)", b"a.txt\r\nb.txt")
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let stats = archive.stats();
+        let packed: u64 = archive
+            .block_table
+            .iter()
+            .map(|b| u64::from(b.packed_size))
+            .sum();
+
+        assert_eq!(5, stats.block_count);
+        assert_eq!(5, stats.files);
+        assert_eq!(3, stats.named);
+        assert_eq!(2, stats.encrypted);
+        assert_eq!(3, stats.compressed);
+        assert_eq!(packed, stats.packed_size);
+        assert_eq!(5 + 3 * DATA.len() as u64 + 12, stats.unpacked_size);
+    }
+
+    #[test]
+    fn apply_listfile() {
+        let buf = Builder::new()
+            .listfile(false)
+            .file("a.txt", b"a")
+            .file_with("Units\\b.txt", b"b", FILE_SINGLE_UNIT | FILE_ENCRYPTED)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert!(archive.list_files().unwrap().is_empty());
+        assert!(archive.open_file_by_index(1).is_err());
+
+        let listfile = vec![
+            String::from("Units\\b.txt"),
+            String::from("missing.txt"),
+            String::from("a.txt"),
+        ];
+        archive.apply_listfile(&listfile);
+        archive.apply_listfile(&listfile);
+
+        assert_eq!(vec!["Units\\b.txt", "a.txt"], archive.list_files().unwrap());
+
+        let names: Vec<String> = archive.files().map(|f| f.name().to_string()).collect();
+
+        assert_eq!(vec!["a.txt", "Units\\b.txt"], names);
+        assert_eq!(
+            Some(String::from("Units\\b.txt")),
+            archive.manifest().files[1].name
+        );
+    }
+
+    #[test]
+    fn manifest_compression() {
+        let names: Vec<String> = (0..12)
+            .map(|i| format!("Units\\Human\\Footman{}.mdx", i))
+            .collect();
+        let listfile = names.join("\r\n");
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 7) as u8).collect();
+        let buf = Builder::new()
+            .listfile(false)
+            .file_packed("(listfile)", listfile.as_bytes(), fixture::BZIP2_LISTFILE)
+            .file_with("a.dat", &data, FILE_COMPRESS | FILE_ENCRYPTED)
+            .file("b.txt", DATA)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let files = archive.manifest().files;
+
+        assert_eq!(vec![CompressionMethod::Bzip2], files[0].compression);
+        assert_eq!(
+            fixture::BZIP2_LISTFILE.len() as f32 / listfile.len() as f32,
+            files[0].ratio
+        );
+        // the key of the unnamed file is recovered to read its mask byte
+        assert_eq!(None, files[1].name);
+        #[cfg(feature = "zlib")]
+        assert_eq!(vec![CompressionMethod::Zlib], files[1].compression);
+        // stored sectors behind a sector offset table take more space than the data
+        assert!(files[2].compression.is_empty());
+        assert!(files[2].ratio > 1.0);
+    }
+
+    #[test]
+    fn open_file_empty_basename() {
+        let buf = Builder::new()
+            .file_with("Units\\", DATA, FILE_COMPRESS | FILE_ENCRYPTED)
+            .file("Plain\\", DATA)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert!(matches!(
+            archive.open_file("Units\\"),
+            Err(MpqError::InvalidFileName(name)) if name == "Units\\"
+        ));
+
+        // unencrypted files don't need a key
+        assert!(archive.open_file("Plain\\").is_ok());
+    }
+
+    #[test]
+    fn has_listfile_and_attributes() {
+        let archive = Archive::load(Builder::new().file("a.txt", DATA).build()).unwrap();
+
+        assert!(archive.has_listfile());
+        assert!(!archive.has_attributes());
+
+        let buf = Builder::new()
+            .listfile(false)
+            .file("a.txt", DATA)
+            .file("(attributes)", &[0; 8])
+            .build();
+        let archive = Archive::load(buf).unwrap();
+
+        assert!(!archive.has_listfile());
+        assert!(archive.has_attributes());
+    }
+
+    #[test]
+    fn delete_marker() {
+        // the marker comes first so it shadows the real file in the hash table
+        let buf = Builder::new()
+            .file_with("a.txt", b"", FILE_DELETE_MARKER)
+            .file("a.txt", DATA)
+            .file("b.txt", DATA)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert!(matches!(
+            archive.open_file("a.txt"),
+            Err(MpqError::FileNotFound(_))
+        ));
+        assert!(!archive.contains("a.txt"));
+        assert!(archive.is_deleted("a.txt"));
+        assert!(archive.contains("b.txt"));
+        assert!(!archive.is_deleted("b.txt"));
+
+        let blocks = archive.block_table.len();
+        assert_eq!(blocks - 1, archive.files().count());
+    }
+
+    #[test]
+    fn protected_table_sizes() {
+        let buf = Builder::new().file("a.txt", DATA).build();
+
+        assert!(!Archive::load(buf.clone()).unwrap().is_protected());
+
+        // block table claims far more entries than follow it
+        let mut inflated = buf.clone();
+        LittleEndian::write_u32(&mut inflated[0x1C..], 0x10000);
+
+        let mut archive = Archive::load(inflated).unwrap();
+
+        assert!(archive.is_protected());
+        assert_eq!(2, archive.block_table.len());
+        assert_eq!(0x10000, archive.info().block_table_count);
+        assert!(archive.computed_size() <= buf.len() as u64);
+
+        let file = archive.open_file("a.txt").unwrap();
+        assert_eq!(DATA.to_vec(), file.read_to_vec(&mut archive).unwrap());
+
+        // hash table overlapping the block table
+        let mut overlapped = buf;
+        let block_table_offset = LittleEndian::read_u32(&overlapped[0x14..]);
+        LittleEndian::write_u32(&mut overlapped[0x10..], block_table_offset - 16);
+
+        assert!(Archive::load(overlapped).unwrap().is_protected());
+    }
+
+    #[test]
+    fn find() {
+        let buf = Builder::new()
+            .file("Units\\Human\\Footman.mdx", DATA)
+            .file("Units\\Human\\Footman.blp", DATA)
+            .file("Units\\Orc\\Grunt.mdx", DATA)
+            .file("war3map.j", DATA)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert_eq!(
+            vec!["Units\\Human\\Footman.mdx", "Units\\Orc\\Grunt.mdx"],
+            archive.find("*.MDX")
+        );
+        assert_eq!(3, archive.find("units\\*").len());
+        assert_eq!(vec!["war3map.j"], archive.find("war3map.?"));
+        assert!(archive.find("*.wav").is_empty());
+    }
+
+    #[test]
+    fn valid_files() {
+        let buf = Builder::new()
+            .listfile(false)
+            .file("a.txt", DATA)
+            .file("(listfile)", b"a.txt\r\nmissing.txt\r\n(listfile)")
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert_eq!(3, archive.list_files().unwrap().len());
+        assert_eq!(vec!["a.txt", "(listfile)"], archive.valid_files());
+    }
+
+    #[test]
+    fn compact() {
+        let dead: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let dir = fixture::temp_dir("archive-compact");
+        let src = dir.join("src.mpq");
+        let dest = dir.join("dest.mpq");
+
+        fs::write(
+            &src,
+            Builder::new()
+                .file_with("a.txt", b"", FILE_DELETE_MARKER)
+                .file("a.txt", &dead)
+                .file("b.txt", DATA)
+                .file_with("Units\\c.txt", DATA, FILE_ENCRYPTED | FILE_FIX_KEY)
+                .build(),
+        )
+        .unwrap();
+
+        let mut archive = Archive::open(&src).unwrap();
+
+        assert_eq!(3, archive.compact(&dest).unwrap());
+
+        let mut compacted = Archive::open(&dest).unwrap();
+
+        assert!(fs::metadata(&dest).unwrap().len() < fs::metadata(&src).unwrap().len());
+        assert!(!compacted.contains("a.txt"));
+        assert_eq!(3, compacted.block_table.len());
+        assert_eq!(vec!["b.txt", "Units\\c.txt"], compacted.valid_files());
+
+        for name in &["b.txt", "Units\\c.txt"] {
+            let file = compacted.open_file(name).unwrap();
+
+            assert_eq!(DATA.to_vec(), file.read_to_vec(&mut compacted).unwrap());
+        }
+
+        assert!(compacted.open_file("Units\\c.txt").unwrap().is_encrypted());
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn compact_unnamed() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let dir = fixture::temp_dir("archive-compact-unnamed");
+        let src = dir.join("src.mpq");
+        let dest = dir.join("dest.mpq");
+
+        // without a listfile no block has a name to compact by
+        fs::write(
+            &src,
+            Builder::new()
+                .listfile(false)
+                .file_with("a.txt", b"", FILE_DELETE_MARKER)
+                .file("a.txt", &data)
+                .file_with_locale("b.txt", b"neutral", 0, 0)
+                .file_with_locale("b.txt", b"deutsch", 0, 0x407)
+                .file_with("c.dat", &data, FILE_IMPLODE)
+                .file_with(
+                    "d.dat",
+                    &data,
+                    FILE_COMPRESS | FILE_ENCRYPTED | FILE_FIX_KEY,
+                )
+                .file_with("e.dat", DATA, FILE_SINGLE_UNIT | FILE_ENCRYPTED)
+                .build(),
+        )
+        .unwrap();
+
+        let mut archive = Archive::open(&src).unwrap();
+        let offset = archive.open_file("d.dat").unwrap().block.offset;
+
+        assert_eq!(5, archive.compact(&dest).unwrap());
+
+        let mut compacted = Archive::open(&dest).unwrap();
+
+        assert!(!compacted.contains("a.txt"));
+        assert_eq!(6, compacted.block_table.len());
+
+        let file = compacted.open_file("b.txt").unwrap();
+        assert_eq!(
+            b"neutral".to_vec(),
+            file.read_to_vec(&mut compacted).unwrap()
+        );
+
+        let file = compacted.open_file("c.dat").unwrap();
+        assert_eq!(Method::Implode, file.method());
+        assert_eq!(data, file.read_to_vec(&mut compacted).unwrap());
+
+        let file = compacted.open_file("d.dat").unwrap();
+        assert!(file.block.offset < offset);
+        assert_eq!(data, file.read_to_vec(&mut compacted).unwrap());
+
+        let file = compacted.open_file("e.dat").unwrap();
+        assert_eq!(DATA.to_vec(), file.read_to_vec(&mut compacted).unwrap());
+
+        let mut compacted = Archive::options()
+            .default_locale(0x407)
+            .open(&dest)
+            .unwrap();
+        let file = compacted.open_file("b.txt").unwrap();
+
+        assert_eq!(
+            b"deutsch".to_vec(),
+            file.read_to_vec(&mut compacted).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn compact_unknown_key() {
+        let dir = fixture::temp_dir("archive-compact-key");
+        let src = dir.join("src.mpq");
+        let dest = dir.join("dest.mpq");
+
+        // the key of a moved single unit file can't be recovered without its name
+        fs::write(
+            &src,
+            Builder::new()
+                .listfile(false)
+                .file_with("a.txt", b"", FILE_DELETE_MARKER)
+                .file("a.txt", DATA)
+                .file_with(
+                    "c.txt",
+                    DATA,
+                    FILE_SINGLE_UNIT | FILE_ENCRYPTED | FILE_FIX_KEY,
+                )
+                .build(),
+        )
+        .unwrap();
+
+        let mut archive = Archive::open(&src).unwrap();
+
+        // a compaction that fails leaves dest alone
+        fs::write(&dest, b"existing").unwrap();
+
+        assert!(matches!(
+            archive.compact(&dest),
+            Err(MpqError::UnknownFileKey)
+        ));
+        assert_eq!(b"existing".to_vec(), fs::read(&dest).unwrap());
+
+        archive.apply_listfile(&[String::from("c.txt")]);
+        assert_eq!(1, archive.compact(&dest).unwrap());
+
+        let mut compacted = Archive::open(&dest).unwrap();
+        let file = compacted.open_file("c.txt").unwrap();
+
+        assert_eq!(DATA.to_vec(), file.read_to_vec(&mut compacted).unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn compact_het() {
+        let dir = fixture::temp_dir("archive-compact-het");
+        let src = dir.join("src.mpq");
+        let dest = dir.join("dest.mpq");
+
+        fs::write(
+            &src,
+            Builder::new()
+                .het_bet(false)
+                .file_with("a.txt", b"", FILE_DELETE_MARKER)
+                .file("b.txt", DATA)
+                .build(),
+        )
+        .unwrap();
+
+        let mut archive = Archive::open(&src).unwrap();
+
+        assert_eq!(2, archive.compact(&dest).unwrap());
+
+        let mut compacted = Archive::open(&dest).unwrap();
+        let file = compacted.open_file("b.txt").unwrap();
+
+        assert!(!compacted.contains("a.txt"));
+        assert_eq!(DATA.to_vec(), file.read_to_vec(&mut compacted).unwrap());
+
+        // without a hash table an unnamed block can't be found once copied
+        let buf = Builder::new()
+            .het_bet(false)
+            .listfile(false)
+            .file("b.txt", DATA)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert!(matches!(archive.compact(&dest), Err(MpqError::Corrupt(_))));
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn read_to_end() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let buf = Builder::new()
+            .file("a.dat", &data)
+            .file_with("b.dat", &data, FILE_COMPRESS | FILE_SINGLE_UNIT)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        // size fields smaller than what the files decode to
+        for block in &mut archive.block_table[0..2] {
+            block.unpacked_size = 1000;
+        }
+
+        for &name in &["a.dat", "b.dat"] {
+            let file = archive.open_file(name).unwrap();
+
+            assert!(file.read_to_vec(&mut archive).is_err());
+            assert_eq!(data, file.read_to_end(&mut archive, 1 << 20).unwrap());
+            assert!(file.read_to_end(&mut archive, 2000).is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn read_to_end_grows() {
+        // packs to far less than the sector size the buffer starts from
+        let data = vec![7; 100_000];
+        let buf = Builder::new()
+            .file_with("a.dat", &data, FILE_COMPRESS | FILE_SINGLE_UNIT)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        archive.block_table[0].unpacked_size = 10;
+
+        let file = archive.open_file("a.dat").unwrap();
+
+        assert!(file.packed_size() < 4096);
+        assert_eq!(data, file.read_to_end(&mut archive, 1 << 20).unwrap());
+        assert!(file.read_to_end(&mut archive, 99_999).is_err());
+    }
+
+    #[test]
+    fn read_to_vec() {
+        let data: Vec<u8> = (0..10000u32).map(|i| (i % 13) as u8).collect();
+        let buf = Builder::new()
+            .file("compressed.dat", &data)
+            .file_with("encrypted.dat", &data, FILE_COMPRESS | FILE_ENCRYPTED)
+            .file_with("stored.dat", &data, 0)
+            .file_with("single.dat", &data, FILE_COMPRESS | FILE_SINGLE_UNIT)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        for name in archive.list_files().unwrap() {
+            let file = archive.open_file(&name).unwrap();
+            let mut buf = vec![0; file.size() as usize];
+            let len = file.read(&mut archive, &mut buf).unwrap();
+
+            assert_eq!(buf[..len].to_vec(), file.read_to_vec(&mut archive).unwrap());
+            assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+        }
+    }
+
+    #[test]
+    fn read_returns_size() {
+        let data: Vec<u8> = (0..10000u32).map(|i| (i % 13) as u8).collect();
+        let buf = Builder::new()
+            .always_compress(true)
+            .file("compressed.dat", &data)
+            .file_with("imploded.dat", &data, FILE_IMPLODE)
+            .file_with("stored.dat", &data, 0)
+            .file_with("single.dat", &data, FILE_IMPLODE | FILE_SINGLE_UNIT)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        for name in archive.list_files().unwrap() {
+            let file = archive.open_file(&name).unwrap();
+            let mut buf = vec![0; file.size() as usize];
+
+            assert_eq!(
+                file.size() as usize,
+                file.read(&mut archive, &mut buf).unwrap()
+            );
+            assert_eq!(data, buf);
+        }
+    }
+
+    #[test]
+    fn set_decompressor() {
+        let data: Vec<u8> = (0..300u32).map(|i| (i % 13) as u8).collect();
+        let mut packed = vec![0x04];
+        packed.extend(data.iter().map(|b| b ^ 0x55));
+
+        let buf = Builder::new()
+            .file_with("a.dat", &packed, FILE_SINGLE_UNIT)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        // turn the stored file into one compressed with the unknown method 0x04
+        let index = archive.find_hash("a.dat").unwrap().block_index as usize;
+        archive.block_table[index].flags |= FILE_COMPRESS;
+        archive.block_table[index].unpacked_size = data.len() as u32;
+
+        let file = archive.open_file("a.dat").unwrap();
+
+        assert!(matches!(
+            file.read_to_vec(&mut archive),
+            Err(MpqError::UnsupportedCompression(0x04))
+        ));
+
+        archive.set_decompressor(
+            0x04,
+            Box::new(|src, out| {
+                for (o, b) in out.iter_mut().zip(src) {
+                    *o = b ^ 0x55;
+                }
+
+                Ok(src.len().min(out.len()))
+            }),
+        );
+
+        assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+    }
+
+    #[test]
+    fn block_index_and_name_hash() {
+        let buf = Builder::new()
+            .file("a.txt", DATA)
+            .file("b.txt", DATA)
+            .file("c.txt", DATA)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        for (i, name) in ["a.txt", "b.txt", "c.txt"].iter().enumerate() {
+            let file = archive.open_file(name).unwrap();
+            let hash_a = hash_string(name, 0x100);
+            let hash_b = hash_string(name, 0x200);
+            let entry = archive
+                .hash_table
+                .iter()
+                .find(|h| h.hash_a == hash_a && h.hash_b == hash_b)
+                .unwrap();
+
+            assert_eq!(i as u32, file.block_index());
+            assert_eq!(entry.block_index, file.block_index());
+            assert_eq!((hash_a, hash_b), file.name_hash());
+        }
+    }
+
+    #[test]
+    fn open_block_index_out_of_range() {
+        let buf = Builder::new().file("a.txt", DATA).build();
+        let mut archive = Archive::load(buf).unwrap();
+        let hash_a = hash_string("a.txt", 0x100);
+
+        for hash in &mut archive.hash_table {
+            if hash.hash_a == hash_a {
+                hash.block_index = 1000;
+            }
+        }
+
+        assert!(matches!(
+            archive.open_file("a.txt"),
+            Err(MpqError::Corrupt(_))
+        ));
+        assert!(archive.contains("a.txt"));
+    }
+
+    #[test]
+    fn read_methods() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 13) as u8).collect();
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .file_with("compressed.dat", &data, FILE_COMPRESS | FILE_SECTOR_CRC)
+            .file_with("imploded.dat", &data, FILE_IMPLODE | FILE_SECTOR_CRC)
+            .file_with("stored.dat", &data, 0)
+            .file_with(
+                "single_compressed.dat",
+                &data,
+                FILE_COMPRESS | FILE_SINGLE_UNIT,
+            )
+            .file_with(
+                "single_imploded.dat",
+                &data,
+                FILE_IMPLODE | FILE_SINGLE_UNIT,
+            )
+            .file_with("single_stored.dat", &data, FILE_SINGLE_UNIT)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        for (name, method) in &[
+            ("compressed.dat", Method::Compress),
+            ("imploded.dat", Method::Implode),
+            ("stored.dat", Method::Stored),
+            ("single_compressed.dat", Method::Compress),
+            ("single_imploded.dat", Method::Implode),
+            ("single_stored.dat", Method::Stored),
+        ] {
+            let file = archive.open_file(name).unwrap();
+
+            assert_eq!(*method, file.method());
+            assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+            assert_eq!(data, file.read_to_end(&mut archive, data.len()).unwrap());
+        }
+
+        // a stored single unit file must be exactly its unpacked size
+        let mut file = archive.open_file("single_stored.dat").unwrap();
+        file.block.packed_size -= 1;
+
+        assert!(matches!(
+            file.read_to_vec(&mut archive),
+            Err(MpqError::SizeMismatch(3000, 2999))
+        ));
+    }
+
+    #[test]
+    fn read_size_mismatch() {
+        let data: Vec<u8> = (0..10000u32).map(|i| (i % 13) as u8).collect();
+        let buf = Builder::new().file("a.dat", &data).build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        // claim the file is larger, then smaller than its sectors decode to
+        for delta in &[1, -1] {
+            let mut file = archive.open_file("a.dat").unwrap();
+            file.block.unpacked_size = (file.block.unpacked_size as i32 + delta) as u32;
+
+            let mut buf = vec![0; file.size() as usize];
+
+            assert!(file.read(&mut archive, &mut buf).is_err());
+        }
+
+        // buffer too small for the file
+        let file = archive.open_file("a.dat").unwrap();
+        let mut buf = vec![0; 4096];
+
+        assert!(file.read(&mut archive, &mut buf).is_err());
+    }
+
+    #[test]
+    fn read_single_unit_encrypted() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let buf = Builder::new()
+            .file_with("a.dat", &data, FILE_SINGLE_UNIT | FILE_ENCRYPTED)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let mut file = archive.open_file("a.dat").unwrap();
+
+        assert!(!file.is_compressed());
+        assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+
+        // raw data that doesn't match the unpacked size
+        file.block.packed_size -= 1;
+
+        assert!(matches!(
+            file.read_to_vec(&mut archive),
+            Err(MpqError::SizeMismatch(1000, 999))
+        ));
+    }
+
+    #[test]
+    fn read_range() {
+        let data: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .file_with("sectors.dat", &data, FILE_COMPRESS | FILE_ENCRYPTED)
+            .file_with("stored.dat", &data, 0)
+            .file_with("single.dat", &data, FILE_COMPRESS | FILE_SINGLE_UNIT)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        for name in archive.list_files().unwrap() {
+            let file = archive.open_file(&name).unwrap();
+            let full = file.read_to_vec(&mut archive).unwrap();
+
+            for &(offset, len) in &[(0, 10), (1000, 3000), (511, 2), (19990, 100)] {
+                let mut buf = vec![0; len];
+                let read = file
+                    .read_range(&mut archive, offset as u64, &mut buf)
+                    .unwrap();
+                let end = (offset + len).min(full.len());
+
+                assert_eq!(end - offset, read);
+                assert_eq!(&full[offset..end], &buf[..read]);
+            }
+
+            assert_eq!(
+                0,
+                file.read_range(&mut archive, 20000, &mut [0; 4]).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn extract_file() {
+        let dir = fixture::temp_dir("extract-file");
+        let buf = Builder::new().file("a.txt", DATA).build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert_eq!(
+            DATA.len(),
+            archive.extract_file("a.txt", dir.join("one")).unwrap()
+        );
+
+        let file = archive.open_file("a.txt").unwrap();
+        file.extract(&mut archive, dir.join("two")).unwrap();
+
+        assert_eq!(
+            fs::read(dir.join("two")).unwrap(),
+            fs::read(dir.join("one")).unwrap()
+        );
+        assert!(matches!(
+            archive.extract_file("missing.txt", dir.join("three")),
+            Err(MpqError::FileNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn extract_with() {
+        let dir = fixture::temp_dir("extract-with");
+        let path = dir.join("a.txt");
+        let buf = Builder::new().file("a.txt", DATA).build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("a.txt").unwrap();
+
+        fs::write(&path, b"existing").unwrap();
+
+        assert!(matches!(
+            file.extract_with(&mut archive, &path, false),
+            Err(MpqError::AlreadyExists(_))
+        ));
+        assert_eq!(b"existing".to_vec(), fs::read(&path).unwrap());
+
+        file.extract_with(&mut archive, &path, true).unwrap();
+
+        assert_eq!(DATA.to_vec(), fs::read(&path).unwrap());
+
+        // a file that fails to decode doesn't replace the existing one
+        let buf = Builder::new()
+            .file_packed("a.txt", b"corrupt", &[0x20, 0xFF])
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("a.txt").unwrap();
+
+        assert!(file.extract_with(&mut archive, &path, true).is_err());
+        assert_eq!(DATA.to_vec(), fs::read(&path).unwrap());
+        assert!(!dir.join("a.txt.part").exists());
+    }
+
+    #[test]
+    fn extract_wav() {
+        let samples: Vec<u8> = (0..10000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let dest = fixture::temp_dir("extract-wav").join("sound.wav");
+        let buf = Builder::new()
+            .file("Sound\\sound.wav", &fixture::wave(&samples))
+            .file("a.txt", DATA)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        let file = archive.open_file("Sound\\sound.wav").unwrap();
+
+        assert_eq!(
+            44 + samples.len(),
+            file.extract_wav(&mut archive, &dest).unwrap()
+        );
+
+        let wav = fs::read(&dest).unwrap();
+
+        assert_eq!(b"RIFF", &wav[0..4]);
+        assert_eq!(b"WAVE", &wav[8..12]);
+        assert_eq!(1, LittleEndian::read_u16(&wav[20..]));
+        assert_eq!(2, LittleEndian::read_u16(&wav[22..]));
+        assert_eq!(22050, LittleEndian::read_u32(&wav[24..]));
+        assert_eq!(16, LittleEndian::read_u16(&wav[34..]));
+        assert_eq!(samples.len() as u32, LittleEndian::read_u32(&wav[40..]));
+        assert_eq!(samples, &wav[44..]);
+
+        // existing files are left alone
+        assert!(matches!(
+            file.extract_wav(&mut archive, &dest),
+            Err(MpqError::AlreadyExists(_))
+        ));
+        assert!(file.extract_wav(&mut archive, "/").is_err());
+
+        let file = archive.open_file("a.txt").unwrap();
+
+        assert!(matches!(
+            file.extract_wav(&mut archive, dest.with_file_name("a.wav")),
+            Err(MpqError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn extract_to() {
+        let buf = Builder::new().file("a.txt", DATA).build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("a.txt").unwrap();
+        let mut out: Vec<u8> = Vec::new();
+
+        assert_eq!(DATA.len(), file.extract_to(&mut archive, &mut out).unwrap());
+        assert_eq!(DATA.to_vec(), out);
+        assert_eq!(
+            DATA.len(),
+            file.extract_to(&mut archive, &mut std::io::sink()).unwrap()
+        );
+    }
+
+    #[test]
+    fn open_file_many() {
+        let names: Vec<String> = (0..2000).map(|i| format!("Files\\{}.txt", i)).collect();
+        let builder = names
+            .iter()
+            .fold(Builder::new(), |b, name| b.file(name, name.as_bytes()));
+        let mut archive = Archive::load(builder.build()).unwrap();
+
+        for name in &names {
+            let file = archive.open_file(name).unwrap();
+
+            assert_eq!(
+                name.as_bytes(),
+                &file.read_to_vec(&mut archive).unwrap()[..]
+            );
+        }
+
+        assert!(matches!(
+            archive.open_file("Files\\2000.txt"),
+            Err(MpqError::FileNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn load_invalid_header() {
+        let buf = Builder::new().file("a.txt", DATA).build();
+
+        // tables larger than the archive are read as far as they go
+        let mut protected = buf.clone();
+        LittleEndian::write_u32(&mut protected[0x18..], 0x4000_0000);
+        assert!(Archive::load(protected).unwrap().is_protected());
+
+        let mut corrupt = buf.clone();
+        LittleEndian::write_u32(&mut corrupt[0x18..], 3);
+        assert!(matches!(Archive::load(corrupt), Err(MpqError::Corrupt(_))));
+
+        let mut protected = buf.clone();
+        LittleEndian::write_u32(&mut protected[0x1C..], 0xFFFF_FFFF);
+        assert!(Archive::load(protected).unwrap().is_protected());
+
+        let mut corrupt = buf;
+        LittleEndian::write_u16(&mut corrupt[0x0E..], 40);
+        assert!(matches!(Archive::load(corrupt), Err(MpqError::Corrupt(_))));
+    }
+
+    #[test]
+    fn load_unsupported_version() {
+        let buf = Builder::new().file("a.txt", DATA).build();
+
+        // newer than v4
+        let mut v5 = buf.clone();
+        LittleEndian::write_u16(&mut v5[0x0C..], 4);
+        assert!(matches!(
+            Archive::load(v5),
+            Err(MpqError::UnsupportedFormatVersion(4))
+        ));
+
+        // a v2 version with a v1 sized header has no extended fields
+        let mut v2 = buf;
+        LittleEndian::write_u16(&mut v2[0x0C..], 1);
+        let mut archive = Archive::load(v2).unwrap();
+        assert!(archive.open_file("a.txt").is_ok());
+    }
+
+    #[test]
+    fn load_het_bet() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let names: Vec<String> = (0..20).map(|i| format!("dir\\file{}.dat", i)).collect();
+        let build = |compress| {
+            let mut builder =
+                Builder::new().file_with("enc.dat", &data, FILE_COMPRESS | FILE_ENCRYPTED);
+
+            for name in &names {
+                builder = builder.file_with(name, name.as_bytes(), FILE_SINGLE_UNIT);
+            }
+
+            builder.het_bet(compress).build()
+        };
+
+        for &compress in &[false, true] {
+            let buf = build(compress);
+
+            // the tables of the v4 archive are stored compressed
+            if compress {
+                let het_offset = LittleEndian::read_u64(&buf[0x3C..]) as usize;
+                let data_size = LittleEndian::read_u32(&buf[het_offset + 8..]) as u64;
+                assert!(LittleEndian::read_u64(&buf[0x5C..]) < data_size + 12);
+            }
+
+            let mut archive = Archive::load(buf).unwrap();
+
+            assert!(archive.hash_table.is_empty());
+            assert_eq!(22, archive.block_table.len());
+            assert_eq!(
+                data,
+                archive
+                    .open_file("enc.dat")
+                    .unwrap()
+                    .read_to_vec(&mut archive)
+                    .unwrap()
+            );
+
+            for name in &names {
+                let file = archive.open_file(&name.replace('\\', "/")).unwrap();
+                assert_eq!(
+                    name.as_bytes(),
+                    &file.read_to_vec(&mut archive).unwrap()[..]
+                );
+            }
+
+            assert!(archive.open_file("missing.dat").is_err());
+            assert_eq!(21, archive.list_files().unwrap().len());
+        }
+    }
+
+    #[test]
+    fn load_hi_block_table() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let builder =
+            Builder::new()
+                .file("a.dat", &data)
+                .file_with("b.dat", &data, FILE_SINGLE_UNIT);
+
+        let mut archive = Archive::load(builder.hi_block_table(&[]).build()).unwrap();
+        let low = archive.block_table[1].offset;
+
+        assert_eq!(1, archive.info().format_version);
+        assert_eq!(0x2C, archive.info().header_size);
+
+        for name in &["a.dat", "b.dat"] {
+            let file = archive.open_file(name).unwrap();
+
+            assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+        }
+
+        // files beyond 4 GiB keep their full offset, reading them fails cleanly
+        let buf = Builder::new()
+            .file("a.dat", &data)
+            .file_with("b.dat", &data, FILE_SINGLE_UNIT)
+            .hi_block_table(&[0, 1])
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert!(archive.open_file("a.dat").is_ok());
+        assert_eq!(0x1_0000_0000 + low, archive.block_table[1].offset);
+
+        let file = archive.open_file("b.dat").unwrap();
+
+        assert_eq!(3000, file.size());
+        assert_eq!(0x1_0000_0000 + low, file.sectors()[0].offset);
+        assert!(file.read_to_vec(&mut archive).is_err());
+        assert_eq!(0x1_0000_0000 + low, archive.manifest().files[1].offset);
+    }
+
+    #[test]
+    fn open_file_invalid_sector_offsets() {
+        let data: Vec<u8> = (0..10000u32).map(|i| (i % 13) as u8).collect();
+        let buf = Builder::new().file("a.dat", &data).build();
+
+        // swap the offsets of the second and third sector
+        let mut corrupt = buf.clone();
+        let second = LittleEndian::read_u32(&corrupt[0x24..]);
+        let third = LittleEndian::read_u32(&corrupt[0x28..]);
+        LittleEndian::write_u32(&mut corrupt[0x24..], third);
+        LittleEndian::write_u32(&mut corrupt[0x28..], second);
+
+        let mut archive = Archive::load(corrupt).unwrap();
+        assert!(matches!(
+            archive.open_file("a.dat"),
+            Err(MpqError::Corrupt(_))
+        ));
+
+        // last offset beyond the packed size
+        let mut corrupt = buf;
+        LittleEndian::write_u32(&mut corrupt[0x2C..], 0xFFFF);
+
+        let mut archive = Archive::load(corrupt).unwrap();
+        assert!(matches!(
+            archive.open_file("a.dat"),
+            Err(MpqError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn read_raw_sector() {
+        let data: Vec<u8> = (0..1300u32).map(|i| (i % 13) as u8).collect();
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .file_with(
+                "a.dat",
+                &data,
+                FILE_COMPRESS | FILE_ENCRYPTED | FILE_SECTOR_CRC,
+            )
+            .file_with(
+                "b.dat",
+                &data,
+                FILE_COMPRESS | FILE_ENCRYPTED | FILE_SINGLE_UNIT,
+            )
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        for name in &["a.dat", "b.dat"] {
+            let file = archive.open_file(name).unwrap();
+            let mut out = Vec::new();
+
+            for (i, sector) in file.sectors().iter().enumerate() {
+                let raw = file.read_raw_sector(&mut archive, i).unwrap();
+                let mut buf = vec![0; sector.unpacked_size as usize];
+
+                assert_eq!(sector.packed_size as usize, raw.len());
+                assert_eq!(
+                    buf.len(),
+                    decompress(&raw, &mut buf, &mut Vec::new()).unwrap()
+                );
+
+                out.extend_from_slice(&buf);
+            }
+
+            assert_eq!(data, out);
+            assert!(matches!(
+                file.read_raw_sector(&mut archive, file.sectors().len()),
+                Err(MpqError::SectorNotFound(i)) if i == file.sectors().len()
+            ));
+        }
+    }
+
+    #[test]
+    fn sectors() {
+        let data: Vec<u8> = (0..1300u32).map(|i| (i * 31 % 251) as u8).collect();
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .file_with("a.dat", &data, FILE_COMPRESS)
+            .file_with("b.dat", &data, 0)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        let file = archive.open_file("a.dat").unwrap();
+        let sectors = file.sectors();
+        let offset_table = 4 * 4;
+
+        assert_eq!(3, sectors.len());
+        assert_eq!(
+            vec![512, 512, 276],
+            sectors
+                .iter()
+                .map(|s| s.unpacked_size)
+                .collect::<Vec<u32>>()
+        );
+        assert_eq!(file.block.offset + offset_table, sectors[0].offset);
+        assert_eq!(
+            file.packed_size() - offset_table,
+            sectors
+                .iter()
+                .map(|s| u64::from(s.packed_size))
+                .sum::<u64>()
+        );
+
+        let file = archive.open_file("b.dat").unwrap();
+        let sectors = file.sectors();
+
+        assert_eq!(3, sectors.len());
+        assert_eq!(file.block.offset + 1024, sectors[2].offset);
+        assert_eq!(276, sectors[2].packed_size);
+    }
+
+    #[test]
+    fn display() {
+        let buf = Builder::new()
+            .file("a.txt", &[b'a'; 1000])
+            .file_with("b.txt", b"hello", FILE_ENCRYPTED | FILE_SINGLE_UNIT)
+            .build();
+        let size = buf.len();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert_eq!(
+            format!("MPQ v1, 3 files, 4096 byte sectors, {} bytes", size),
+            archive.to_string()
+        );
+
+        let file = archive.open_file("b.txt").unwrap();
+
+        assert_eq!("b.txt: 5 bytes, 5 packed (100.0%), -es", file.to_string());
+        assert_eq!(
+            "         5          5 100.0% -es   b.txt",
+            format!("{:#}", file)
+        );
+
+        let file = archive.open_file("a.txt").unwrap();
+        let display = file.to_string();
+
+        assert!(display.starts_with("a.txt: 1000 bytes, "));
+        assert!(display.ends_with("%), c--"));
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn read_stored_sectors() {
+        // noise that doesn't compress between runs that do
+        let mut seed: u32 = 1;
+        let mut data: Vec<u8> = vec![0; 1024];
+        data.extend((0..512).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        }));
+        data.extend(vec![7; 700]);
+
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .file("a.dat", &data)
+            .file_with("b.dat", &data, FILE_COMPRESS | FILE_SINGLE_UNIT)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("a.dat").unwrap();
+        let sectors = file.sectors();
+
+        assert!(sectors[1].packed_size < sectors[1].unpacked_size);
+        assert_eq!(sectors[2].unpacked_size, sectors[2].packed_size);
+        assert!(sectors[3].packed_size < sectors[3].unpacked_size);
+        assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+
+        let file = archive.open_file("b.dat").unwrap();
+
+        assert!(file.packed_size() < file.size());
+        assert_eq!(data, file.read_to_vec(&mut archive).unwrap());
+    }
+
+    #[test]
+    fn read_many_sectors() {
+        let data: Vec<u8> = (0..1 << 20).map(|i: u32| (i * 31 % 251) as u8).collect();
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .file_with("big.dat", &data, FILE_COMPRESS | FILE_ENCRYPTED)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("big.dat").unwrap();
+        let mut buf = vec![0; file.size() as usize];
+
+        assert_eq!(2048, file.sector_offsets.len() - 1);
+        assert_eq!(data.len(), file.read(&mut archive, &mut buf).unwrap());
+        assert_eq!(data, buf);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn read_parallel() {
+        let data: Vec<u8> = (0..1 << 20).map(|i: u32| (i * 31 % 251) as u8).collect();
+        let buf = Builder::new()
+            .sector_size_shift(0)
+            .file_with(
+                "big.dat",
+                &data,
+                FILE_COMPRESS | FILE_ENCRYPTED | FILE_SECTOR_CRC,
+            )
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let file = archive.open_file("big.dat").unwrap();
+        let mut serial = vec![0; file.size() as usize];
+        let mut parallel = vec![0; file.size() as usize];
+
+        file.read(&mut archive, &mut serial).unwrap();
+
+        assert_eq!(
+            data.len(),
+            file.read_parallel(&mut archive, &mut parallel).unwrap()
+        );
+        assert_eq!(serial, parallel);
+
+        // a block claiming more than its sectors decode to
+        let mut file = archive.open_file("big.dat").unwrap();
+        file.block.unpacked_size += 1;
+        let mut parallel = vec![0; file.size() as usize];
+
+        assert!(matches!(
+            file.read_parallel(&mut archive, &mut parallel),
+            Err(MpqError::SizeMismatch(expected, read)) if expected == data.len() + 1 && read == data.len()
+        ));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn extract_all_parallel() {
+        let dir = fixture::temp_dir("extract-all-parallel");
+        let mut builder = Builder::new().sector_size_shift(0);
+
+        for i in 0..16u32 {
+            let data: Vec<u8> = (0..i * 700).map(|j| (j * (i + 3) % 251) as u8).collect();
+            let flags = match i % 3 {
+                0 => FILE_COMPRESS,
+                1 => FILE_COMPRESS | FILE_ENCRYPTED | FILE_SECTOR_CRC,
+                _ => FILE_COMPRESS | FILE_SINGLE_UNIT,
+            };
+
+            builder = builder.file_with(&format!("dir{}\\file{}.dat", i % 4, i), &data, flags);
+        }
+
+        let mut archive = Archive::load(builder.build()).unwrap();
+        let names = archive.list_files().unwrap();
+
+        for name in &names {
+            let file = archive.open_file(name).unwrap();
+            file.extract_tree(&mut archive, dir.join("serial")).unwrap();
+        }
+
+        assert_eq!(
+            names.len(),
+            archive
+                .extract_all_parallel(dir.join("parallel"), 4)
+                .unwrap()
+        );
+
+        for name in &names {
+            let path = name.replace('\\', "/");
+            let serial = fs::read(dir.join("serial").join(&path)).unwrap();
+            let parallel = fs::read(dir.join("parallel").join(&path)).unwrap();
+
+            assert_eq!(serial, parallel);
+        }
+    }
+
+    #[test]
+    fn from_slice() {
+        let path = fixture::temp_dir("from-slice").join("test.mpq");
+        let buf = Builder::new()
+            .file("a.txt", DATA)
+            .file_with("b.txt", DATA, FILE_COMPRESS | FILE_ENCRYPTED)
+            .build();
+        fs::write(&path, buf).unwrap();
+
+        let buf = fs::read(&path).unwrap();
+        let mut archive = Archive::from_slice(&buf).unwrap();
+
+        assert_eq!(buf.as_ptr(), archive.file.get_ref().as_ptr());
+
+        for name in &["a.txt", "b.txt"] {
+            let file = archive.open_file(name).unwrap();
+
+            assert_eq!(DATA, &file.read_to_vec(&mut archive).unwrap()[..]);
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn open_mmap() {
+        let dir = fixture::temp_dir("open-mmap");
+        let path = dir.join("test.mpq");
+        let buf = Builder::new()
+            .file("a.txt", DATA)
+            .file_with("b.txt", DATA, FILE_COMPRESS | FILE_ENCRYPTED)
+            .build();
+        fs::write(&path, buf).unwrap();
+
+        let mut archive = Archive::open(&path).unwrap();
+        let mut mapped = Archive::open_mmap(&path).unwrap();
+
+        for name in &["a.txt", "b.txt", "(listfile)"] {
+            let file = archive.open_file(name).unwrap();
+            let mut expected = vec![0; file.size() as usize];
+            file.read(&mut archive, &mut expected).unwrap();
+
+            let file = mapped.open_file(name).unwrap();
+            let mut buf = vec![0; file.size() as usize];
+            file.read(&mut mapped, &mut buf).unwrap();
+
+            assert_eq!(expected, buf);
+        }
     }
 }
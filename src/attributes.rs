@@ -0,0 +1,169 @@
+use crate::error::MpqError;
+use byteorder::{ByteOrder, LittleEndian};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const ATTRIBUTES_VERSION: u32 = 100;
+
+const ATTRIBUTE_CRC32: u32 = 0x00000001; // file has crc32 of each block
+const ATTRIBUTE_FILETIME: u32 = 0x00000002; // file has FILETIME of each block
+const ATTRIBUTE_MD5: u32 = 0x00000004; // file has md5 of each block
+const ATTRIBUTE_ALL: u32 = 0x0000000F; // includes the patch bit array, which is not exposed
+
+// FILETIME of the unix epoch, in 100 nanosecond intervals since 1601
+const FILETIME_UNIX_EPOCH: u64 = 116444736000000000;
+
+/// Contents of the `(attributes)` file
+///
+/// Each vector is indexed the same as the block table and is empty if the
+/// archive does not store that attribute.
+#[derive(Debug, Clone, Default)]
+pub struct Attributes {
+    /// CRC32 of each file's uncompressed data
+    pub crc32: Vec<u32>,
+    /// last modification time of each file as a windows FILETIME
+    pub timestamp: Vec<u64>,
+    /// MD5 of each file's uncompressed data
+    pub md5: Vec<[u8; 16]>,
+}
+
+impl Attributes {
+    pub fn new(src: &[u8], block_count: usize) -> Result<Attributes, MpqError> {
+        if src.len() < 8 {
+            return Err(MpqError::Corrupt("Attributes file too short"));
+        }
+
+        let version = LittleEndian::read_u32(src);
+        let flags = LittleEndian::read_u32(&src[4..]);
+
+        if version != ATTRIBUTES_VERSION || flags & !ATTRIBUTE_ALL != 0 {
+            return Err(MpqError::Corrupt("Unsupported attributes version"));
+        }
+
+        let mut entry_size = 0;
+
+        if flags & ATTRIBUTE_CRC32 != 0 {
+            entry_size += 4;
+        }
+
+        if flags & ATTRIBUTE_FILETIME != 0 {
+            entry_size += 8;
+        }
+
+        if flags & ATTRIBUTE_MD5 != 0 {
+            entry_size += 16;
+        }
+
+        // some archivers omit the entry for the (attributes) file itself
+        let data = &src[8..];
+        let count = if data.len() >= block_count * entry_size {
+            block_count
+        } else if block_count > 0 && data.len() >= (block_count - 1) * entry_size {
+            block_count - 1
+        } else {
+            return Err(MpqError::Corrupt("Attributes file truncated"));
+        };
+
+        let mut attributes = Attributes::default();
+        let mut pos = 0;
+
+        if flags & ATTRIBUTE_CRC32 != 0 {
+            for _ in 0..count {
+                attributes.crc32.push(LittleEndian::read_u32(&data[pos..]));
+                pos += 4;
+            }
+        }
+
+        if flags & ATTRIBUTE_FILETIME != 0 {
+            for _ in 0..count {
+                attributes
+                    .timestamp
+                    .push(LittleEndian::read_u64(&data[pos..]));
+                pos += 8;
+            }
+        }
+
+        if flags & ATTRIBUTE_MD5 != 0 {
+            for _ in 0..count {
+                let mut md5 = [0; 16];
+                md5.copy_from_slice(&data[pos..pos + 16]);
+                attributes.md5.push(md5);
+                pos += 16;
+            }
+        }
+
+        Ok(attributes)
+    }
+
+    // modification time stored for block_index, none if there is no timestamp or
+    // it is zero
+    pub fn modified(&self, block_index: u32) -> Option<SystemTime> {
+        let filetime = match self.timestamp.get(block_index as usize) {
+            Some(&v) if v != 0 => v,
+            _ => return None,
+        };
+        let intervals = |v: u64| Duration::new(v / 10_000_000, (v % 10_000_000) as u32 * 100);
+
+        if filetime >= FILETIME_UNIX_EPOCH {
+            UNIX_EPOCH.checked_add(intervals(filetime - FILETIME_UNIX_EPOCH))
+        } else {
+            UNIX_EPOCH.checked_sub(intervals(FILETIME_UNIX_EPOCH - filetime))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Attributes;
+    use crate::archive::Archive;
+    use crate::fixture::Builder;
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn read_attributes() {
+        // crc32 and filetime for "a.txt", "(attributes)" and "(listfile)"
+        let mut attributes = vec![0; 8 + 3 * 12];
+        LittleEndian::write_u32(&mut attributes[0..], 100);
+        LittleEndian::write_u32(&mut attributes[4..], 0x3);
+        LittleEndian::write_u32(&mut attributes[8..], 0xDEADBEEF);
+        LittleEndian::write_u64(&mut attributes[20..], 0x01D9_0000_1234_5678);
+
+        let buf = Builder::new()
+            .file("a.txt", b"hello")
+            .file("(attributes)", &attributes)
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let attributes = archive.read_attributes().unwrap().unwrap();
+
+        assert_eq!(3, attributes.crc32.len());
+        assert_eq!(0xDEADBEEF, attributes.crc32[0]);
+        assert_eq!(0x01D9_0000_1234_5678, attributes.timestamp[0]);
+        assert!(attributes.md5.is_empty());
+    }
+
+    #[test]
+    fn modified() {
+        let attributes = Attributes {
+            timestamp: vec![0x01D9_0000_1234_5678, 0, 116444736000000000 + 15],
+            ..Attributes::default()
+        };
+
+        let expected = UNIX_EPOCH + Duration::new(1669292828, 956018400);
+
+        assert_eq!(Some(expected), attributes.modified(0));
+        assert_eq!(None, attributes.modified(1));
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_nanos(1500)),
+            attributes.modified(2)
+        );
+        assert_eq!(None, attributes.modified(3));
+    }
+
+    #[test]
+    fn missing_attributes() {
+        let buf = Builder::new().file("a.txt", b"hello").build();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert!(archive.read_attributes().unwrap().is_none());
+    }
+}
@@ -1,106 +1,842 @@
-use crate::archive::Archive;
+use crate::archive::{local_path, Archive, File};
+use crate::attributes::Attributes;
+use crate::error::MpqError;
+use crate::listfile::parse_listfile;
+use crate::patch::apply_patch;
 use std::collections::HashSet;
-use std::io::{Error, ErrorKind};
+use std::fs;
+use std::io::Write;
 use std::path::Path;
 
+/// A stack of archives searched in priority order
+///
+/// Files are resolved from the highest priority archive to the lowest. Among
+/// archives of equal priority the most recently added one is searched first.
 #[derive(Default)]
 pub struct Chain {
     chain: Vec<Archive>,
+    priorities: Vec<i32>,
 }
 
 impl Chain {
     pub fn new() -> Self {
-        Chain { chain: Vec::new() }
+        Chain {
+            chain: Vec::new(),
+            priorities: Vec::new(),
+        }
     }
 
     pub fn size(&self) -> usize {
         self.chain.len()
     }
 
-    pub fn add<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
-        match Archive::open(path) {
-            Ok(v) => self.chain.insert(0, v),
-            Err(e) => return Err(e),
+    // add archive with a higher priority than any archive already in the chain
+    pub fn add<P: AsRef<Path>>(&mut self, path: P) -> Result<(), MpqError> {
+        let priority = match self.priorities.first() {
+            Some(v) => v.saturating_add(1),
+            None => 0,
+        };
+
+        self.add_with_priority(path, priority)
+    }
+
+    // add archives in load order, each above those before it. stops at the first
+    // that fails to open and returns its position in paths, the archives added
+    // before it stay in the chain
+    pub fn add_all<P: AsRef<Path>>(&mut self, paths: &[P]) -> Result<(), (usize, MpqError)> {
+        for (i, path) in paths.iter().enumerate() {
+            self.add(path).map_err(|e| (i, e))?;
         }
 
         Ok(())
     }
 
-    pub fn read(&mut self, filename: &str) -> Result<Vec<u8>, Error> {
-        for archive in &mut self.chain.iter_mut() {
-            if let Ok(file) = archive.open_file(filename) {
-                let mut buf: Vec<u8> = vec![0; file.size() as usize];
+    pub fn add_with_priority<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        priority: i32,
+    ) -> Result<(), MpqError> {
+        let archive = Archive::open(path)?;
+        let index = self
+            .priorities
+            .iter()
+            .position(|&p| p <= priority)
+            .unwrap_or(self.chain.len());
 
-                match file.read(archive, &mut buf) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        println!("{} {:#?}", e, archive);
-                    }
-                }
+        self.chain.insert(index, archive);
+        self.priorities.insert(index, priority);
+
+        Ok(())
+    }
+
+    // remove the archive at index, where index 0 is searched first
+    pub fn remove(&mut self, index: usize) -> Option<Archive> {
+        if index >= self.chain.len() {
+            return None;
+        }
+
+        self.priorities.remove(index);
 
-                return Ok(buf);
+        Some(self.chain.remove(index))
+    }
+
+    // whether any archive in the chain has filename in its hash table, unless a
+    // deletion marker above it hides it
+    pub fn contains(&mut self, filename: &str) -> bool {
+        for archive in &self.chain {
+            if archive.is_deleted(filename) {
+                return false;
+            }
+
+            if archive.contains(filename) {
+                return true;
             }
         }
 
-        Err(Error::new(
-            ErrorKind::NotFound,
-            "File not found in mpq chain",
-        ))
+        false
     }
 
-    pub fn list(&mut self) -> Result<Vec<String>, Error> {
-        let mut contents: HashSet<String> = HashSet::new();
+    // number of archives in the chain with filename in their hash table, however
+    // deep the overrides go. deletion markers aren't counted
+    pub fn count_sources(&mut self, filename: &str) -> usize {
+        self.chain
+            .iter()
+            .filter(|archive| archive.contains(filename))
+            .count()
+    }
 
-        for archive in &mut self.chain.iter_mut() {
-            if let Ok(file) = archive.open_file("(listfile)") {
-                let mut buf: Vec<u8> = vec![0; file.size() as usize];
-
-                match file.read(archive, &mut buf) {
-                    Ok(_) => {}
-                    Err(e) => return Err(e),
-                };
-
-                let archive_string = match String::from_utf8(buf) {
-                    Ok(v) => v,
-                    Err(_) => return Err(Error::new(ErrorKind::InvalidData, "Utf8Error")),
-                };
-
-                contents.extend(
-                    archive_string
-                        .lines()
-                        .map(String::from)
-                        .collect::<Vec<String>>(),
-                );
+    // open filename from the first archive that has it, along with the index of
+    // that archive. a deletion marker hides the file in every archive below it
+    fn open_file(&mut self, filename: &str) -> Result<(usize, File), MpqError> {
+        for (index, archive) in &mut self.chain.iter_mut().enumerate() {
+            if archive.is_deleted(filename) {
+                break;
+            }
+
+            if let Ok(file) = archive.open_file(filename) {
+                return Ok((index, file));
             }
         }
 
-        Ok(contents.into_iter().collect::<Vec<String>>())
+        Err(MpqError::FileNotFound(String::from(filename)))
     }
 
-    pub fn read_to_string(&mut self, filename: &str) -> Result<String, Error> {
-        match self.read(filename) {
-            Ok(buf) => match String::from_utf8(buf) {
-                Ok(v) => Ok(v),
-                Err(_) => Err(Error::new(ErrorKind::InvalidData, "Utf8Error")),
-            },
-            Err(e) => Err(e),
+    // apply an external listfile to every archive in the chain, see
+    // Archive::apply_listfile
+    pub fn add_listfile<P: AsRef<Path>>(&mut self, path: P) -> Result<(), MpqError> {
+        let names = parse_listfile(&fs::read(path)?);
+
+        for archive in &mut self.chain.iter_mut() {
+            archive.apply_listfile(&names);
         }
+
+        Ok(())
     }
 
-    // extract file from archive to the local filesystem
-    pub fn extract<P: AsRef<Path>>(&mut self, filename: &str, path: P) -> Result<usize, Error> {
+    pub fn read(&mut self, filename: &str) -> Result<Vec<u8>, MpqError> {
+        self.read_from(filename).map(|(_, buf)| buf)
+    }
+
+    // read file, also returning the index of the archive that provided it
+    pub fn read_from(&mut self, filename: &str) -> Result<(usize, Vec<u8>), MpqError> {
+        let (index, file) = self.open_file(filename)?;
+        let buf = file.read_to_vec(&mut self.chain[index])?;
+
+        Ok((index, buf))
+    }
+
+    // stream file to w without reading it into memory first, returns the number of
+    // bytes written
+    pub fn read_to_writer<W: Write>(
+        &mut self,
+        filename: &str,
+        w: &mut W,
+    ) -> Result<usize, MpqError> {
+        let (index, file) = self.open_file(filename)?;
+
+        file.extract_to(&mut self.chain[index], w)
+    }
+
+    // listed files in priority order, each name appears once at the position of
+    // the archive it resolves to
+    pub fn list(&mut self) -> Result<Vec<String>, MpqError> {
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut contents: Vec<String> = Vec::new();
+
         for archive in &mut self.chain.iter_mut() {
+            for name in archive.list_files()? {
+                if seen.insert(name.clone()) {
+                    contents.push(name);
+                }
+            }
+        }
+
+        Ok(contents)
+    }
+
+    // lazily iterate listed files in priority order, skipping duplicates and
+    // archives whose listfile can't be read
+    pub fn iter_files(&mut self) -> impl Iterator<Item = String> + '_ {
+        let mut seen: HashSet<String> = HashSet::new();
+
+        self.chain
+            .iter_mut()
+            .flat_map(|archive| archive.list_files().unwrap_or_default())
+            .filter(move |name| seen.insert(name.clone()))
+    }
+
+    // read filename from the highest archive holding it in full, with the patch
+    // files of every archive above that one applied from the lowest up
+    pub fn read_patched(&mut self, filename: &str) -> Result<Vec<u8>, MpqError> {
+        let mut patches = Vec::new();
+
+        for archive in &mut self.chain {
+            if archive.is_deleted(filename) {
+                break;
+            }
+
             let file = match archive.open_file(filename) {
-                Ok(f) => f,
+                Ok(v) => v,
                 Err(_) => continue,
             };
 
-            return file.extract(archive, path);
+            if file.is_patch_file() {
+                patches.push(file.read_patch(archive)?);
+                continue;
+            }
+
+            let mut data = file.read_to_vec(archive)?;
+
+            for patch in patches.iter().rev() {
+                data = apply_patch(&data, patch)?;
+            }
+
+            return Ok(data);
+        }
+
+        Err(MpqError::FileNotFound(String::from(filename)))
+    }
+
+    pub fn read_to_string(&mut self, filename: &str) -> Result<String, MpqError> {
+        Ok(String::from_utf8(self.read(filename)?)?)
+    }
+
+    // extract file from archive to the local filesystem
+    pub fn extract<P: AsRef<Path>>(&mut self, filename: &str, path: P) -> Result<usize, MpqError> {
+        let (index, file) = self.open_file(filename)?;
+
+        file.extract(&mut self.chain[index], path)
+    }
+
+    // extract file below dest, recreating the archive's directory structure
+    pub fn extract_tree<P: AsRef<Path>>(
+        &mut self,
+        filename: &str,
+        dest: P,
+    ) -> Result<usize, MpqError> {
+        let (index, file) = self.open_file(filename)?;
+
+        file.extract_tree(&mut self.chain[index], dest)
+    }
+
+    // extract every listed file below dest, returns the number of files extracted
+    // and the name and error of each that failed. names no archive has are skipped
+    pub fn extract_all<P: AsRef<Path>>(
+        &mut self,
+        dest: P,
+    ) -> Result<(usize, Vec<(String, MpqError)>), MpqError> {
+        let mut count = 0;
+        let mut failed = Vec::new();
+
+        for filename in self.list()? {
+            match self.extract_tree(&filename, dest.as_ref()) {
+                Ok(_) => count += 1,
+                Err(MpqError::FileNotFound(_)) => {}
+                Err(e) => failed.push((filename, e)),
+            }
+        }
+
+        Ok((count, failed))
+    }
+
+    // like extract_all, giving each file the modification time stored in the
+    // (attributes) of the archive it comes from
+    pub fn extract_all_with_times<P: AsRef<Path>>(
+        &mut self,
+        dest: P,
+    ) -> Result<(usize, Vec<(String, MpqError)>), MpqError> {
+        let attributes: Vec<Attributes> = self
+            .chain
+            .iter_mut()
+            .map(|archive| archive.read_attributes().ok().flatten().unwrap_or_default())
+            .collect();
+        let mut count = 0;
+        let mut failed = Vec::new();
+
+        for filename in self.list()? {
+            let extracted = self.open_file(&filename).and_then(|(index, file)| {
+                let path = local_path(dest.as_ref(), file.name())?;
+
+                file.extract_with_times(&mut self.chain[index], path, &attributes[index])
+            });
+
+            match extracted {
+                Ok(_) => count += 1,
+                Err(MpqError::FileNotFound(_)) => {}
+                Err(e) => failed.push((filename, e)),
+            }
+        }
+
+        Ok((count, failed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Chain;
+    use crate::archive::{FILE_COMPRESS, FILE_DELETE_MARKER, FILE_PATCH_FILE, FILE_SECTOR_CRC};
+    use crate::attributes::Attributes;
+    use crate::error::MpqError;
+    use crate::fixture::{self, Builder};
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::fs;
+
+    #[test]
+    fn add_with_priority() {
+        let dir = fixture::temp_dir("chain-priority");
+        let base = dir.join("base.mpq");
+        let patch = dir.join("patch.mpq");
+        let extra = dir.join("extra.mpq");
+
+        fs::write(&base, Builder::new().file("a.txt", b"base").build()).unwrap();
+        fs::write(&patch, Builder::new().file("a.txt", b"patch").build()).unwrap();
+        fs::write(&extra, Builder::new().file("a.txt", b"extra").build()).unwrap();
+
+        let mut chain = Chain::new();
+        chain.add_with_priority(&base, 10).unwrap();
+        chain.add_with_priority(&patch, 0).unwrap();
+
+        assert_eq!("base", chain.read_to_string("a.txt").unwrap());
+
+        chain.add(&extra).unwrap();
+
+        assert_eq!("extra", chain.read_to_string("a.txt").unwrap());
+        assert_eq!(3, chain.size());
+    }
+
+    #[test]
+    fn contains_and_remove() {
+        let dir = fixture::temp_dir("chain-contains-remove");
+        let base = dir.join("base.mpq");
+        let patch = dir.join("patch.mpq");
+
+        let archive = Builder::new()
+            .file("a.txt", b"base")
+            .file("b.txt", b"base only")
+            .build();
+        fs::write(&base, archive).unwrap();
+        fs::write(&patch, Builder::new().file("a.txt", b"patch").build()).unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&base).unwrap();
+        chain.add(&patch).unwrap();
+
+        assert!(chain.contains("a.txt"));
+        assert!(chain.contains("B.TXT"));
+        assert!(!chain.contains("c.txt"));
+        assert_eq!("patch", chain.read_to_string("a.txt").unwrap());
+
+        // dropping the patch exposes the base layer again
+        let removed = chain.remove(0).unwrap();
+
+        assert!(removed.contains("a.txt"));
+        assert!(!removed.contains("b.txt"));
+        assert_eq!(1, chain.size());
+        assert_eq!("base", chain.read_to_string("a.txt").unwrap());
+        assert!(chain.remove(1).is_none());
+
+        // new layers still take priority over what remains
+        chain.add(&patch).unwrap();
+
+        assert_eq!("patch", chain.read_to_string("a.txt").unwrap());
+    }
+
+    #[test]
+    fn read_from() {
+        let dir = fixture::temp_dir("chain-read-from");
+        let base = dir.join("base.mpq");
+        let patch = dir.join("patch.mpq");
+
+        let archive = Builder::new()
+            .file("a.txt", b"base")
+            .file("b.txt", b"base only")
+            .file_packed("bad.txt", b"corrupt", &[0x20, 0xFF])
+            .build();
+        fs::write(&base, archive).unwrap();
+        fs::write(&patch, Builder::new().file("a.txt", b"patch").build()).unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&base).unwrap();
+        chain.add(&patch).unwrap();
+
+        assert_eq!((0, b"patch".to_vec()), chain.read_from("a.txt").unwrap());
+        assert_eq!(
+            (1, b"base only".to_vec()),
+            chain.read_from("b.txt").unwrap()
+        );
+        assert!(chain.read_from("c.txt").is_err());
+        // a file failing to decode is an error, not a zeroed buffer
+        assert!(chain.read_from("bad.txt").is_err());
+        assert!(chain.read_to_string("bad.txt").is_err());
+    }
+
+    #[test]
+    fn read_to_writer() {
+        let dir = fixture::temp_dir("chain-read-to-writer");
+        let base = dir.join("base.mpq");
+        let patch = dir.join("patch.mpq");
+        let large: Vec<u8> = (0..50000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+        fs::write(&base, Builder::new().file("a.dat", &large).build()).unwrap();
+        fs::write(&patch, Builder::new().file("b.txt", b"patch").build()).unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&base).unwrap();
+        chain.add(&patch).unwrap();
+
+        let mut out = Vec::new();
+
+        assert_eq!(
+            large.len(),
+            chain.read_to_writer("a.dat", &mut out).unwrap()
+        );
+        assert_eq!(chain.read("a.dat").unwrap(), out);
+
+        let mut out = Vec::new();
+
+        assert_eq!(5, chain.read_to_writer("b.txt", &mut out).unwrap());
+        assert_eq!(b"patch".to_vec(), out);
+        assert!(chain.read_to_writer("c.txt", &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn add_all() {
+        let dir = fixture::temp_dir("chain-add-all");
+        let base = dir.join("base.mpq");
+        let bad = dir.join("bad.mpq");
+        let patch = dir.join("patch.mpq");
+
+        fs::write(&base, Builder::new().file("a.txt", b"base").build()).unwrap();
+        fs::write(&bad, b"not an archive").unwrap();
+        fs::write(&patch, Builder::new().file("a.txt", b"patch").build()).unwrap();
+
+        let mut chain = Chain::new();
+
+        match chain.add_all(&[&base, &bad, &patch]) {
+            Err((1, _)) => {}
+            v => panic!("unexpected {:?}", v.map_err(|(i, _)| i)),
+        }
+
+        assert_eq!(1, chain.size());
+        assert_eq!(b"base".to_vec(), chain.read("a.txt").unwrap());
+
+        let mut chain = Chain::new();
+        chain.add_all(&[&base, &patch]).unwrap();
+
+        assert_eq!(2, chain.size());
+        assert_eq!(b"patch".to_vec(), chain.read("a.txt").unwrap());
+    }
+
+    #[test]
+    fn count_sources() {
+        let dir = fixture::temp_dir("chain-count-sources");
+        let mut chain = Chain::new();
+
+        for (name, files) in &[
+            ("base.mpq", &["a.txt", "b.txt"][..]),
+            ("extra.mpq", &["b.txt"][..]),
+            ("patch.mpq", &["a.txt"][..]),
+        ] {
+            let path = dir.join(name);
+            let archive = files
+                .iter()
+                .fold(Builder::new(), |builder, file| builder.file(file, b"data"))
+                .build();
+
+            fs::write(&path, archive).unwrap();
+            chain.add(&path).unwrap();
         }
 
-        Err(Error::new(
-            ErrorKind::NotFound,
-            "File not found in mpq chain",
-        ))
+        assert_eq!(2, chain.count_sources("a.txt"));
+        assert_eq!(2, chain.count_sources("B.TXT"));
+        assert_eq!(3, chain.count_sources("(listfile)"));
+        assert_eq!(0, chain.count_sources("c.txt"));
+    }
+
+    #[test]
+    fn extract_all_with_times() {
+        let dir = fixture::temp_dir("chain-extract-times");
+        let dest = dir.join("out");
+        let path = dir.join("base.mpq");
+
+        // filetime of "Units\\a.txt", "(attributes)" and "(listfile)"
+        let mut attributes = vec![0; 8 + 3 * 8];
+        LittleEndian::write_u32(&mut attributes[0..], 100);
+        LittleEndian::write_u32(&mut attributes[4..], 0x2);
+        LittleEndian::write_u64(&mut attributes[8..], 0x01D9_0000_1234_5678);
+
+        let archive = Builder::new()
+            .file("Units\\a.txt", b"a")
+            .file("(attributes)", &attributes)
+            .build();
+        fs::write(&path, archive).unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&path).unwrap();
+
+        let (count, failed) = chain.extract_all_with_times(&dest).unwrap();
+
+        assert_eq!(2, count);
+        assert!(failed.is_empty());
+
+        let expected = Attributes {
+            timestamp: vec![0x01D9_0000_1234_5678],
+            ..Attributes::default()
+        };
+        let modified = fs::metadata(dest.join("Units").join("a.txt"))
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        assert_eq!(expected.modified(0).unwrap(), modified);
+    }
+
+    #[test]
+    fn extract_traversal() {
+        let dir = fixture::temp_dir("chain-extract-traversal");
+        let dest = dir.join("out");
+        let path = dir.join("base.mpq");
+
+        let archive = Builder::new()
+            .file("..\\evil.txt", b"evil")
+            .file("Units\\..\\..\\evil.txt", b"evil")
+            .file("a.txt", b"a")
+            .build();
+        fs::write(&path, archive).unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&path).unwrap();
+
+        match chain.extract_tree("..\\evil.txt", &dest) {
+            Err(MpqError::InvalidFileName(name)) => assert_eq!("..\\evil.txt", name),
+            v => panic!("unexpected {:?}", v),
+        }
+
+        let (count, failed) = chain.extract_all(&dest).unwrap();
+        let names: Vec<&str> = failed.iter().map(|(name, _)| name.as_str()).collect();
+
+        assert_eq!(1, count);
+        assert_eq!(vec!["..\\evil.txt", "Units\\..\\..\\evil.txt"], names);
+        assert!(dest.join("a.txt").exists());
+        assert!(!dir.join("evil.txt").exists());
+        assert!(!dest.join("Units").exists());
+    }
+
+    #[test]
+    fn extract_all() {
+        let dir = fixture::temp_dir("chain-extract-all");
+        let base = dir.join("base.mpq");
+        let patch = dir.join("patch.mpq");
+        let dest = dir.join("out");
+
+        let archive = Builder::new()
+            .file("Units\\Human\\Footman.mdx", b"footman")
+            .file("readme.txt", b"base")
+            .file_packed("bad.txt", b"corrupt", &[0x20, 0xFF])
+            .build();
+        fs::write(&base, archive).unwrap();
+        fs::write(&patch, Builder::new().file("readme.txt", b"patch").build()).unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&base).unwrap();
+        chain.add(&patch).unwrap();
+
+        // a file that fails to extract is reported by name with its error
+        let (count, failed) = chain.extract_all(&dest).unwrap();
+
+        assert_eq!(2, count);
+        assert_eq!(1, failed.len());
+        assert_eq!("bad.txt", failed[0].0);
+        assert!(!matches!(failed[0].1, MpqError::FileNotFound(_)));
+        assert_eq!(
+            b"footman".to_vec(),
+            fs::read(dest.join("Units").join("Human").join("Footman.mdx")).unwrap()
+        );
+        assert_eq!(
+            b"patch".to_vec(),
+            fs::read(dest.join("readme.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn add_listfile() {
+        let dir = fixture::temp_dir("chain-add-listfile");
+        let base = dir.join("base.mpq");
+        let listfile = dir.join("listfile.txt");
+        let dest = dir.join("out");
+
+        let archive = Builder::new()
+            .listfile(false)
+            .file("Units\\Human\\Footman.mdx", b"footman")
+            .file("readme.txt", b"base")
+            .build();
+        fs::write(&base, archive).unwrap();
+        fs::write(&listfile, "readme.txt\r\nUnits\\Human\\Footman.mdx\r\n").unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&base).unwrap();
+
+        assert!(chain.list().unwrap().is_empty());
+
+        chain.add_listfile(&listfile).unwrap();
+
+        assert_eq!(
+            vec!["readme.txt", "Units\\Human\\Footman.mdx"],
+            chain.list().unwrap()
+        );
+        assert_eq!(2, chain.extract_all(&dest).unwrap().0);
+        assert_eq!(
+            b"footman".to_vec(),
+            fs::read(dest.join("Units").join("Human").join("Footman.mdx")).unwrap()
+        );
+    }
+
+    #[test]
+    fn iter_files() {
+        let dir = fixture::temp_dir("chain-iter-files");
+        let base = dir.join("base.mpq");
+        let patch = dir.join("patch.mpq");
+
+        let archive = Builder::new()
+            .file("c.txt", b"base")
+            .file("a.txt", b"base")
+            .file("b.txt", b"base")
+            .build();
+        fs::write(&base, archive).unwrap();
+
+        let archive = Builder::new()
+            .file("d.txt", b"patch")
+            .file("a.txt", b"patch")
+            .build();
+        fs::write(&patch, archive).unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&base).unwrap();
+        chain.add(&patch).unwrap();
+
+        assert_eq!(
+            vec!["d.txt", "a.txt", "c.txt", "b.txt"],
+            chain.iter_files().collect::<Vec<String>>()
+        );
+    }
+
+    #[test]
+    fn delete_marker() {
+        let dir = fixture::temp_dir("chain-delete-marker");
+        let base = dir.join("base.mpq");
+        let patch = dir.join("patch.mpq");
+
+        fs::write(
+            &base,
+            Builder::new()
+                .file("a.txt", b"base")
+                .file("b.txt", b"base")
+                .build(),
+        )
+        .unwrap();
+        fs::write(
+            &patch,
+            Builder::new()
+                .file_with("a.txt", b"", FILE_DELETE_MARKER)
+                .build(),
+        )
+        .unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&base).unwrap();
+
+        assert!(chain.contains("a.txt"));
+
+        chain.add(&patch).unwrap();
+
+        assert!(!chain.contains("a.txt"));
+        assert!(chain.contains("b.txt"));
+        assert!(matches!(
+            chain.read_to_string("a.txt"),
+            Err(MpqError::FileNotFound(_))
+        ));
+        assert_eq!("base", chain.read_to_string("b.txt").unwrap());
+    }
+
+    #[test]
+    fn read_patched() {
+        let dir = fixture::temp_dir("chain-read-patched");
+        let base = dir.join("base.mpq");
+        let patch = dir.join("patch.mpq");
+        let patch2 = dir.join("patch2.mpq");
+        let v1 = b"base file contents".to_vec();
+        let v2 = b"base file contents, patched".to_vec();
+        let v3 = b"patched twice".to_vec();
+
+        fs::write(&base, Builder::new().file("a.txt", &v1).build()).unwrap();
+        fs::write(
+            &patch,
+            Builder::new()
+                .file_with(
+                    "a.txt",
+                    &fixture::patch(&v1, &v2, true),
+                    FILE_COMPRESS | FILE_PATCH_FILE,
+                )
+                .build(),
+        )
+        .unwrap();
+        fs::write(
+            &patch2,
+            Builder::new()
+                .file_with(
+                    "a.txt",
+                    &fixture::patch(&v2, &v3, false),
+                    FILE_COMPRESS | FILE_PATCH_FILE,
+                )
+                .build(),
+        )
+        .unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&base).unwrap();
+        chain.add(&patch).unwrap();
+
+        assert_eq!(v2, chain.read_patched("a.txt").unwrap());
+
+        chain.add(&patch2).unwrap();
+
+        assert_eq!(v3, chain.read_patched("a.txt").unwrap());
+
+        // a patch without its base can't be applied
+        chain.remove(2);
+
+        assert!(matches!(
+            chain.read_patched("a.txt"),
+            Err(MpqError::FileNotFound(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "bzip2")]
+    fn list_bzip2_listfile() {
+        let dir = fixture::temp_dir("chain-list-bzip2");
+        let base = dir.join("base.mpq");
+        let names: Vec<String> = (0..12)
+            .map(|i| format!("Units\\Human\\Footman{}.mdx", i))
+            .collect();
+
+        fs::write(
+            &base,
+            Builder::new()
+                .listfile(false)
+                .file("Units\\Human\\Footman0.mdx", b"footman")
+                .file_packed(
+                    "(listfile)",
+                    names.join("\r\n").as_bytes(),
+                    fixture::BZIP2_LISTFILE,
+                )
+                .build(),
+        )
+        .unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&base).unwrap();
+
+        assert_eq!(names, chain.list().unwrap());
+    }
+
+    #[test]
+    fn list_empty_or_corrupt() {
+        let dir = fixture::temp_dir("chain-list-empty");
+        let empty = dir.join("empty.mpq");
+        let corrupt = dir.join("corrupt.mpq");
+        let header = dir.join("header.mpq");
+
+        let archive = Builder::new()
+            .listfile(false)
+            .file("(listfile)", b"")
+            .file("a.txt", b"base")
+            .build();
+        fs::write(&empty, archive).unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&empty).unwrap();
+
+        assert!(chain.list().unwrap().is_empty());
+
+        // a listfile failing its sector checksum is an error rather than no names
+        let mut archive = Builder::new()
+            .listfile(false)
+            .file_with("(listfile)", b"a.txt", FILE_COMPRESS | FILE_SECTOR_CRC)
+            .file("a.txt", b"base")
+            .build();
+        archive[0x20 + 12] ^= 0xFF;
+        fs::write(&corrupt, archive).unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&corrupt).unwrap();
+
+        assert!(matches!(
+            chain.list(),
+            Err(MpqError::SectorChecksumMismatch(0))
+        ));
+
+        let mut archive = Builder::new().file("a.txt", b"base").build();
+        archive[0x18] = 3;
+        fs::write(&header, archive).unwrap();
+
+        assert!(matches!(
+            Chain::new().add(&header),
+            Err(MpqError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn list() {
+        let dir = fixture::temp_dir("chain-list");
+        let base = dir.join("base.mpq");
+        let patch = dir.join("patch.mpq");
+        let extra = dir.join("extra.mpq");
+
+        let archive = Builder::new()
+            .file("c.txt", b"base")
+            .file("a.txt", b"base")
+            .file("b.txt", b"base")
+            .build();
+        fs::write(&base, archive).unwrap();
+
+        let archive = Builder::new()
+            .file("b.txt", b"patch")
+            .file("d.txt", b"patch")
+            .build();
+        fs::write(&patch, archive).unwrap();
+        fs::write(&extra, Builder::new().file("a.txt", b"extra").build()).unwrap();
+
+        let mut chain = Chain::new();
+        chain.add(&base).unwrap();
+        chain.add_with_priority(&extra, -1).unwrap();
+        chain.add(&patch).unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(
+                vec!["b.txt", "d.txt", "c.txt", "a.txt"],
+                chain.list().unwrap()
+            );
+        }
     }
 }
@@ -1,7 +1,12 @@
+use crate::error::MpqError;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+#[cfg(feature = "bzip2")]
 use bzip2_rs as bzip2;
-use implode::exploder::Exploder;
 use implode::symbol::DEFAULT_CODE_TABLE;
-use std::io::{self, Error, ErrorKind};
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
 
 const COMPRESSION_HUFFMAN: u8 = 0x01;
 const COMPRESSION_ZLIB: u8 = 0x02;
@@ -12,111 +17,978 @@ const COMPRESSION_ADPCM_MONO: u8 = 0x40;
 const COMPRESSION_ADPCM_STEREO: u8 = 0x80;
 const COMPRESSION_LZMA: u8 = 0x12;
 
-pub fn decompress(data: &mut [u8], out: &mut [u8]) -> Result<usize, Error> {
-    let compression_type = data[0];
+// methods in the order they are undone, sparse is always applied first when
+// compressing so it is undone last
+const DECOMPRESSION_ORDER: [u8; 7] = [
+    COMPRESSION_BZIP2,
+    COMPRESSION_PKWARE,
+    COMPRESSION_ZLIB,
+    COMPRESSION_HUFFMAN,
+    COMPRESSION_ADPCM_STEREO,
+    COMPRESSION_ADPCM_MONO,
+    COMPRESSION_SPARSE,
+];
 
-    if compression_type & COMPRESSION_BZIP2 != 0 {
-        let mut ouput = io::Cursor::new(out);
-        let mut reader = bzip2::DecoderReader::new(&data[1..]);
-        io::copy(&mut reader, &mut ouput)?;
-        return Ok(ouput.position() as usize);
+// methods decompress_method can undo, zlib and bzip2 can be left out of a build
+const SUPPORTED: u8 = COMPRESSION_PKWARE
+    | COMPRESSION_SPARSE
+    | COMPRESSION_ADPCM_MONO
+    | COMPRESSION_ADPCM_STEREO
+    | if cfg!(feature = "zlib") {
+        COMPRESSION_ZLIB
+    } else {
+        0
     }
+    | if cfg!(feature = "bzip2") {
+        COMPRESSION_BZIP2
+    } else {
+        0
+    };
 
-    if compression_type & COMPRESSION_ZLIB != 0 {
-        let mut zlib = flate2::Decompress::new(true);
+// how the data of a block is packed, a block uses one for every sector
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Method {
+    Stored,
+    Implode,
+    Compress,
+}
+
+/// A compression method of MPQ sectors
+///
+/// Chooses how [`ArchiveWriter`](crate::ArchiveWriter) packs a file, which can
+/// only write `None`, `Zlib` and `Implode`. Sectors that don't shrink are stored
+/// as is, and a file none of whose sectors shrink is written uncompressed.
+/// [`FileEntry`](crate::FileEntry) lists the methods a file was found to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum CompressionMethod {
+    None,
+    /// Stored by the writer without the zlib feature
+    Zlib,
+    Bzip2,
+    /// PKWARE Data Compression Library, either marked by `FILE_IMPLODE` or named
+    /// in the mask byte of a compressed sector
+    Implode,
+    Huffman,
+    Sparse,
+    AdpcmMono,
+    AdpcmStereo,
+    Lzma,
+}
+
+impl CompressionMethod {
+    // bits of the method in the mask byte leading a compressed sector
+    pub(crate) fn mask(self) -> u8 {
+        match self {
+            CompressionMethod::None => 0,
+            CompressionMethod::Zlib => COMPRESSION_ZLIB,
+            CompressionMethod::Bzip2 => COMPRESSION_BZIP2,
+            CompressionMethod::Implode => COMPRESSION_PKWARE,
+            CompressionMethod::Huffman => COMPRESSION_HUFFMAN,
+            CompressionMethod::Sparse => COMPRESSION_SPARSE,
+            CompressionMethod::AdpcmMono => COMPRESSION_ADPCM_MONO,
+            CompressionMethod::AdpcmStereo => COMPRESSION_ADPCM_STEREO,
+            CompressionMethod::Lzma => COMPRESSION_LZMA,
+        }
+    }
 
-        match zlib.decompress(&data[1..], out, flate2::FlushDecompress::None) {
-            Ok(_) => {}
-            Err(e) => return Err(Error::new(ErrorKind::Other, e)),
+    // methods named by a mask byte in the order they were applied, lzma is a
+    // value of its own rather than a bit
+    pub(crate) fn from_mask(mask: u8) -> Vec<CompressionMethod> {
+        if mask == COMPRESSION_LZMA {
+            return vec![CompressionMethod::Lzma];
         }
 
-        return Ok(zlib.total_out() as usize);
+        [
+            CompressionMethod::Sparse,
+            CompressionMethod::AdpcmMono,
+            CompressionMethod::AdpcmStereo,
+            CompressionMethod::Huffman,
+            CompressionMethod::Zlib,
+            CompressionMethod::Implode,
+            CompressionMethod::Bzip2,
+        ]
+        .iter()
+        .copied()
+        .filter(|method| mask & method.mask() != 0)
+        .collect()
     }
+}
+
+/// Decompresses data following a compression mask byte into the output buffer,
+/// returning the number of bytes written
+pub type Decompressor = Box<dyn Fn(&[u8], &mut [u8]) -> Result<usize, MpqError> + Send + Sync>;
 
-    if compression_type & COMPRESSION_PKWARE != 0 {
-        let mut exploder = Exploder::new(&DEFAULT_CODE_TABLE);
+// decompressors registered with an archive, by the mask byte they handle
+pub(crate) type Decompressors = HashMap<u8, Decompressor>;
 
-        let mut cpos: u32 = 1;
-        let len = data.len();
-        let mut c = 0;
+// undo method on a sector or single unit file, data the size of out is stored
+// whatever the method. a registered decompressor takes precedence over the
+// built in methods for the mask byte it was registered for
+pub(crate) fn unpack(
+    method: Method,
+    data: &[u8],
+    out: &mut [u8],
+    scratch: &mut Vec<u8>,
+    custom: &Decompressors,
+) -> Result<usize, MpqError> {
+    match method {
+        Method::Compress => match data.first().and_then(|mask| custom.get(mask)) {
+            Some(f) if data.len() != out.len() => f(&data[1..], out),
+            _ => decompress(data, out, scratch),
+        },
+        Method::Implode => explode(data, out),
+        Method::Stored => {
+            copy_stored(data, out).ok_or(MpqError::SizeMismatch(out.len(), data.len()))
+        }
+    }
+}
 
-        while !exploder.ended {
-            let abuf = &mut data[cpos as usize..len];
+// undo every compression method named in the leading mask byte, intermediate
+// results are kept in scratch so it can be reused between sectors. out must be
+// the size of the decompressed data, data of that size is stored and copied as is
+pub fn decompress(data: &[u8], out: &mut [u8], scratch: &mut Vec<u8>) -> Result<usize, MpqError> {
+    if let Some(len) = copy_stored(data, out) {
+        return Ok(len);
+    }
 
-            let x = exploder.explode_block(abuf).unwrap();
+    let compression_type = match data.first() {
+        Some(v) => *v,
+        None => return Err(MpqError::Corrupt("Empty compressed sector")),
+    };
 
-            cpos += x.0 as u32;
+    if compression_type == COMPRESSION_LZMA {
+        return Err(MpqError::UnsupportedCompression(compression_type));
+    }
 
-            let bf = x.1;
+    // bits that name no method are ignored, some protectors set them on purpose
+    let mut methods = DECOMPRESSION_ORDER
+        .iter()
+        .filter(|&&m| compression_type & m != 0);
 
-            for (d, s) in out.iter_mut().zip(bf.iter()) {
-                *d = *s;
-                c += 1;
-            }
-        }
+    let first = match methods.next() {
+        Some(&m) => m,
+        None => return Err(MpqError::UnsupportedCompression(compression_type)),
+    };
 
-        return Ok(c);
+    if methods
+        .clone()
+        .chain(Some(&first))
+        .any(|m| SUPPORTED & m == 0)
+    {
+        return Err(MpqError::UnsupportedCompression(compression_type));
     }
 
-    if compression_type & COMPRESSION_HUFFMAN != 0 {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Compression algorithm Huffman not supported",
-        ));
+    let mut len = decompress_method(first, &data[1..], out)?;
+
+    for &method in methods {
+        scratch.clear();
+        scratch.extend_from_slice(&out[..len]);
+
+        len = decompress_method(method, scratch, out)?;
     }
 
-    if compression_type & COMPRESSION_SPARSE != 0 {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Compression algorithm Sparse not supported",
-        ));
+    Ok(len)
+}
+
+fn decompress_method(method: u8, data: &[u8], out: &mut [u8]) -> Result<usize, MpqError> {
+    match method {
+        #[cfg(feature = "bzip2")]
+        COMPRESSION_BZIP2 => {
+            let mut ouput = io::Cursor::new(out);
+            let mut reader = bzip2::DecoderReader::new(data);
+            io::copy(&mut reader, &mut ouput)?;
+            Ok(ouput.position() as usize)
+        }
+        #[cfg(feature = "zlib")]
+        COMPRESSION_ZLIB => inflate(data, out),
+        COMPRESSION_PKWARE => explode_stream(data, out),
+        COMPRESSION_SPARSE => unsparse(data, out),
+        COMPRESSION_ADPCM_MONO => decode_adpcm(data, out, 1),
+        COMPRESSION_ADPCM_STEREO => decode_adpcm(data, out, 2),
+        _ => Err(MpqError::UnsupportedCompression(method)),
     }
+}
 
-    if compression_type & COMPRESSION_ADPCM_STEREO != 0 {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Compression algorithm ADPCM Stereo not supported",
-        ));
+// inflate a zlib stream, feeding it until the stream ends or out is full
+#[cfg(feature = "zlib")]
+fn inflate(data: &[u8], out: &mut [u8]) -> Result<usize, MpqError> {
+    let mut zlib = flate2::Decompress::new(true);
+
+    loop {
+        let consumed = zlib.total_in() as usize;
+        let produced = zlib.total_out() as usize;
+
+        // out is full, the stream must not decode to anything more
+        if produced == out.len() {
+            let mut probe = [0; 1];
+
+            return match zlib.decompress(
+                &data[consumed..],
+                &mut probe,
+                flate2::FlushDecompress::None,
+            ) {
+                Ok(_) if zlib.total_out() as usize == out.len() => Ok(out.len()),
+                Ok(_) => Err(output_overflow("zlib")),
+                Err(e) => Err(MpqError::Decompression(e.to_string())),
+            };
+        }
+
+        let status = match zlib.decompress(
+            &data[consumed..],
+            &mut out[produced..],
+            flate2::FlushDecompress::None,
+        ) {
+            Ok(v) => v,
+            Err(e) => return Err(MpqError::Decompression(e.to_string())),
+        };
+
+        if status == flate2::Status::StreamEnd {
+            return Ok(zlib.total_out() as usize);
+        }
+
+        // no progress means the input ended before the stream did
+        if zlib.total_in() as usize == consumed && zlib.total_out() as usize == produced {
+            return Err(MpqError::Decompression(String::from(
+                "unexpected end of zlib stream",
+            )));
+        }
     }
+}
 
-    if compression_type & COMPRESSION_ADPCM_MONO != 0 {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Compression algorithm ADPCM Stereo not supported",
-        ));
+// expand runs of zeros, data starts with the big endian size of the output
+fn unsparse(data: &[u8], out: &mut [u8]) -> Result<usize, MpqError> {
+    if data.len() < 4 {
+        return Err(MpqError::Corrupt("Sparse data too short"));
     }
 
-    if compression_type & COMPRESSION_LZMA != 0 {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Compression algorithm LZMA not supported",
-        ));
+    let size = BigEndian::read_u32(data) as usize;
+
+    if size > out.len() {
+        return Err(output_overflow("sparse"));
+    }
+
+    let mut pos = 4;
+    let mut c = 0;
+
+    while pos < data.len() && c < size {
+        let control = data[pos];
+        pos += 1;
+
+        if control & 0x80 != 0 {
+            let count = ((control & 0x7F) as usize + 1)
+                .min(size - c)
+                .min(data.len() - pos);
+
+            out[c..c + count].copy_from_slice(&data[pos..pos + count]);
+            pos += count;
+            c += count;
+        } else {
+            let count = ((control & 0x7F) as usize + 3).min(size - c);
+
+            for b in &mut out[c..c + count] {
+                *b = 0;
+            }
+            c += count;
+        }
     }
 
-    Err(Error::new(ErrorKind::Other, "No compression type found"))
+    Ok(c)
 }
 
-pub fn explode(data: &mut [u8], out: &mut [u8]) -> Result<usize, Error> {
-    let mut exploder = Exploder::new(&DEFAULT_CODE_TABLE);
+const ADPCM_INITIAL_STEP_INDEX: usize = 0x2C;
+
+const ADPCM_STEP_SIZES: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+const ADPCM_NEXT_STEP: [isize; 32] = [
+    -1, 0, -1, 4, -1, 2, -1, 6, -1, 1, -1, 5, -1, 3, -1, 7, -1, 1, -1, 5, -1, 3, -1, 7, -1, 2, -1,
+    4, -1, 6, -1, 8,
+];
+
+// decode blizzard's adpcm into 16 bit little endian pcm with channels interleaved.
+// the channel count comes from the mask byte, the data starts with a zero byte, the
+// bit shift and the first sample of every channel. a set high bit in a byte marks
+// a command instead of a sample: repeat the last sample or move the step index
+fn decode_adpcm(data: &[u8], out: &mut [u8], channels: usize) -> Result<usize, MpqError> {
+    let header_size = 2 + channels * 2;
 
-    let mut cpos: u32 = 0;
-    let len = data.len();
+    if data.len() < header_size {
+        return Err(MpqError::Corrupt("ADPCM data too short"));
+    }
+
+    let shift = u32::from(data[1]);
+    let mut predicted = [0i32; 2];
+    let mut step_index = [ADPCM_INITIAL_STEP_INDEX; 2];
     let mut c = 0;
 
-    while !exploder.ended {
-        let abuf = &mut data[cpos as usize..len];
+    let mut write = |out: &mut [u8], sample: i32| match out.get_mut(c..c + 2) {
+        Some(dest) => {
+            LittleEndian::write_i16(dest, sample as i16);
+            c += 2;
+            Ok(())
+        }
+        None => Err(output_overflow("adpcm")),
+    };
 
-        let x = exploder.explode_block(abuf).unwrap();
+    for channel in 0..channels {
+        predicted[channel] = i32::from(LittleEndian::read_i16(&data[2 + channel * 2..]));
+        write(out, predicted[channel])?;
+    }
 
-        cpos += x.0 as u32;
+    let mut channel = channels - 1;
 
-        let bf = x.1;
+    for &encoded in &data[header_size..] {
+        channel = (channel + 1) % channels;
 
-        for (d, s) in out.iter_mut().zip(bf.iter()) {
-            *d = *s;
-            c += 1;
+        if encoded & 0x80 != 0 {
+            match encoded & 0x7F {
+                0 => {
+                    step_index[channel] = step_index[channel].saturating_sub(1);
+                    write(out, predicted[channel])?;
+                }
+                // step index changes don't use up the channel's turn
+                1 => {
+                    step_index[channel] = (step_index[channel] + 8).min(ADPCM_STEP_SIZES.len() - 1);
+                    channel = (channel + channels - 1) % channels;
+                }
+                2 => {}
+                _ => {
+                    step_index[channel] = step_index[channel].saturating_sub(8);
+                    channel = (channel + channels - 1) % channels;
+                }
+            }
+
+            continue;
+        }
+
+        let step = ADPCM_STEP_SIZES[step_index[channel]];
+        let mut difference = step.checked_shr(shift).unwrap_or(0);
+
+        for bit in 0..6 {
+            if encoded & (1 << bit) != 0 {
+                difference += step >> bit;
+            }
         }
+
+        predicted[channel] = if encoded & 0x40 != 0 {
+            (predicted[channel] - difference).max(i32::from(i16::MIN))
+        } else {
+            (predicted[channel] + difference).min(i32::from(i16::MAX))
+        };
+        write(out, predicted[channel])?;
+
+        let next = step_index[channel] as isize + ADPCM_NEXT_STEP[usize::from(encoded & 0x1F)];
+        step_index[channel] = next.clamp(0, ADPCM_STEP_SIZES.len() as isize - 1) as usize;
     }
 
     Ok(c)
 }
+
+// compressing a sector or file that doesn't shrink stores it instead, it's then
+// exactly the size of its output
+fn copy_stored(data: &[u8], out: &mut [u8]) -> Option<usize> {
+    if data.len() != out.len() {
+        return None;
+    }
+
+    out.copy_from_slice(data);
+
+    Some(out.len())
+}
+
+// undo pkware implode of a FILE_IMPLODE sector or file, which has no mask byte.
+// like decompress, data the size of out is copied as is
+pub fn explode(data: &[u8], out: &mut [u8]) -> Result<usize, MpqError> {
+    match copy_stored(data, out) {
+        Some(len) => Ok(len),
+        None => explode_stream(data, out),
+    }
+}
+
+// binary literals are the only kind implode writes, and the dictionary holds
+// 64 << dict_bits bytes of which only 4 to 6 are valid. every length and distance
+// is checked, so a corrupt stream is an error rather than a bad copy
+fn explode_stream(data: &[u8], out: &mut [u8]) -> Result<usize, MpqError> {
+    let table = &DEFAULT_CODE_TABLE;
+
+    let dict_bits = match data {
+        [0, bits @ 4..=6, ..] => u32::from(*bits),
+        [1, ..] => {
+            return Err(MpqError::Decompression(String::from(
+                "pkware ASCII literals are unsupported",
+            )))
+        }
+        _ => {
+            return Err(MpqError::Decompression(String::from(
+                "Invalid pkware header",
+            )))
+        }
+    };
+
+    let mut reader = BitReader {
+        data: &data[2..],
+        bits: 0,
+        count: 0,
+    };
+    let truncated = || MpqError::Decompression(String::from("Truncated pkware stream"));
+    let mut c = 0;
+
+    loop {
+        if reader.read(1).ok_or_else(truncated)? == 0 {
+            let literal = reader.read(8).ok_or_else(truncated)?;
+
+            match out.get_mut(c) {
+                Some(b) => *b = literal as u8,
+                None => return Err(output_overflow("pkware")),
+            }
+
+            c += 1;
+            continue;
+        }
+
+        let code = usize::from(table.len_codes[reader.peek(8) as usize]);
+
+        reader
+            .consume(u32::from(table.len_bits[code]))
+            .ok_or_else(truncated)?;
+
+        let extra = reader
+            .read(u32::from(table.extra_len_bits[code]))
+            .ok_or_else(truncated)?;
+        let value = u32::from(table.len_base[code]) + extra;
+
+        // a length of 519 ends the stream
+        if value == IMPLODE_END {
+            return Ok(c);
+        }
+
+        let dist_code = u32::from(table.dist_codes[reader.peek(8) as usize]);
+
+        reader
+            .consume(u32::from(table.dist_bits[dist_code as usize]))
+            .ok_or_else(truncated)?;
+
+        let add_bits = if value == 0 { 2 } else { dict_bits };
+        let dist =
+            ((dist_code << add_bits) | reader.read(add_bits).ok_or_else(truncated)?) as usize + 1;
+        let len = value as usize + IMPLODE_MIN_MATCH;
+
+        if dist > c {
+            return Err(MpqError::Decompression(String::from(
+                "pkware distance before the start of the output",
+            )));
+        }
+
+        if c + len > out.len() {
+            return Err(output_overflow("pkware"));
+        }
+
+        // matches may overlap the bytes they produce
+        for i in c..c + len {
+            out[i] = out[i - dist];
+        }
+
+        c += len;
+    }
+}
+
+// bits of an implode stream being read, least significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    bits: u64,
+    count: u32,
+}
+
+impl BitReader<'_> {
+    fn fill(&mut self) {
+        while self.count <= 56 {
+            let (&byte, rest) = match self.data.split_first() {
+                Some(v) => v,
+                None => break,
+            };
+
+            self.bits |= u64::from(byte) << self.count;
+            self.count += 8;
+            self.data = rest;
+        }
+    }
+
+    // the next len bits without consuming them, zero past the end of the stream
+    fn peek(&mut self, len: u32) -> u32 {
+        self.fill();
+
+        (self.bits & ((1 << len) - 1)) as u32
+    }
+
+    fn consume(&mut self, len: u32) -> Option<()> {
+        self.fill();
+
+        if len > self.count {
+            return None;
+        }
+
+        self.bits >>= len;
+        self.count -= len;
+
+        Some(())
+    }
+
+    fn read(&mut self, len: u32) -> Option<u32> {
+        let value = self.peek(len);
+
+        self.consume(len).map(|_| value)
+    }
+}
+
+const IMPLODE_DICT_BITS: u32 = 6;
+const IMPLODE_MIN_MATCH: usize = 2;
+const IMPLODE_MAX_MATCH: usize = 518;
+// length past the minimum that ends the stream
+const IMPLODE_END: u32 = 517;
+// earlier positions tried for a match before settling on the longest so far
+const IMPLODE_MATCH_CHAIN: usize = 64;
+
+// bits of the stream being written by implode, least significant bit first
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn push(&mut self, value: u32, len: u32) {
+        self.bits |= value << self.count;
+        self.count += len;
+
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+
+        self.out
+    }
+}
+
+// code of symbol in a decoding table indexed by the next eight bits, which is the
+// lowest index decoding to it
+fn implode_code(lut: &[u8; 256], symbol: usize) -> u32 {
+    (0..256)
+        .find(|&b| usize::from(lut[b]) == symbol)
+        .unwrap_or(0) as u32
+}
+
+fn implode_prefix(data: &[u8], i: usize) -> usize {
+    usize::from(data[i]) << 8 | usize::from(data[i + 1])
+}
+
+// chain position i onto the earlier positions sharing its first two bytes
+fn implode_insert(data: &[u8], head: &mut [usize], prev: &mut [usize], i: usize) {
+    if i + 1 < data.len() {
+        prev[i] = head[implode_prefix(data, i)];
+        head[implode_prefix(data, i)] = i;
+    }
+}
+
+// pkware implode data as binary literals and matches found by greedy search.
+// the result is a stream explode reads back, it may be larger than data
+pub(crate) fn implode(data: &[u8]) -> Vec<u8> {
+    let table = &DEFAULT_CODE_TABLE;
+    let len_codes: Vec<u32> = (0..16).map(|k| implode_code(&table.len_codes, k)).collect();
+    let dist_codes: Vec<u32> = (0..64)
+        .map(|k| implode_code(&table.dist_codes, k))
+        .collect();
+
+    let window = 64 << IMPLODE_DICT_BITS;
+    let mut writer = BitWriter {
+        out: vec![0, IMPLODE_DICT_BITS as u8],
+        bits: 0,
+        count: 0,
+    };
+
+    // most recent position of every two byte prefix and the one before it
+    let mut head = vec![usize::MAX; 0x10000];
+    let mut prev = vec![usize::MAX; data.len()];
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let mut best_len = 0;
+        let mut best_dist = 0;
+
+        if pos + 1 < data.len() {
+            let max_len = IMPLODE_MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[implode_prefix(data, pos)];
+            let mut chain = 0;
+
+            while candidate != usize::MAX
+                && pos - candidate <= window
+                && chain < IMPLODE_MATCH_CHAIN
+            {
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[pos..pos + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                let dist = pos - candidate;
+
+                // two byte matches only carry two low distance bits
+                let reachable = len > IMPLODE_MIN_MATCH || dist <= 64 << 2;
+
+                if reachable && len > best_len {
+                    best_len = len;
+                    best_dist = dist;
+                }
+
+                candidate = prev[candidate];
+                chain += 1;
+            }
+        }
+
+        if best_len < IMPLODE_MIN_MATCH {
+            writer.push(u32::from(data[pos]) << 1, 9);
+            implode_insert(data, &mut head, &mut prev, pos);
+            pos += 1;
+            continue;
+        }
+
+        // length, the 517 past the minimum ending the stream is never produced
+        let value = (best_len - IMPLODE_MIN_MATCH) as u16;
+        let code = (0..16)
+            .rev()
+            .find(|&k| table.len_base[k] <= value)
+            .unwrap_or(0);
+
+        writer.push(1, 1);
+        writer.push(len_codes[code], u32::from(table.len_bits[code]));
+        writer.push(
+            u32::from(value - table.len_base[code]),
+            u32::from(table.extra_len_bits[code]),
+        );
+
+        let add_bits = if value == 0 { 2 } else { IMPLODE_DICT_BITS };
+        let dist = (best_dist - 1) as u32;
+        let dist_code = (dist >> add_bits) as usize;
+
+        writer.push(dist_codes[dist_code], u32::from(table.dist_bits[dist_code]));
+        writer.push(dist & ((1 << add_bits) - 1), add_bits);
+
+        for i in pos..pos + best_len {
+            implode_insert(data, &mut head, &mut prev, i);
+        }
+
+        pos += best_len;
+    }
+
+    // end of stream, a length of 519: length code 15 with every extra bit set
+    writer.push(1, 1);
+    writer.push(len_codes[15], u32::from(table.len_bits[15]));
+    writer.push(0xFF, u32::from(table.extra_len_bits[15]));
+
+    writer.finish()
+}
+
+// out is a hard limit on every method, a stream decoding to more is an error.
+// bzip2 gets the same error from writing into out
+fn output_overflow(method: &str) -> MpqError {
+    io::Error::new(
+        io::ErrorKind::WriteZero,
+        format!("{} stream larger than output", method),
+    )
+    .into()
+}
+
+// whether e is the error of a stream decoding to more than its output holds
+pub(crate) fn is_output_overflow(e: &MpqError) -> bool {
+    matches!(e, MpqError::Io(e) if e.kind() == io::ErrorKind::WriteZero)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        decompress, explode, implode as implode_matches, implode_code, BitWriter,
+        CompressionMethod, DEFAULT_CODE_TABLE,
+    };
+    use crate::error::MpqError;
+    use crate::fixture::implode;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::{self, Write};
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn decompress_chained() {
+        let mut expected = b"abc".to_vec();
+        expected.extend_from_slice(&[0; 100]);
+        expected.extend_from_slice(b"xyz");
+
+        // sparse: literal "abc", 100 zeros, literal "xyz", then zlib
+        let mut sparse = (expected.len() as u32).to_be_bytes().to_vec();
+        sparse.extend_from_slice(b"\x82abc\x61\x82xyz");
+
+        let mut encoder = ZlibEncoder::new(vec![0x22], Compression::default());
+        encoder.write_all(&sparse).unwrap();
+        let data = encoder.finish().unwrap();
+
+        let mut scratch = Vec::new();
+        let mut out = vec![0xFF; expected.len()];
+
+        assert_eq!(
+            expected.len(),
+            decompress(&data, &mut out, &mut scratch).unwrap()
+        );
+        assert_eq!(expected, out);
+
+        // a second sector reuses the scratch allocation
+        let ptr = scratch.as_ptr();
+        let mut out = vec![0xFF; expected.len()];
+
+        decompress(&data, &mut out, &mut scratch).unwrap();
+
+        assert_eq!(expected, out);
+        assert_eq!(ptr, scratch.as_ptr());
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn decompress_zlib_blocks() {
+        let expected: Vec<u8> = (0..4096u32).map(|i| (i * 7 % 253) as u8).collect();
+
+        // several deflate blocks, the last only complete once the stream is finished
+        let mut encoder = ZlibEncoder::new(vec![0x02], Compression::default());
+        for chunk in expected.chunks(1000) {
+            encoder.write_all(chunk).unwrap();
+            encoder.flush().unwrap();
+        }
+        let data = encoder.finish().unwrap();
+
+        let mut out = vec![0; expected.len()];
+
+        assert_eq!(
+            expected.len(),
+            decompress(&data, &mut out, &mut Vec::new()).unwrap()
+        );
+        assert_eq!(expected, out);
+
+        // truncated stream is an error rather than short output
+        let mut out = vec![0; expected.len()];
+
+        assert!(decompress(&data[..data.len() / 2], &mut out, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn decompress_stored() {
+        // looks like a bzip2 mask byte but is the size of the output
+        let data = b"\x10 stored data";
+        let mut out = vec![0; data.len()];
+
+        assert_eq!(
+            data.len(),
+            decompress(data, &mut out, &mut Vec::new()).unwrap()
+        );
+        assert_eq!(&data[..], &out[..]);
+
+        let mut out = vec![0; data.len()];
+
+        assert_eq!(data.len(), explode(data, &mut out).unwrap());
+        assert_eq!(&data[..], &out[..]);
+    }
+
+    #[test]
+    fn decompress_unsupported() {
+        let mut out = vec![0; 16];
+
+        for &mask in &[0x12, 0x04, 0x01, 0x41, 0x81] {
+            assert!(matches!(
+                decompress(&[mask, 0, 0], &mut out, &mut Vec::new()),
+                Err(MpqError::UnsupportedCompression(b)) if b == mask
+            ));
+        }
+
+        assert!(decompress(&[], &mut out, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn decompress_overflow() {
+        let expected: Vec<u8> = (0..1000u32).map(|i| (i % 7) as u8).collect();
+        let is_overflow = |r: Result<usize, MpqError>| match r {
+            Err(MpqError::Io(e)) => e.kind() == io::ErrorKind::WriteZero,
+            _ => false,
+        };
+
+        let mut encoder = ZlibEncoder::new(vec![0x02], Compression::default());
+        encoder.write_all(&expected).unwrap();
+        let zlib = encoder.finish().unwrap();
+
+        let mut sparse = vec![0x20];
+        sparse.extend_from_slice(&1000u32.to_be_bytes());
+        sparse.extend_from_slice(&[0x7F; 8]);
+
+        let imploded = implode(&expected);
+
+        for &len in &[0, 1, 999] {
+            let mut out = vec![0; len];
+
+            if cfg!(feature = "zlib") {
+                assert!(is_overflow(decompress(&zlib, &mut out, &mut Vec::new())));
+            }
+
+            assert!(is_overflow(decompress(&sparse, &mut out, &mut Vec::new())));
+            assert!(is_overflow(explode(&imploded, &mut out)));
+        }
+
+        let mut out = vec![0; 1000];
+        assert_eq!(1000, explode(&imploded, &mut out).unwrap());
+        assert_eq!(expected, out);
+    }
+
+    #[test]
+    #[cfg(not(feature = "zlib"))]
+    fn decompress_zlib_disabled() {
+        let mut encoder = ZlibEncoder::new(vec![0x02], Compression::default());
+        encoder.write_all(b"hello").unwrap();
+        let data = encoder.finish().unwrap();
+
+        assert!(matches!(
+            decompress(&data, &mut [0; 5], &mut Vec::new()),
+            Err(MpqError::UnsupportedCompression(0x02))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "zlib")]
+    fn decompress_unknown_bits() {
+        let mut encoder = ZlibEncoder::new(vec![0x06], Compression::default());
+        encoder.write_all(b"hello").unwrap();
+        let data = encoder.finish().unwrap();
+
+        let mut out = vec![0; 5];
+
+        assert_eq!(5, decompress(&data, &mut out, &mut Vec::new()).unwrap());
+        assert_eq!(b"hello", &out[..]);
+    }
+
+    #[test]
+    fn implode_round_trip() {
+        let mut data = b"ab".repeat(3);
+        data.extend((0..6000u32).map(|i| (i * i % 251) as u8));
+        data.extend_from_slice(&[7; 1000]);
+        data.extend_from_slice(b"xyabxyzab");
+
+        let packed = implode_matches(&data);
+        let mut out = vec![0; data.len()];
+
+        assert!(packed.len() < data.len());
+        assert_eq!(data.len(), explode(&packed, &mut out).unwrap());
+        assert_eq!(data, out);
+
+        let packed = implode_matches(b"");
+
+        assert_eq!(0, explode(&packed, &mut []).unwrap());
+    }
+
+    #[test]
+    fn explode_invalid() {
+        let mut out = vec![0; 16];
+
+        // ascii literals, seven dictionary bits and a stream without an end
+        for data in &[&[1, 6, 0][..], &[0, 7, 0], &[0, 6, 0x10]] {
+            assert!(matches!(
+                explode(data, &mut out),
+                Err(MpqError::Decompression(_))
+            ));
+        }
+
+        // a match before any output, which has nothing to copy from
+        let table = &DEFAULT_CODE_TABLE;
+        let mut writer = BitWriter {
+            out: vec![0, 6],
+            bits: 0,
+            count: 0,
+        };
+
+        writer.push(1, 1);
+        writer.push(
+            implode_code(&table.len_codes, 0),
+            u32::from(table.len_bits[0]),
+        );
+        writer.push(
+            implode_code(&table.dist_codes, 0),
+            u32::from(table.dist_bits[0]),
+        );
+        writer.push(0, 2);
+
+        assert!(matches!(
+            explode(&writer.finish(), &mut out),
+            Err(MpqError::Decompression(e)) if e.contains("distance")
+        ));
+    }
+
+    #[test]
+    fn methods_from_mask() {
+        assert_eq!(
+            vec![CompressionMethod::AdpcmStereo, CompressionMethod::Huffman],
+            CompressionMethod::from_mask(0x81)
+        );
+        assert_eq!(
+            vec![CompressionMethod::Bzip2],
+            CompressionMethod::from_mask(0x10)
+        );
+        assert_eq!(
+            vec![CompressionMethod::Lzma],
+            CompressionMethod::from_mask(0x12)
+        );
+        assert!(CompressionMethod::from_mask(0).is_empty());
+    }
+
+    fn pcm(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn decompress_adpcm_mono() {
+        // first sample 256, then step 494 up and down, a repeat, a larger step and
+        // one saturating sample. 0x82 does nothing
+        let data = [0x40, 0, 4, 0x00, 0x01, 0x01, 0x41, 0x80, 0x81, 0x02, 0x82];
+        let mut out = vec![0; 10];
+
+        assert_eq!(10, decompress(&data, &mut out, &mut Vec::new()).unwrap());
+        assert_eq!(pcm(&[256, 780, 256, 256, 797]), out);
+
+        let data = [0x40, 0, 4, 0x00, 0x7D, 0x3F];
+        let mut out = vec![0; 4];
+
+        assert_eq!(4, decompress(&data, &mut out, &mut Vec::new()).unwrap());
+        assert_eq!(pcm(&[32000, 32767]), out);
+
+        // one sample more than out has room for
+        assert!(decompress(&data, &mut [0; 3], &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn decompress_adpcm_stereo() {
+        // samples alternate between channels, a step change keeps the channel's turn
+        let data = [0x80, 0, 4, 100, 0, 0x9C, 0xFF, 0x01, 0x41, 0x81, 0x00];
+        let mut out = vec![0; 10];
+
+        assert_eq!(10, decompress(&data, &mut out, &mut Vec::new()).unwrap());
+        assert_eq!(pcm(&[100, -100, 624, -624, 690]), out);
+
+        // as mono the second sample is read as two commands
+        let mut mono = data;
+        mono[0] = 0x40;
+
+        assert_eq!(8, decompress(&mono, &mut out, &mut Vec::new()).unwrap());
+    }
+}
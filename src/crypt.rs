@@ -1,6 +1,6 @@
 use byteorder::{ByteOrder, LittleEndian};
 
-static CRYPT_TABLE: [u32; 0x500] = [
+pub(crate) static CRYPT_TABLE: [u32; 0x500] = [
     0x55c636e2, 0x02be0170, 0x584b71d4, 0x2984f00e, 0xb682c809, 0x91cf876b, 0x775a9c24, 0x597d5ca5,
     0x5a1afeb2, 0xd3e9ce0d, 0x32cdcdf8, 0xb18201cd, 0x3cce05ce, 0xa55d13be, 0xbb0afe71, 0x9376ab33,
     0x848f645e, 0x87e45a45, 0x45b86017, 0x5e656ca8, 0x1b851a95, 0x2542dbd7, 0xab4df9e4, 0x5976ae9b,
@@ -163,13 +163,30 @@ static CRYPT_TABLE: [u32; 0x500] = [
     0x1c9fa44a, 0xc406b6d7, 0xeedca152, 0x6149809c, 0xb0099ef4, 0xc5f653a5, 0x4c10790d, 0x7303286c,
 ];
 
+/// Hash type giving the starting index of a name in the hash table
+pub const HASH_TABLE_INDEX: u32 = 0x000;
+/// Hash type of the first name check stored in a hash table entry
+pub const HASH_NAME_A: u32 = 0x100;
+/// Hash type of the second name check stored in a hash table entry
+pub const HASH_NAME_B: u32 = 0x200;
+/// Hash type used to derive encryption keys from file and table names
+pub const HASH_FILE_KEY: u32 = 0x300;
+
+/// Hash a name the way MPQ archives do
+///
+/// Names are hashed byte by byte, case insensitive for ASCII letters, and `/`
+/// is treated as `\`. `offset` selects one of the four hash types:
+/// [`HASH_TABLE_INDEX`], [`HASH_NAME_A`], [`HASH_NAME_B`] or [`HASH_FILE_KEY`].
 pub fn hash_string(key: &str, offset: u32) -> u32 {
     let mut seed1: u32 = 0x7fed7fed;
     let mut seed2: u32 = 0xeeeeeeee;
-    let mut ch;
 
-    for c in key.replace('/', "\\").chars() {
-        ch = c.to_uppercase().next().unwrap() as u32;
+    for b in key.bytes() {
+        let ch = u32::from(if b == b'/' {
+            b'\\'
+        } else {
+            b.to_ascii_uppercase()
+        });
         seed1 = CRYPT_TABLE[(offset.wrapping_add(ch)) as usize] ^ (seed1.wrapping_add(seed2));
         seed2 = ch
             .wrapping_add(seed1)
@@ -181,28 +198,179 @@ pub fn hash_string(key: &str, offset: u32) -> u32 {
     seed1
 }
 
+/// Compute the `(hash_a, hash_b, index)` triple of a name
+///
+/// `index` is not yet reduced to a table position, mask it with the hash table
+/// size minus one.
+pub fn hash_name(name: &str) -> (u32, u32, u32) {
+    (
+        hash_string(name, HASH_NAME_A),
+        hash_string(name, HASH_NAME_B),
+        hash_string(name, HASH_TABLE_INDEX),
+    )
+}
+
+// 64 bit name hash of the HET table, bob jenkins' hashlittle2 over the lowercase
+// name with `/` treated as `\`. storm seeds pc with 2 and pb with 1, b is the
+// upper half
+pub(crate) fn hash_jenkins(name: &str) -> u64 {
+    let key: Vec<u8> = name
+        .bytes()
+        .map(|b| match b {
+            b'/' => b'\\',
+            _ => b.to_ascii_lowercase(),
+        })
+        .collect();
+
+    let (c, b) = hashlittle2(&key, 2, 1);
+
+    (u64::from(b) << 32) | u64::from(c)
+}
+
+// lookup3.c hashlittle2, returns the primary and secondary hash (c, b) seeded
+// with pc and pb
+fn hashlittle2(key: &[u8], pc: u32, pb: u32) -> (u32, u32) {
+    let mut a = 0xdeadbeefu32
+        .wrapping_add(key.len() as u32)
+        .wrapping_add(pc);
+    let mut b = a;
+    let mut c = a.wrapping_add(pb);
+
+    let word = |k: &[u8]| {
+        k.iter()
+            .enumerate()
+            .fold(0u32, |v, (i, &x)| v.wrapping_add(u32::from(x) << (i * 8)))
+    };
+
+    let mut k = key;
+
+    while k.len() > 12 {
+        a = a.wrapping_add(word(&k[0..4]));
+        b = b.wrapping_add(word(&k[4..8]));
+        c = c.wrapping_add(word(&k[8..12]));
+
+        a = a.wrapping_sub(c) ^ c.rotate_left(4);
+        c = c.wrapping_add(b);
+        b = b.wrapping_sub(a) ^ a.rotate_left(6);
+        a = a.wrapping_add(c);
+        c = c.wrapping_sub(b) ^ b.rotate_left(8);
+        b = b.wrapping_add(a);
+        a = a.wrapping_sub(c) ^ c.rotate_left(16);
+        c = c.wrapping_add(b);
+        b = b.wrapping_sub(a) ^ a.rotate_left(19);
+        a = a.wrapping_add(c);
+        c = c.wrapping_sub(b) ^ b.rotate_left(4);
+        b = b.wrapping_add(a);
+
+        k = &k[12..];
+    }
+
+    // an empty tail skips the final mix
+    if k.is_empty() {
+        return (c, b);
+    }
+
+    a = a.wrapping_add(word(&k[..k.len().min(4)]));
+    if k.len() > 4 {
+        b = b.wrapping_add(word(&k[4..k.len().min(8)]));
+    }
+    if k.len() > 8 {
+        c = c.wrapping_add(word(&k[8..]));
+    }
+
+    c ^= b;
+    c = c.wrapping_sub(b.rotate_left(14));
+    a ^= c;
+    a = a.wrapping_sub(c.rotate_left(11));
+    b ^= a;
+    b = b.wrapping_sub(a.rotate_left(25));
+    c ^= b;
+    c = c.wrapping_sub(b.rotate_left(16));
+    a ^= c;
+    a = a.wrapping_sub(c.rotate_left(4));
+    b ^= a;
+    b = b.wrapping_sub(a.rotate_left(14));
+    c ^= b;
+    c = c.wrapping_sub(b.rotate_left(24));
+
+    (c, b)
+}
+
+// inverse of decrypt, the key schedule is driven by the plaintext
+pub fn encrypt(data: &mut [u8], mut seed: u32) {
+    let mut seed2: u32 = 0xeeeeeeee;
+
+    for chunk in data.chunks_exact_mut(4) {
+        seed2 = seed2.wrapping_add(CRYPT_TABLE[(0x400 + (seed & 0xff)) as usize]);
+        let ch = LittleEndian::read_u32(chunk);
+        LittleEndian::write_u32(chunk, ch ^ (seed.wrapping_add(seed2)));
+        seed = ((!seed << 0x15).wrapping_add(0x11111111)) | (seed >> 0x0b);
+        seed2 = ch
+            .wrapping_add(seed2)
+            .wrapping_add(seed2 << 5)
+            .wrapping_add(3);
+    }
+}
+
 pub fn decrypt(data: &mut [u8], mut seed: u32) {
     let mut seed2: u32 = 0xeeeeeeee;
-    let mut it = 0;
-    let mut ch;
 
-    while it < data.len() - 3 {
+    // trailing bytes that don't fill a whole u32 are left as is
+    for chunk in data.chunks_exact_mut(4) {
         seed2 = seed2.wrapping_add(CRYPT_TABLE[(0x400 + (seed & 0xff)) as usize]);
-        ch = LittleEndian::read_u32(&data[it..]) ^ (seed.wrapping_add(seed2));
+        let ch = LittleEndian::read_u32(chunk) ^ (seed.wrapping_add(seed2));
         seed = ((!seed << 0x15).wrapping_add(0x11111111)) | (seed >> 0x0b);
         seed2 = ch
             .wrapping_add(seed2)
             .wrapping_add(seed2 << 5)
             .wrapping_add(3);
-        LittleEndian::write_u32(&mut data[it..], ch);
+        LittleEndian::write_u32(chunk, ch);
+    }
+}
 
-        it += 4;
+// recover the key of an encrypted sector offset table from its first two entries,
+// the first is the size of the table and the second is at most a sector further
+pub fn detect_file_key(data: &[u8], table_size: u32, sector_size: u32) -> Option<u32> {
+    if data.len() < 8 {
+        return None;
     }
+
+    let encrypted0 = LittleEndian::read_u32(data);
+    let encrypted1 = LittleEndian::read_u32(&data[4..]);
+    let max_decrypted1 = table_size.wrapping_add(sector_size);
+    let key1_plus_key2 = (encrypted0 ^ table_size).wrapping_sub(0xeeeeeeee);
+
+    for i in 0..0x100 {
+        let key1 = key1_plus_key2.wrapping_sub(CRYPT_TABLE[0x400 + i]);
+        let mut key2 = 0xeeeeeeeeu32.wrapping_add(CRYPT_TABLE[(0x400 + (key1 & 0xff)) as usize]);
+        let decrypted0 = encrypted0 ^ key1.wrapping_add(key2);
+
+        if decrypted0 != table_size {
+            continue;
+        }
+
+        let seed = ((!key1 << 0x15).wrapping_add(0x11111111)) | (key1 >> 0x0b);
+        key2 = decrypted0
+            .wrapping_add(key2)
+            .wrapping_add(key2 << 5)
+            .wrapping_add(3)
+            .wrapping_add(CRYPT_TABLE[(0x400 + (seed & 0xff)) as usize]);
+        let decrypted1 = encrypted1 ^ seed.wrapping_add(key2);
+
+        if decrypted1 <= max_decrypted1 {
+            // offset table is encrypted with the file key minus one
+            return Some(key1.wrapping_add(1));
+        }
+    }
+
+    None
 }
 
 #[cfg(test)]
 mod test {
-    use super::hash_string;
+    use super::{
+        decrypt, detect_file_key, encrypt, hash_jenkins, hash_name, hash_string, hashlittle2,
+    };
 
     #[test]
     fn hash() {
@@ -212,4 +380,79 @@ mod test {
         assert_eq!(0xF4E6C69D, hash_string("arr\\units.dat", 0));
         assert_eq!(0xA26067F3, hash_string("unit\\neutral\\acritter.grp", 0));
     }
+
+    #[test]
+    fn hash_listfile() {
+        assert_eq!(
+            (0xFD657910, 0x4E9B98A7, 0x5F3DE859),
+            hash_name("(listfile)")
+        );
+        assert_eq!(hash_name("(LISTFILE)"), hash_name("(listfile)"));
+    }
+
+    #[test]
+    fn hash_lookup3() {
+        // test vectors from lookup3.c
+        let text = b"Four score and seven years ago";
+
+        assert_eq!((0xdeadbeef, 0xdeadbeef), hashlittle2(b"", 0, 0));
+        assert_eq!((0xbd5b7dde, 0xdeadbeef), hashlittle2(b"", 0, 0xdeadbeef));
+        assert_eq!((0x17770551, 0xce7226e6), hashlittle2(text, 0, 0));
+        assert_eq!((0xe3607cae, 0xbd371de4), hashlittle2(text, 0, 1));
+        assert_eq!((0xcd628161, 0x6cbea4b3), hashlittle2(text, 1, 0));
+
+        assert_eq!(hash_jenkins("a/B.txt"), hash_jenkins("A\\b.TXT"));
+    }
+
+    #[test]
+    fn hash_het() {
+        // HashStringJenkins: hashlittle2(name, len, &secondary = 2, &primary = 1)
+        // and primary << 32 | secondary
+        let (c, b) = hashlittle2(b"(listfile)", 2, 1);
+
+        assert_eq!(
+            u64::from(b) << 32 | u64::from(c),
+            hash_jenkins("(LISTFILE)")
+        );
+        assert_ne!(hashlittle2(b"(listfile)", 1, 2), (c, b));
+    }
+
+    #[test]
+    fn encrypt_round_trip() {
+        let mut seed: u32 = 0x12345678;
+        let mut random = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            seed
+        };
+
+        for _ in 0..64 {
+            let key = random();
+            let len = (random() % 1024) as usize;
+            let data: Vec<u8> = (0..len).map(|_| (random() >> 16) as u8).collect();
+            let mut buf = data.clone();
+
+            encrypt(&mut buf, key);
+
+            if len >= 4 {
+                assert_ne!(data, buf);
+            }
+
+            decrypt(&mut buf, key);
+
+            assert_eq!(data, buf);
+        }
+    }
+
+    #[test]
+    fn detect_key() {
+        let key = hash_string("units.dat", 0x300);
+        let mut table = vec![0; 12];
+        table[0] = 12;
+        table[4] = 0x20;
+        table[8] = 0x40;
+
+        encrypt(&mut table, key.wrapping_sub(1));
+
+        assert_eq!(Some(key), detect_file_key(&table, 12, 0x1000));
+    }
 }
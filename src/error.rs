@@ -0,0 +1,45 @@
+use std::io;
+use std::string::FromUtf8Error;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MpqError {
+    #[error("Not a valid MPQ archive")]
+    InvalidMagic,
+    #[error("Unsupported MPQ format version {0}")]
+    UnsupportedFormatVersion(u16),
+    #[error("File not found: {0}")]
+    FileNotFound(String),
+    #[error("No file at block index {0}")]
+    BlockNotFound(u32),
+    #[error("No sector at index {0}")]
+    SectorNotFound(usize),
+    #[error("Unable to extract filename from path: {0}")]
+    InvalidFileName(String),
+    #[error("File already exists: {0}")]
+    AlreadyExists(String),
+    #[error("Unable to detect file key")]
+    UnknownFileKey,
+    #[error("Patch file not supported")]
+    PatchFileUnsupported,
+    #[error("Compression type {0:#04X} not supported")]
+    UnsupportedCompression(u8),
+    #[error("Decompression failed: {0}")]
+    Decompression(String),
+    #[error("Decompressed {1} bytes, expected {0}")]
+    SizeMismatch(usize, usize),
+    #[error("Sector {0} checksum error")]
+    SectorChecksumMismatch(usize),
+    #[error("CRC32 mismatch")]
+    Crc32Mismatch,
+    #[error("MD5 mismatch")]
+    Md5Mismatch,
+    #[error("MD5 mismatch of the {0}")]
+    TableMd5Mismatch(&'static str),
+    #[error("Corrupt archive: {0}")]
+    Corrupt(&'static str),
+    #[error("{0}")]
+    Utf8(#[from] FromUtf8Error),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
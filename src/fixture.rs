@@ -0,0 +1,753 @@
+//! In-memory MPQ archives for unit tests
+
+#![allow(dead_code)]
+
+use crate::archive::{
+    FILE_COMPRESS, FILE_ENCRYPTED, FILE_EXISTS, FILE_FIX_KEY, FILE_IMPLODE, FILE_PATCH_FILE,
+    FILE_SECTOR_CRC, FILE_SINGLE_UNIT,
+};
+use crate::crypt::{encrypt, hash_jenkins, hash_string};
+use crate::patch::PATCH_INFO_SIZE;
+use adler32::RollingAdler32;
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use md5::{Digest, Md5};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+const HASH_ENTRY_EMPTY: u32 = 0xFFFFFFFF;
+
+// (listfile) naming Units\Human\Footman0.mdx to Footman11.mdx, compressed as a
+// single unit with bzip2 which the fixture has no encoder for
+pub const BZIP2_LISTFILE: &[u8] = &[
+    0x10, 0x42, 0x5A, 0x68, 0x39, 0x31, 0x41, 0x59, 0x26, 0x53, 0x59, 0xCC, 0xC7, 0x66, 0x64, 0x00,
+    0x00, 0x24, 0x5F, 0x80, 0x00, 0x12, 0x00, 0x01, 0x7F, 0xE0, 0x01, 0x40, 0x02, 0x04, 0x24, 0x23,
+    0x8E, 0x40, 0x20, 0x00, 0x68, 0x24, 0x54, 0x19, 0x06, 0x20, 0x01, 0xA0, 0x8A, 0xA0, 0x00, 0x00,
+    0x34, 0xDA, 0x8D, 0x22, 0xA9, 0x17, 0x4D, 0xC2, 0x38, 0x63, 0x96, 0x72, 0x9C, 0x7B, 0x6D, 0xB1,
+    0x1B, 0x80, 0xA5, 0x44, 0x8B, 0x41, 0x7A, 0x0B, 0xC0, 0x41, 0x48, 0x33, 0x06, 0x01, 0xE8, 0x19,
+    0x00, 0xC8, 0x1F, 0x41, 0x80, 0x6D, 0x06, 0x80, 0x60, 0x19, 0x83, 0x00, 0xFE, 0x2E, 0xE4, 0x8A,
+    0x70, 0xA1, 0x21, 0x99, 0x8E, 0xCC, 0xC8,
+];
+
+pub struct Entry {
+    pub name: String,
+    pub data: Vec<u8>,
+    pub flags: u32,
+    pub locale: u16,
+    // single unit data written as is instead of compressing data
+    pub packed: Option<Vec<u8>>,
+}
+
+// where the sector checksums of a FILE_SECTOR_CRC file go
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumTable {
+    // after the data sectors, with an extra offset table entry for its end
+    Listed,
+    // as Listed, compressed like a sector
+    Compressed,
+    // after the data sectors up to the end of the block, without an extra entry
+    Appended,
+}
+
+pub struct Builder {
+    files: Vec<Entry>,
+    sector_size_shift: u16,
+    listfile: bool,
+    always_compress: bool,
+    crc32_checksums: bool,
+    checksum_table: ChecksumTable,
+    hi_block_table: Option<Vec<u16>>,
+    het_bet: Option<bool>,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            files: Vec::new(),
+            sector_size_shift: 3,
+            listfile: true,
+            always_compress: false,
+            crc32_checksums: false,
+            checksum_table: ChecksumTable::Listed,
+            hi_block_table: None,
+            het_bet: None,
+        }
+    }
+
+    pub fn sector_size_shift(mut self, shift: u16) -> Builder {
+        self.sector_size_shift = shift;
+        self
+    }
+
+    pub fn listfile(mut self, listfile: bool) -> Builder {
+        self.listfile = listfile;
+        self
+    }
+
+    // keep compressed sectors even if they are larger than the input
+    pub fn always_compress(mut self, always_compress: bool) -> Builder {
+        self.always_compress = always_compress;
+        self
+    }
+
+    // store sector checksums as crc32 rather than adler32
+    pub fn crc32_checksums(mut self, crc32_checksums: bool) -> Builder {
+        self.crc32_checksums = crc32_checksums;
+        self
+    }
+
+    pub fn checksum_table(mut self, checksum_table: ChecksumTable) -> Builder {
+        self.checksum_table = checksum_table;
+        self
+    }
+
+    // write a v2 header followed by the given upper 16 bits of each block offset,
+    // missing entries are zero
+    pub fn hi_block_table(mut self, hi_block_table: &[u16]) -> Builder {
+        self.hi_block_table = Some(hi_block_table.to_vec());
+        self
+    }
+
+    // replace the hash and block tables with HET and BET tables, in a v4 header
+    // with compressed tables or a v3 header with tables stored as is
+    pub fn het_bet(mut self, compress: bool) -> Builder {
+        self.het_bet = Some(compress);
+        self
+    }
+
+    // add a sector based, zlib compressed file
+    pub fn file(self, name: &str, data: &[u8]) -> Builder {
+        self.file_with(name, data, FILE_COMPRESS)
+    }
+
+    pub fn file_with(self, name: &str, data: &[u8], flags: u32) -> Builder {
+        self.file_with_locale(name, data, flags, 0)
+    }
+
+    pub fn file_with_locale(mut self, name: &str, data: &[u8], flags: u32, locale: u16) -> Builder {
+        self.files.push(Entry {
+            name: String::from(name),
+            data: data.to_vec(),
+            flags: flags | FILE_EXISTS,
+            locale,
+            packed: None,
+        });
+        self
+    }
+
+    // add a compressed single unit file whose packed data is given, for methods the
+    // fixture can't compress with
+    pub fn file_packed(mut self, name: &str, data: &[u8], packed: &[u8]) -> Builder {
+        self.files.push(Entry {
+            name: String::from(name),
+            data: data.to_vec(),
+            flags: FILE_COMPRESS | FILE_SINGLE_UNIT | FILE_EXISTS,
+            locale: 0,
+            packed: Some(packed.to_vec()),
+        });
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let sector_size = 512usize << self.sector_size_shift;
+        let mut entries: Vec<&Entry> = self.files.iter().collect();
+        let listfile;
+
+        if self.listfile {
+            let names: Vec<&str> = self.files.iter().map(|e| e.name.as_str()).collect();
+
+            listfile = Entry {
+                name: String::from("(listfile)"),
+                data: names.join("\r\n").into_bytes(),
+                flags: FILE_COMPRESS | FILE_EXISTS,
+                locale: 0,
+                packed: None,
+            };
+
+            entries.push(&listfile);
+        }
+
+        let header_size = match (self.het_bet, &self.hi_block_table) {
+            (Some(true), _) => 0xD0,
+            (Some(false), _) => 0x44,
+            (None, Some(_)) => 0x2C,
+            (None, None) => 0x20,
+        };
+        let mut out = vec![0; header_size];
+        let mut blocks: Vec<[u32; 4]> = Vec::new();
+
+        for entry in &entries {
+            let offset = out.len() as u32;
+            let packed = encode(
+                entry,
+                offset,
+                sector_size,
+                self.always_compress,
+                self.crc32_checksums,
+                self.checksum_table,
+            );
+
+            blocks.push([
+                offset,
+                packed.len() as u32,
+                entry.data.len() as u32,
+                entry.flags,
+            ]);
+            out.extend_from_slice(&packed);
+        }
+
+        if let Some(compress) = self.het_bet {
+            let (het, bet) = het_bet_tables(&entries, &blocks);
+            let het = ext_table(
+                b"HET\x1A",
+                &het,
+                hash_string("(hash table)", 0x300),
+                compress,
+            );
+            let bet = ext_table(
+                b"BET\x1A",
+                &bet,
+                hash_string("(block table)", 0x300),
+                compress,
+            );
+
+            let het_table_offset = out.len() as u64;
+            out.extend_from_slice(&het);
+            let bet_table_offset = out.len() as u64;
+            out.extend_from_slice(&bet);
+
+            let archive_size = out.len();
+            let header = &mut out[0..header_size];
+            header[0..4].copy_from_slice(b"MPQ\x1A");
+            LittleEndian::write_u32(&mut header[0x04..], header_size as u32);
+            LittleEndian::write_u32(&mut header[0x08..], archive_size as u32);
+            LittleEndian::write_u16(&mut header[0x0C..], if compress { 3 } else { 2 });
+            LittleEndian::write_u16(&mut header[0x0E..], self.sector_size_shift);
+            LittleEndian::write_u32(&mut header[0x10..], archive_size as u32);
+            LittleEndian::write_u32(&mut header[0x14..], archive_size as u32);
+            LittleEndian::write_u64(&mut header[0x2C..], archive_size as u64);
+            LittleEndian::write_u64(&mut header[0x34..], bet_table_offset);
+            LittleEndian::write_u64(&mut header[0x3C..], het_table_offset);
+
+            // v4 headers also hold the md5 of both tables and of the header itself
+            if compress {
+                LittleEndian::write_u64(&mut header[0x5C..], het.len() as u64);
+                LittleEndian::write_u64(&mut header[0x64..], bet.len() as u64);
+                header[0xA0..0xB0].copy_from_slice(&Md5::digest(&bet));
+                header[0xB0..0xC0].copy_from_slice(&Md5::digest(&het));
+                let md5 = Md5::digest(&header[..0xC0]);
+                header[0xC0..0xD0].copy_from_slice(&md5);
+            }
+
+            return out;
+        }
+
+        let hash_count = (entries.len() * 2).next_power_of_two().max(4);
+        let mut hash_table = vec![0xFF; hash_count * 16];
+
+        for (block_index, entry) in entries.iter().enumerate() {
+            let mut index = hash_string(&entry.name, 0) as usize & (hash_count - 1);
+
+            while LittleEndian::read_u32(&hash_table[index * 16 + 12..]) != HASH_ENTRY_EMPTY {
+                index = (index + 1) & (hash_count - 1);
+            }
+
+            let slot = &mut hash_table[index * 16..(index + 1) * 16];
+            LittleEndian::write_u32(&mut slot[0..], hash_string(&entry.name, 0x100));
+            LittleEndian::write_u32(&mut slot[4..], hash_string(&entry.name, 0x200));
+            LittleEndian::write_u16(&mut slot[8..], entry.locale);
+            LittleEndian::write_u16(&mut slot[10..], 0);
+            LittleEndian::write_u32(&mut slot[12..], block_index as u32);
+        }
+
+        let mut block_table = vec![0; blocks.len() * 16];
+
+        for (i, block) in blocks.iter().enumerate() {
+            for (j, value) in block.iter().enumerate() {
+                LittleEndian::write_u32(&mut block_table[i * 16 + j * 4..], *value);
+            }
+        }
+
+        encrypt(&mut hash_table, hash_string("(hash table)", 0x300));
+        encrypt(&mut block_table, hash_string("(block table)", 0x300));
+
+        let hash_table_offset = out.len() as u32;
+        out.extend_from_slice(&hash_table);
+        let block_table_offset = out.len() as u32;
+        out.extend_from_slice(&block_table);
+
+        let hi_block_table_offset = out.len() as u64;
+
+        if let Some(hi_block_table) = &self.hi_block_table {
+            for i in 0..blocks.len() {
+                let hi = hi_block_table.get(i).cloned().unwrap_or(0);
+                out.extend_from_slice(&hi.to_le_bytes());
+            }
+        }
+
+        let archive_size = out.len() as u32;
+        let header = &mut out[0..header_size];
+        header[0..4].copy_from_slice(b"MPQ\x1A");
+        LittleEndian::write_u32(&mut header[0x04..], header_size as u32);
+        LittleEndian::write_u32(&mut header[0x08..], archive_size);
+        LittleEndian::write_u16(&mut header[0x0C..], 0);
+        LittleEndian::write_u16(&mut header[0x0E..], self.sector_size_shift);
+        LittleEndian::write_u32(&mut header[0x10..], hash_table_offset);
+        LittleEndian::write_u32(&mut header[0x14..], block_table_offset);
+        LittleEndian::write_u32(&mut header[0x18..], hash_count as u32);
+        LittleEndian::write_u32(&mut header[0x1C..], blocks.len() as u32);
+
+        if self.hi_block_table.is_some() {
+            LittleEndian::write_u16(&mut header[0x0C..], 1);
+            LittleEndian::write_u64(&mut header[0x20..], hi_block_table_offset);
+        }
+
+        out
+    }
+}
+
+// set count bits of value at bit offset, least significant bit first
+fn write_bits(dst: &mut [u8], offset: usize, count: usize, value: u64) {
+    for i in 0..count {
+        if value >> i & 1 != 0 {
+            dst[(offset + i) / 8] |= 1 << ((offset + i) % 8);
+        }
+    }
+}
+
+// HET table with 64 bit name hashes and BET table with 32 bit offsets and sizes
+// and an 8 bit flag index
+fn het_bet_tables(entries: &[&Entry], blocks: &[[u32; 4]]) -> (Vec<u8>, Vec<u8>) {
+    let count = entries.len();
+    let total = (count * 2).next_power_of_two().max(4);
+    let index_size = (32 - (count as u32).leading_zeros()).max(1) as usize;
+    let mut name_hashes = vec![0; total];
+    let mut indexes = vec![0; (total * index_size).div_ceil(8)];
+    let mut bet_hashes = vec![0; (count * 56).div_ceil(8)];
+
+    for (i, entry) in entries.iter().enumerate() {
+        let hash = hash_jenkins(&entry.name) | 1 << 63;
+        let mut slot = (hash % total as u64) as usize;
+
+        while name_hashes[slot] != 0 {
+            slot = (slot + 1) % total;
+        }
+
+        name_hashes[slot] = (hash >> 56) as u8;
+        write_bits(&mut indexes, slot * index_size, index_size, i as u64);
+        write_bits(&mut bet_hashes, i * 56, 56, hash & ((1 << 56) - 1));
+    }
+
+    let het_header = [
+        (12 + 32 + total + indexes.len()) as u32,
+        count as u32,
+        total as u32,
+        64,
+        index_size as u32,
+        0,
+        index_size as u32,
+        indexes.len() as u32,
+    ];
+    let mut het: Vec<u8> = het_header.iter().flat_map(|v| v.to_le_bytes()).collect();
+    het.extend_from_slice(&name_hashes);
+    het.extend_from_slice(&indexes);
+
+    let mut flags: Vec<u32> = Vec::new();
+
+    for block in blocks {
+        if !flags.contains(&block[3]) {
+            flags.push(block[3]);
+        }
+    }
+
+    let entry_size = 104;
+    let mut table = vec![0; (count * entry_size).div_ceil(8)];
+
+    for (i, block) in blocks.iter().enumerate() {
+        let base = i * entry_size;
+        let flag_index = flags.iter().position(|&f| f == block[3]).unwrap();
+
+        write_bits(&mut table, base, 32, u64::from(block[0]));
+        write_bits(&mut table, base + 32, 32, u64::from(block[2]));
+        write_bits(&mut table, base + 64, 32, u64::from(block[1]));
+        write_bits(&mut table, base + 96, 8, flag_index as u64);
+    }
+
+    let bet_header = [
+        (12 + 76 + flags.len() * 4 + table.len() + bet_hashes.len()) as u32,
+        count as u32,
+        0x10,
+        entry_size as u32,
+        0,
+        32,
+        64,
+        96,
+        104,
+        32,
+        32,
+        32,
+        8,
+        0,
+        56,
+        0,
+        56,
+        bet_hashes.len() as u32,
+        flags.len() as u32,
+    ];
+    let mut bet: Vec<u8> = bet_header
+        .iter()
+        .chain(flags.iter())
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+    bet.extend_from_slice(&table);
+    bet.extend_from_slice(&bet_hashes);
+
+    (het, bet)
+}
+
+// extended table header followed by the table, compressed then encrypted
+fn ext_table(signature: &[u8], data: &[u8], key: u32, compressed: bool) -> Vec<u8> {
+    let mut packed = if compressed {
+        compress(data, FILE_COMPRESS, false)
+    } else {
+        data.to_vec()
+    };
+
+    encrypt(&mut packed, key);
+
+    let mut out = signature.to_vec();
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(&packed);
+
+    out
+}
+
+fn file_key(entry: &Entry, offset: u32) -> u32 {
+    let basename = entry.name.rsplit(&['\\', '/'][..]).next().unwrap();
+    let mut key = hash_string(basename, 0x300);
+
+    if entry.flags & FILE_FIX_KEY != 0 {
+        key = (key.wrapping_add(offset)) ^ entry.data.len() as u32;
+    }
+
+    key
+}
+
+// adler32 of a sector as stored before encryption, or crc32 like some archivers
+fn checksum(sector: &[u8], crc32: bool) -> u32 {
+    if crc32 {
+        crc32fast::hash(sector)
+    } else {
+        let mut adler = RollingAdler32::from_value(0);
+        adler.update_buffer(sector);
+        adler.hash()
+    }
+}
+
+fn compress(data: &[u8], flags: u32, always: bool) -> Vec<u8> {
+    let packed = if flags & FILE_IMPLODE != 0 {
+        implode(data)
+    } else if cfg!(feature = "zlib") {
+        let mut encoder = ZlibEncoder::new(vec![0x02], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    } else {
+        sparse(data)
+    };
+
+    if always || packed.len() < data.len() {
+        packed
+    } else {
+        data.to_vec()
+    }
+}
+
+// sparse compressed data, which builds without the zlib feature can read
+fn sparse(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x20];
+    let mut i = 0;
+
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    while i < data.len() {
+        let zeros = data[i..].iter().take(130).take_while(|&&b| b == 0).count();
+
+        if zeros >= 3 {
+            out.push((zeros - 3) as u8);
+            i += zeros;
+            continue;
+        }
+
+        let start = i;
+
+        while i < data.len() && i - start < 128 && !data[i..].starts_with(&[0, 0, 0]) {
+            i += 1;
+        }
+
+        out.push(0x80 | (i - start - 1) as u8);
+        out.extend_from_slice(&data[start..i]);
+    }
+
+    out
+}
+
+// pkware implode stream made of literals only, which explode accepts but which
+// never compresses
+pub fn implode(data: &[u8]) -> Vec<u8> {
+    // binary literals, 4 KiB dictionary
+    let mut out = vec![0, 6];
+    let mut bits: u32 = 0;
+    let mut count = 0;
+
+    let mut push = |out: &mut Vec<u8>, value: u32, len: u32| {
+        bits |= value << count;
+        count += len;
+
+        while count >= 8 {
+            out.push(bits as u8);
+            bits >>= 8;
+            count -= 8;
+        }
+    };
+
+    for &b in data {
+        push(&mut out, u32::from(b) << 1, 9);
+    }
+
+    // end of stream is a match of length 519: flag bit, length code 15 (seven zero
+    // bits) and eight extra bits
+    push(&mut out, 1, 1);
+    push(&mut out, 0, 7);
+    push(&mut out, 0xFF, 8);
+    push(&mut out, 0, 7);
+
+    out
+}
+
+fn encode(
+    entry: &Entry,
+    offset: u32,
+    sector_size: usize,
+    always_compress: bool,
+    crc32_checksums: bool,
+    checksum_table: ChecksumTable,
+) -> Vec<u8> {
+    // patch files start with a patch info, their data follows it
+    if entry.flags & FILE_PATCH_FILE != 0 {
+        let data = Entry {
+            name: entry.name.clone(),
+            data: entry.data.clone(),
+            flags: entry.flags & !FILE_PATCH_FILE,
+            locale: entry.locale,
+            packed: entry.packed.clone(),
+        };
+        let mut packed = vec![0; PATCH_INFO_SIZE];
+
+        LittleEndian::write_u32(&mut packed[0x00..], PATCH_INFO_SIZE as u32);
+        LittleEndian::write_u32(&mut packed[0x04..], 0x80000000);
+        LittleEndian::write_u32(&mut packed[0x08..], entry.data.len() as u32);
+        packed[0x0C..].copy_from_slice(&Md5::digest(&entry.data));
+        packed.extend(encode(
+            &data,
+            offset,
+            sector_size,
+            always_compress,
+            crc32_checksums,
+            checksum_table,
+        ));
+
+        return packed;
+    }
+
+    if let Some(packed) = &entry.packed {
+        return packed.clone();
+    }
+
+    let key = file_key(entry, offset);
+    let encrypted = entry.flags & FILE_ENCRYPTED != 0;
+    let compressed = entry.flags & (FILE_COMPRESS | FILE_IMPLODE) != 0;
+
+    if entry.flags & FILE_SINGLE_UNIT != 0 {
+        let mut packed = if compressed {
+            compress(&entry.data, entry.flags, always_compress)
+        } else {
+            entry.data.clone()
+        };
+        let checksum = checksum(&packed, crc32_checksums);
+
+        if encrypted {
+            encrypt(&mut packed, key);
+        }
+
+        if compressed && entry.flags & FILE_SECTOR_CRC != 0 {
+            packed.extend_from_slice(&checksum.to_le_bytes());
+        }
+
+        return packed;
+    }
+
+    let mut sectors: Vec<Vec<u8>> = entry
+        .data
+        .chunks(sector_size)
+        .map(|chunk| {
+            if compressed {
+                compress(chunk, entry.flags, always_compress)
+            } else {
+                chunk.to_vec()
+            }
+        })
+        .collect();
+
+    if !compressed {
+        let mut packed = Vec::new();
+
+        for (i, sector) in sectors.iter_mut().enumerate() {
+            if encrypted {
+                encrypt(sector, key.wrapping_add(i as u32));
+            }
+            packed.extend_from_slice(sector);
+        }
+
+        return packed;
+    }
+
+    let checksums = entry.flags & FILE_SECTOR_CRC != 0;
+    let listed = checksums && checksum_table != ChecksumTable::Appended;
+    let num_offsets = sectors.len() + if listed { 2 } else { 1 };
+    let mut offset_table = vec![0; num_offsets * 4];
+    let mut data = Vec::new();
+    let mut position = offset_table.len() as u32;
+
+    for (i, sector) in sectors.iter_mut().enumerate() {
+        LittleEndian::write_u32(&mut offset_table[i * 4..], position);
+        position += sector.len() as u32;
+        data.push(sector.clone());
+
+        if encrypted {
+            encrypt(data.last_mut().unwrap(), key.wrapping_add(i as u32));
+        }
+    }
+
+    LittleEndian::write_u32(&mut offset_table[sectors.len() * 4..], position);
+
+    if checksums {
+        let mut table = vec![0; sectors.len() * 4];
+
+        for (i, sector) in sectors.iter().enumerate() {
+            LittleEndian::write_u32(&mut table[i * 4..], checksum(sector, crc32_checksums));
+        }
+
+        if checksum_table == ChecksumTable::Compressed {
+            table = compress(&table, FILE_COMPRESS, false);
+        }
+
+        position += table.len() as u32;
+
+        if listed {
+            LittleEndian::write_u32(&mut offset_table[(sectors.len() + 1) * 4..], position);
+        }
+
+        data.push(table);
+    }
+
+    if encrypted {
+        encrypt(&mut offset_table, key.wrapping_sub(1));
+    }
+
+    let mut packed = offset_table;
+
+    for sector in data {
+        packed.extend_from_slice(&sector);
+    }
+
+    packed
+}
+
+// PTCH patch turning base into new, either copying new as is or as bsdiff40 data
+// that adds every byte of new to base and takes the rest from the extra block
+pub fn patch(base: &[u8], new: &[u8], bsdiff: bool) -> Vec<u8> {
+    let data = if bsdiff {
+        let add = base.len().min(new.len());
+        let diff: Vec<u8> = (0..add).map(|i| new[i].wrapping_sub(base[i])).collect();
+        let mut data = b"BSDIFF40".to_vec();
+
+        data.extend_from_slice(&12u64.to_le_bytes());
+        data.extend_from_slice(&(add as u64).to_le_bytes());
+        data.extend_from_slice(&(new.len() as u64).to_le_bytes());
+        data.extend_from_slice(&(add as u32).to_le_bytes());
+        data.extend_from_slice(&((new.len() - add) as u32).to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&diff);
+        data.extend_from_slice(&new[add..]);
+        data
+    } else {
+        new.to_vec()
+    };
+
+    let mut out = b"PTCH".to_vec();
+    out.extend_from_slice(&((0x44 + data.len()) as u32).to_le_bytes());
+    out.extend_from_slice(&(base.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(new.len() as u32).to_le_bytes());
+    out.extend_from_slice(b"MD5_");
+    out.extend_from_slice(&0x28u32.to_le_bytes());
+    out.extend_from_slice(&Md5::digest(base));
+    out.extend_from_slice(&Md5::digest(new));
+    out.extend_from_slice(b"XFRM");
+    out.extend_from_slice(&((12 + data.len()) as u32).to_le_bytes());
+    out.extend_from_slice(if bsdiff { b"BSD0" } else { b"COPY" });
+    out.extend_from_slice(&data);
+    out
+}
+
+// place an archive behind `prefix` bytes of junk and a MPQ\x1B user data header
+pub fn embed(prefix: usize, user_data: &[u8], archive: &[u8]) -> Vec<u8> {
+    let mut out = vec![0xCC; prefix];
+    let header_offset = (16 + user_data.len() + 0x1FF) & !0x1FF;
+
+    out.extend_from_slice(b"MPQ\x1B");
+    out.extend_from_slice(&(user_data.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(header_offset as u32).to_le_bytes());
+    out.extend_from_slice(&(user_data.len() as u32).to_le_bytes());
+    out.extend_from_slice(user_data);
+    out.resize(prefix + header_offset, 0);
+    out.extend_from_slice(archive);
+
+    out
+}
+
+// stereo 16 bit WAVE file with an extra chunk before the samples
+pub fn wave(samples: &[u8]) -> Vec<u8> {
+    let mut out = b"RIFF\0\0\0\0WAVE".to_vec();
+
+    out.extend_from_slice(b"fmt \x12\0\0\0");
+    out.extend_from_slice(&[0; 18]);
+    let fmt = out.len() - 18;
+    LittleEndian::write_u16(&mut out[fmt..], 1);
+    LittleEndian::write_u16(&mut out[fmt + 2..], 2);
+    LittleEndian::write_u32(&mut out[fmt + 4..], 22050);
+    LittleEndian::write_u16(&mut out[fmt + 14..], 16);
+
+    out.extend_from_slice(b"LIST\x03\0\0\0abc\0");
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+    out.extend_from_slice(samples);
+
+    let len = out.len() as u32 - 8;
+    LittleEndian::write_u32(&mut out[4..], len);
+
+    out
+}
+
+// unique scratch directory for tests touching the filesystem
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("mpq-test-{}-{}", std::process::id(), name));
+
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
@@ -0,0 +1,55 @@
+// entry points of the fuzz targets, which feed untrusted bytes through the
+// parsing paths an archive reaches. every failure must come back as an error
+
+use crate::archive::Archive;
+use crate::listfile::parse_listfile;
+
+// sizes claimed by the archive aren't trusted, files are read at most this far
+const MAX_READ_SIZE: usize = 1 << 20;
+
+// load data as an archive, then open and read every file it names or indexes
+pub fn open_archive(data: &[u8]) {
+    let mut archive = match Archive::load(data) {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+
+    let _ = archive.computed_size();
+
+    let names = archive
+        .open_file("(listfile)")
+        .and_then(|file| file.read_to_end(&mut archive, MAX_READ_SIZE))
+        .map(|buf| parse_listfile(&buf))
+        .unwrap_or_default();
+
+    let mut files: Vec<_> = names
+        .iter()
+        .filter_map(|name| archive.open_file(name).ok())
+        .collect();
+
+    files.extend(archive.files());
+
+    for file in files {
+        for i in 0..file.sectors().len() {
+            let _ = file.read_raw_sector(&mut archive, i);
+        }
+
+        let _ = file.read_to_end(&mut archive, MAX_READ_SIZE);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::open_archive;
+
+    // inputs the fuzzer found to panic, they must now fail with errors
+    #[test]
+    fn regressions() {
+        // a listfile name outside of ASCII hashed past the end of the crypt table
+        open_archive(include_bytes!("../tests/fixtures/fuzz-non-ascii-name.mpq"));
+        // an imploded sector claiming more dictionary bits than pkware allows
+        open_archive(include_bytes!("../tests/fixtures/fuzz-dict-bits.mpq"));
+        // a v2 extended block table offset overflowing the embedded archive offset
+        open_archive(include_bytes!("../tests/fixtures/fuzz-extended-offset.mpq"));
+    }
+}
@@ -0,0 +1,304 @@
+use crate::compression::decompress;
+use crate::crypt::{decrypt, hash_jenkins};
+use crate::error::MpqError;
+use byteorder::{ByteOrder, LittleEndian};
+
+pub(crate) const ID_HET: u32 = 0x1A544548; // "HET\x1A"
+pub(crate) const ID_BET: u32 = 0x1A544542; // "BET\x1A"
+
+// signature, version and size of the data that follows
+const EXT_HEADER_SIZE: usize = 12;
+const HET_HEADER_SIZE: usize = 32;
+const BET_HEADER_SIZE: usize = 76;
+
+// name hash of a HET slot that was never used
+const HET_ENTRY_FREE: u8 = 0;
+
+// larger than the tables of the most files an archive may hold, a table claiming
+// more is rejected before it is allocated
+const MAX_EXT_TABLE_SIZE: usize = 0x4000000;
+
+// read the table following an extended table header, which is encrypted after
+// the header and compressed when it is stored in fewer bytes than it holds.
+// stored_size is zero when the header doesn't give it, the table is then
+// assumed to be stored as is
+pub(crate) fn read_ext_table(
+    src: &[u8],
+    stored_size: u64,
+    signature: u32,
+    key: u32,
+) -> Result<Vec<u8>, MpqError> {
+    if src.len() < EXT_HEADER_SIZE || LittleEndian::read_u32(src) != signature {
+        return Err(MpqError::Corrupt("Invalid extended table header"));
+    }
+
+    let data_size = LittleEndian::read_u32(&src[8..]) as usize;
+    if data_size > MAX_EXT_TABLE_SIZE {
+        return Err(MpqError::Corrupt("Extended table too large"));
+    }
+
+    let stored_size = match stored_size {
+        0 => EXT_HEADER_SIZE + data_size,
+        v => v as usize,
+    };
+
+    if stored_size < EXT_HEADER_SIZE || stored_size > src.len() {
+        return Err(MpqError::Corrupt(
+            "Extended table extends past the end of the archive",
+        ));
+    }
+
+    let mut data = src[EXT_HEADER_SIZE..stored_size].to_vec();
+
+    decrypt(&mut data, key);
+
+    if data.len() >= data_size {
+        data.truncate(data_size);
+        return Ok(data);
+    }
+
+    let mut out = vec![0; data_size];
+    let len = decompress(&data, &mut out, &mut Vec::new())?;
+
+    if len != data_size {
+        return Err(MpqError::Corrupt("Extended table decompressed short"));
+    }
+
+    Ok(out)
+}
+
+// read count bits starting at bit offset, least significant bit first
+fn read_bits(src: &[u8], offset: u64, count: u32) -> Result<u64, MpqError> {
+    if count > 64 || offset + u64::from(count) > src.len() as u64 * 8 {
+        return Err(MpqError::Corrupt("Bit field out of range"));
+    }
+
+    let mut value = 0;
+
+    for i in 0..u64::from(count) {
+        let bit = offset + i;
+
+        if src[(bit / 8) as usize] & (1 << (bit % 8)) != 0 {
+            value |= 1 << i;
+        }
+    }
+
+    Ok(value)
+}
+
+fn table_slice(src: &[u8], start: usize, len: usize) -> Result<&[u8], MpqError> {
+    start
+        .checked_add(len)
+        .and_then(|end| src.get(start..end))
+        .ok_or(MpqError::Corrupt("Extended table too short"))
+}
+
+// name hash table of v3 and v4 archives, which maps a 64 bit jenkins hash of a
+// name to an index into the BET table
+#[derive(Debug)]
+pub(crate) struct HetTable {
+    name_hash_bits: u32,
+    index_size_total: u32,
+    index_size: u32,
+    name_hashes: Vec<u8>,
+    indexes: Vec<u8>,
+}
+
+impl HetTable {
+    // parse a HET table as returned by read_ext_table
+    pub fn new(src: &[u8]) -> Result<HetTable, MpqError> {
+        let header = table_slice(src, 0, HET_HEADER_SIZE)?;
+        let total_count = LittleEndian::read_u32(&header[0x08..]) as usize;
+        let name_hash_bits = LittleEndian::read_u32(&header[0x0C..]);
+        let index_size_total = LittleEndian::read_u32(&header[0x10..]);
+        let index_size = LittleEndian::read_u32(&header[0x18..]);
+        let index_table_size = LittleEndian::read_u32(&header[0x1C..]) as usize;
+
+        if total_count == 0 || name_hash_bits <= 8 || name_hash_bits > 64 {
+            return Err(MpqError::Corrupt("Invalid HET table header"));
+        }
+
+        if index_size > index_size_total || index_size > 32 {
+            return Err(MpqError::Corrupt("Invalid HET table header"));
+        }
+
+        let name_hashes = table_slice(src, HET_HEADER_SIZE, total_count)?.to_vec();
+        let indexes = table_slice(src, HET_HEADER_SIZE + total_count, index_table_size)?.to_vec();
+
+        Ok(HetTable {
+            name_hash_bits,
+            index_size_total,
+            index_size,
+            name_hashes,
+            indexes,
+        })
+    }
+
+    // full name hash of name as stored across the HET and BET tables
+    fn name_hash(&self, name: &str) -> u64 {
+        let and_mask = match self.name_hash_bits {
+            64 => !0,
+            bits => (1 << bits) - 1,
+        };
+        let or_mask = 1 << (self.name_hash_bits - 1);
+
+        (hash_jenkins(name) & and_mask) | or_mask
+    }
+
+    // BET index of name, bet_hashes holds the remaining name hash bits of every
+    // BET entry to tell apart names sharing the upper eight bits
+    pub fn find(&self, name: &str, bet_hashes: &[u64]) -> Option<u32> {
+        let hash = self.name_hash(name);
+        let hash1 = (hash >> (self.name_hash_bits - 8)) as u8;
+        let hash2 = hash & ((1 << (self.name_hash_bits - 8)) - 1);
+        let total = self.name_hashes.len();
+        let start = (hash % total as u64) as usize;
+        let mut i = start;
+
+        while self.name_hashes[i] != HET_ENTRY_FREE {
+            if self.name_hashes[i] == hash1 {
+                let offset = i as u64 * u64::from(self.index_size_total);
+
+                if let Ok(index) = read_bits(&self.indexes, offset, self.index_size) {
+                    if bet_hashes.get(index as usize) == Some(&hash2) {
+                        return Some(index as u32);
+                    }
+                }
+            }
+
+            i = (i + 1) % total;
+
+            if i == start {
+                break;
+            }
+        }
+
+        None
+    }
+}
+
+// file table of v3 and v4 archives, every field of an entry is packed in as few
+// bits as the largest value needs
+#[derive(Debug)]
+pub(crate) struct BetEntry {
+    pub offset: u64,
+    pub unpacked_size: u64,
+    pub packed_size: u64,
+    pub flags: u32,
+    pub name_hash: u64,
+}
+
+// parse a BET table as returned by read_ext_table
+pub(crate) fn read_bet_table(src: &[u8]) -> Result<Vec<BetEntry>, MpqError> {
+    let header = table_slice(src, 0, BET_HEADER_SIZE)?;
+    let field = |i: usize| LittleEndian::read_u32(&header[i * 4..]);
+
+    let entry_count = field(1) as usize;
+    let entry_size = u64::from(field(3));
+    let bit_index = [field(4), field(5), field(6), field(7)];
+    let bit_count = [field(9), field(10), field(11), field(12)];
+    let hash_size_total = u64::from(field(14));
+    let hash_size = field(16);
+    let hash_array_size = field(17) as usize;
+    let flag_count = field(18) as usize;
+
+    // every entry takes up at least a bit of the table, which bounds the count
+    if entry_size == 0 && entry_count != 0 {
+        return Err(MpqError::Corrupt("Invalid BET table header"));
+    }
+
+    let flags: Vec<u32> = table_slice(src, BET_HEADER_SIZE, flag_count.saturating_mul(4))?
+        .chunks_exact(4)
+        .map(LittleEndian::read_u32)
+        .collect();
+
+    let table_start = BET_HEADER_SIZE + flags.len() * 4;
+    let table_size = (entry_size * entry_count as u64).div_ceil(8);
+    let table = table_slice(src, table_start, table_size as usize)?;
+    let hashes = table_slice(src, table_start + table.len(), hash_array_size)?;
+
+    let mut entries = Vec::with_capacity(entry_count.min(src.len()));
+
+    for i in 0..entry_count as u64 {
+        let base = i * entry_size;
+        let value = |f: usize| read_bits(table, base + u64::from(bit_index[f]), bit_count[f]);
+
+        let flags = if flags.is_empty() {
+            0
+        } else {
+            match flags.get(value(3)? as usize) {
+                Some(&v) => v,
+                None => return Err(MpqError::Corrupt("BET flag index out of range")),
+            }
+        };
+
+        entries.push(BetEntry {
+            offset: value(0)?,
+            unpacked_size: value(1)?,
+            packed_size: value(2)?,
+            flags,
+            name_hash: read_bits(hashes, i * hash_size_total, hash_size)?,
+        });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_bet_table, read_bits, read_ext_table, BET_HEADER_SIZE, ID_BET, ID_HET};
+    use crate::crypt::encrypt;
+    use crate::error::MpqError;
+
+    #[test]
+    fn bits() {
+        let src = [0b1010_1100, 0b0000_0011, 0xFF];
+
+        assert_eq!(0b1100, read_bits(&src, 0, 4).unwrap());
+        assert_eq!(0b11_1010, read_bits(&src, 4, 6).unwrap());
+        assert_eq!(0xFF, read_bits(&src, 16, 8).unwrap());
+        assert_eq!(0, read_bits(&src, 3, 0).unwrap());
+        assert!(read_bits(&src, 20, 8).is_err());
+    }
+
+    #[test]
+    fn ext_table() {
+        let mut body = vec![0; 200];
+        body.extend_from_slice(b"abc");
+
+        // sparse: 130 and 70 zeros then a literal "abc"
+        let mut packed = vec![0x20];
+        packed.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        packed.extend_from_slice(b"\x7F\x43\x82abc");
+
+        encrypt(&mut packed, 0x1234);
+
+        let mut src = ID_HET.to_le_bytes().to_vec();
+        src.extend_from_slice(&1u32.to_le_bytes());
+        src.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        src.extend_from_slice(&packed);
+
+        let stored = src.len() as u64;
+
+        assert_eq!(body, read_ext_table(&src, stored, ID_HET, 0x1234).unwrap());
+        assert!(read_ext_table(&src, stored, ID_BET, 0x1234).is_err());
+        assert!(read_ext_table(&src, stored + 1, ID_HET, 0x1234).is_err());
+
+        // a compressed table claiming gigabytes fails before it is allocated
+        src[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        assert!(matches!(
+            read_ext_table(&src, stored, ID_HET, 0x1234),
+            Err(MpqError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn bet_table_empty_entries() {
+        // four billion entries of no bits each
+        let mut src = vec![0; BET_HEADER_SIZE];
+        src[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        assert!(matches!(read_bet_table(&src), Err(MpqError::Corrupt(_))));
+    }
+}
@@ -1,11 +1,37 @@
-//! A library for reading MPQ archives
+//! A library for reading and writing MPQ archives
 
-#![cfg_attr(feature = "cargo-clippy", allow(clippy::unreadable_literal))]
+#![allow(clippy::unreadable_literal)]
 
 mod archive;
+mod attributes;
 mod chain;
 mod compression;
 mod crypt;
+mod error;
+#[cfg(test)]
+mod fixture;
+#[cfg(any(test, feature = "fuzzing"))]
+#[doc(hidden)]
+pub mod fuzz;
+mod het_bet;
+mod listfile;
+mod manifest;
+mod map_info;
+mod patch;
+mod signature;
+mod wav;
+mod writer;
 
-pub use crate::archive::{Archive, File};
+pub use crate::archive::{Archive, File, OpenOptions, SectorInfo};
+pub use crate::attributes::Attributes;
 pub use crate::chain::Chain;
+pub use crate::compression::{CompressionMethod, Decompressor};
+pub use crate::crypt::{
+    hash_name, hash_string, HASH_FILE_KEY, HASH_NAME_A, HASH_NAME_B, HASH_TABLE_INDEX,
+};
+pub use crate::error::MpqError;
+pub use crate::listfile::parse_listfile;
+pub use crate::manifest::{ArchiveInfo, ArchiveStats, FileEntry, Manifest};
+pub use crate::map_info::W3MapInfo;
+pub use crate::signature::Signature;
+pub use crate::writer::{ArchiveWriter, FileOptions};
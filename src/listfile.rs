@@ -0,0 +1,75 @@
+/// Split the contents of a `(listfile)` into file names
+///
+/// Entries may be separated by `\r\n`, `\n` or a bare `\r`. Surrounding
+/// whitespace is trimmed and blank lines are skipped.
+pub fn parse_listfile(bytes: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(bytes)
+        .split(&['\r', '\n'][..])
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+// match an archive file name against a pattern where * stands for any run of
+// characters, separators included, and ? for one. case is ignored like it is
+// by the hash table
+pub(crate) fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().map(|c| c.to_ascii_uppercase()).collect();
+    let name: Vec<char> = name.chars().map(|c| c.to_ascii_uppercase()).collect();
+    let (mut p, mut n) = (0, 0);
+    // position after the last * and the name position it was tried at
+    let mut retry = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                retry = Some((p, n));
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match retry {
+                // let the last * take one more character
+                Some((star, from)) => {
+                    p = star;
+                    n = from + 1;
+                    retry = Some((star, from + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_listfile, wildcard_match};
+
+    #[test]
+    fn mixed_line_endings() {
+        let listfile = b"a.txt\r\nUnits\\b.mdx\rc.wav\n\r\n  d.txt \n\n";
+
+        assert_eq!(
+            vec!["a.txt", "Units\\b.mdx", "c.wav", "d.txt"],
+            parse_listfile(listfile)
+        );
+        assert!(parse_listfile(b"").is_empty());
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("Units\\*", "units\\Human\\Footman.mdx"));
+        assert!(wildcard_match("*.mdx", "Units\\Human\\Footman.mdx"));
+        assert!(wildcard_match("*\\Foot???.*", "Units\\Human\\Footman.mdx"));
+        assert!(wildcard_match("*a*a*", "banana"));
+        assert!(wildcard_match("*", ""));
+        assert!(!wildcard_match("*.mdx", "Units\\Human\\Footman.blp"));
+        assert!(!wildcard_match("Units\\?", "Units\\ab"));
+        assert!(!wildcard_match("", "a"));
+    }
+}
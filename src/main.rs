@@ -1,4 +1,4 @@
-use mpq::Archive;
+use mpq::{Archive, Attributes, File};
 use std::env;
 use std::io::{self, Write};
 use std::process;
@@ -20,7 +20,7 @@ fn list(archive_file_name: &str) {
         }
     };
 
-    let file = match archive.open_file("(listfile)") {
+    let names = match archive.list_files() {
         Ok(v) => v,
         Err(e) => {
             println!("{}", e);
@@ -28,17 +28,199 @@ fn list(archive_file_name: &str) {
         }
     };
 
-    let mut buf: Vec<u8> = vec![0; file.size() as usize];
+    for name in names {
+        println!("{}", name);
+    }
+}
+
+// print each file with its sizes and flags, falling back to block indices if the
+// archive has no listfile
+fn list_long(archive_file_name: &str) {
+    let mut archive = match Archive::open(archive_file_name) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let names = match archive.list_files() {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let files: Vec<File> = if names.is_empty() {
+        archive.files().collect()
+    } else {
+        names
+            .iter()
+            .filter_map(|name| archive.open_file(name).ok())
+            .collect()
+    };
+
+    println!(
+        "{:>10} {:>10} {:>6} {:5} Name",
+        "Size", "Packed", "Ratio", "Flags"
+    );
+
+    for file in files {
+        println!("{:#}", file);
+    }
+}
+
+// print the archive header and a summary of its files
+fn info(archive_file_name: &str) {
+    let mut archive = match Archive::open(archive_file_name) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
+
+    let info = archive.info();
+    let stats = archive.stats();
+
+    println!("Format version:   {}", info.format_version);
+    println!("Header size:      {}", info.header_size);
+    println!("Archive offset:   {}", info.offset);
+    println!("Archive size:     {}", info.archive_size);
+    println!("Sector size:      {}", info.sector_size);
+    println!(
+        "Hash table:       {} entries at {}",
+        info.hash_table_count, info.hash_table_offset
+    );
+    println!(
+        "Block table:      {} entries at {}",
+        info.block_table_count, info.block_table_offset
+    );
+
+    match info.user_data_size {
+        Some(size) => println!("User data:        {} bytes", size),
+        None => println!("User data:        none"),
+    }
+
+    println!(
+        "Files:            {} ({} named, {} compressed, {} encrypted)",
+        stats.files, stats.named, stats.compressed, stats.encrypted
+    );
+    println!("Packed size:      {}", stats.packed_size);
+    println!("Unpacked size:    {}", stats.unpacked_size);
+}
+
+// extract every listed file below dest, reporting failures instead of stopping
+fn extract_all(archive_file_name: &str, dest: &str) {
+    let mut archive = match Archive::open(archive_file_name) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{}", e);
+            process::exit(1);
+        }
+    };
 
-    match file.read(&mut archive, &mut buf) {
-        Ok(_) => {}
+    let names = match archive.list_files() {
+        Ok(v) => v,
         Err(e) => {
             println!("{}", e);
             process::exit(1);
         }
+    };
+
+    let mut extracted = 0;
+    let mut failed = 0;
+
+    for name in &names {
+        let result = archive
+            .open_file(name)
+            .and_then(|file| file.extract_tree(&mut archive, dest));
+
+        match result {
+            Ok(_) => extracted += 1,
+            Err(e) => {
+                println!("{}: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("extracted {} files, {} failed", extracted, failed);
+
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+// check the table md5s of v4 archives, every file's sector checksums and
+// (attributes) digests, exits nonzero if anything fails
+fn verify(archive_file_name: &str) {
+    let mut archive = match Archive::open(archive_file_name) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("FAILED  {}: {}", archive_file_name, e);
+            process::exit(1);
+        }
+    };
+
+    let mut failed = 0;
+
+    if let Err(e) = archive.verify_tables() {
+        println!("FAILED  (tables): {}", e);
+        failed += 1;
+    }
+
+    let attributes = match archive.read_attributes() {
+        Ok(v) => v.unwrap_or_default(),
+        Err(e) => {
+            println!("FAILED  (attributes): {}", e);
+            failed += 1;
+            Attributes::default()
+        }
+    };
+
+    let mut files: Vec<File> = Vec::new();
+
+    match archive.list_files() {
+        Ok(names) if names.is_empty() => files.extend(archive.files()),
+        Ok(names) => {
+            for name in names {
+                match archive.open_file(&name) {
+                    Ok(file) => files.push(file),
+                    Err(e) => {
+                        println!("FAILED  {}: {}", name, e);
+                        failed += 1;
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            println!("FAILED  (listfile): {}", e);
+            failed += 1;
+        }
     }
 
-    io::stdout().write_all(&buf).unwrap();
+    let mut passed = 0;
+
+    for file in files {
+        match file.verify(&mut archive, &attributes) {
+            Ok(_) => {
+                println!("OK      {}", file.name());
+                passed += 1;
+            }
+            Err(e) => {
+                println!("FAILED  {}: {}", file.name(), e);
+                failed += 1;
+            }
+        }
+    }
+
+    println!("{} files ok, {} failed", passed, failed);
+
+    if failed > 0 {
+        process::exit(1);
+    }
 }
 
 fn main() {
@@ -47,8 +229,25 @@ fn main() {
     let mut opts = getopts::Options::new();
 
     opts.optopt("x", "extract", "extract file from archive", "FILE");
+    opts.optopt(
+        "a",
+        "extract-all",
+        "extract all listed files into DIR",
+        "DIR",
+    );
     opts.optflag("o", "to-stdout", "extract file to standard output");
-    opts.optflag("l", "list", "print (listfile) contents");
+    opts.optflag("l", "list", "print the names listed in (listfile)");
+    opts.optflag("L", "list-long", "list files with their sizes and flags");
+    opts.optflag(
+        "c",
+        "verify",
+        "check table md5s, file checksums and attributes",
+    );
+    opts.optflag(
+        "i",
+        "info",
+        "print the archive header and a summary of its files",
+    );
     opts.optflag("v", "version", "print version info");
     opts.optflag("h", "help", "print this help menu");
 
@@ -74,11 +273,31 @@ fn main() {
         return;
     };
 
+    if matches.opt_present("info") {
+        info(&archive_file_name);
+        return;
+    }
+
+    if matches.opt_present("list-long") {
+        list_long(&archive_file_name);
+        return;
+    }
+
     if matches.opt_present("list") {
         list(&archive_file_name);
         return;
     }
 
+    if matches.opt_present("verify") {
+        verify(&archive_file_name);
+        return;
+    }
+
+    if let Some(dest) = matches.opt_str("extract-all") {
+        extract_all(&archive_file_name, &dest);
+        return;
+    }
+
     if let Some(filename) = matches.opt_str("extract") {
         let mut archive = match Archive::open(archive_file_name) {
             Ok(v) => v,
@@ -91,15 +310,13 @@ fn main() {
         let file = archive.open_file(&filename).unwrap();
 
         if matches.opt_present("to-stdout") {
-            let mut buf: Vec<u8> = vec![0; file.size() as usize];
-
-            match file.read(&mut archive, &mut buf) {
-                Ok(_) => {}
+            let buf = match file.read_to_vec(&mut archive) {
+                Ok(v) => v,
                 Err(e) => {
                     println!("{}", e);
                     process::exit(1);
                 }
-            }
+            };
 
             io::stdout().write_all(&buf).unwrap();
         } else {
@@ -0,0 +1,113 @@
+use crate::compression::CompressionMethod;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// Table of contents of an archive, see [`Archive::manifest`](crate::Archive::manifest)
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct Manifest {
+    /// size of the archive in bytes as recorded in its header
+    pub archive_size: u32,
+    /// size of a file sector in bytes
+    pub sector_size: u32,
+    /// one entry per existing block, in block table order
+    pub files: Vec<FileEntry>,
+}
+
+/// Description of a single block of an archive
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct FileEntry {
+    /// file name, if the archive's listfile names this block
+    pub name: Option<String>,
+    /// index into the block table
+    pub block_index: u32,
+    /// offset of the file data, relative to the beginning of the archive
+    pub offset: u64,
+    /// compressed file size
+    pub packed_size: u32,
+    /// uncompressed file size
+    pub unpacked_size: u32,
+    /// flags for file
+    pub flags: u32,
+    /// language of file using windows LANGID type
+    pub locale: u16,
+    /// CRC32 of the uncompressed data, if the archive stores `(attributes)`
+    pub crc32: Option<u32>,
+    /// methods the file is compressed with, read from its first compressed
+    /// sector. empty if it is stored or can't be opened
+    pub compression: Vec<CompressionMethod>,
+    /// compressed size divided by uncompressed size, 1 for empty files
+    pub ratio: f32,
+}
+
+/// Header fields of an archive, see [`Archive::info`](crate::Archive::info)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ArchiveInfo {
+    /// format version from the header, 0 for the original format
+    pub format_version: u16,
+    /// size of the header in bytes
+    pub header_size: u32,
+    /// position of the header in the underlying data
+    pub offset: u64,
+    /// size of the archive in bytes as recorded in its header
+    pub archive_size: u32,
+    /// size of a file sector in bytes
+    pub sector_size: u32,
+    /// offset of the hash table, relative to the beginning of the archive
+    pub hash_table_offset: u64,
+    /// number of entries in the hash table
+    pub hash_table_count: u32,
+    /// offset of the block table, relative to the beginning of the archive
+    pub block_table_offset: u64,
+    /// number of entries in the block table
+    pub block_table_count: u32,
+    /// size of the user data in front of the archive, if there is any
+    pub user_data_size: Option<u32>,
+}
+
+/// Summary of an archive's blocks, see [`Archive::stats`](crate::Archive::stats)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct ArchiveStats {
+    /// number of entries in the block table, including deleted ones
+    pub block_count: u32,
+    /// number of existing files
+    pub files: u32,
+    /// files named by the listfile or one of the special files
+    pub named: u32,
+    /// files that are encrypted
+    pub encrypted: u32,
+    /// files that are compressed or imploded
+    pub compressed: u32,
+    /// total size of the files as stored
+    pub packed_size: u64,
+    /// total size of the files once decompressed
+    pub unpacked_size: u64,
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod test {
+    use crate::archive::Archive;
+    use crate::fixture::Builder;
+
+    #[test]
+    fn serialize_manifest() {
+        let buf = Builder::new()
+            .file("a.txt", b"hello")
+            .file("Units\\b.txt", b"world!")
+            .build();
+        let mut archive = Archive::load(buf).unwrap();
+        let json = serde_json::to_value(archive.manifest()).unwrap();
+        let files = json["files"].as_array().unwrap();
+
+        assert_eq!(3, files.len());
+        assert_eq!("a.txt", files[0]["name"]);
+        assert_eq!(5, files[0]["unpacked_size"]);
+        assert_eq!("Units\\b.txt", files[1]["name"]);
+        assert_eq!(0, files[1]["locale"]);
+        assert_eq!("(listfile)", files[2]["name"]);
+        assert!(files[0]["crc32"].is_null());
+    }
+}
@@ -0,0 +1,91 @@
+use crate::error::MpqError;
+use byteorder::{ByteOrder, LittleEndian};
+
+const ID_HM3W: &[u8] = b"HM3W";
+
+/// Header of a Warcraft III map, stored in front of the map's archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct W3MapInfo {
+    /// name of the map, usually a trigger string reference like `TRIGSTR_001`
+    pub name: String,
+    /// map flags as stored in the map's war3map.w3i
+    pub flags: u32,
+    /// maximum number of players
+    pub max_players: u32,
+}
+
+impl W3MapInfo {
+    // parse the header at the start of src, None if src doesn't start with one
+    pub fn new(src: &[u8]) -> Result<Option<W3MapInfo>, MpqError> {
+        if !src.starts_with(ID_HM3W) {
+            return Ok(None);
+        }
+
+        // magic and an unused field precede the name
+        let rest = match src.get(8..) {
+            Some(v) => v,
+            None => return Err(MpqError::Corrupt("Map header truncated")),
+        };
+
+        let name_len = match rest.iter().position(|&b| b == 0) {
+            Some(v) => v,
+            None => return Err(MpqError::Corrupt("Map name not terminated")),
+        };
+
+        let fields = &rest[name_len + 1..];
+
+        if fields.len() < 8 {
+            return Err(MpqError::Corrupt("Map header truncated"));
+        }
+
+        Ok(Some(W3MapInfo {
+            name: String::from_utf8_lossy(&rest[..name_len]).into_owned(),
+            flags: LittleEndian::read_u32(fields),
+            max_players: LittleEndian::read_u32(&fields[4..]),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::W3MapInfo;
+    use crate::archive::Archive;
+    use crate::fixture::Builder;
+
+    fn map_header(name: &str, flags: u32, max_players: u32) -> Vec<u8> {
+        let mut out = b"HM3W\0\0\0\0".to_vec();
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        out.extend_from_slice(&flags.to_le_bytes());
+        out.extend_from_slice(&max_players.to_le_bytes());
+        out.resize(0x200, 0);
+
+        out
+    }
+
+    #[test]
+    fn read_w3_map_info() {
+        let mut buf = map_header("Lost Temple", 0x8C, 4);
+        buf.extend(Builder::new().file("war3map.j", b"function main").build());
+
+        let archive = Archive::load(buf).unwrap();
+
+        assert_eq!(
+            Some(W3MapInfo {
+                name: String::from("Lost Temple"),
+                flags: 0x8C,
+                max_players: 4,
+            }),
+            archive.read_w3_map_info().unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_map_info() {
+        let archive = Archive::load(Builder::new().file("a.txt", b"a").build()).unwrap();
+
+        assert!(archive.read_w3_map_info().unwrap().is_none());
+        assert!(W3MapInfo::new(b"HM3W\0\0\0\0no terminator").is_err());
+        assert!(W3MapInfo::new(b"HM3W\0\0\0\0name\0\x01\0").is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use crate::error::MpqError;
+use byteorder::{ByteOrder, LittleEndian};
+use md5::{Digest, Md5};
+use std::convert::TryFrom;
+
+const ID_PTCH: &[u8] = b"PTCH";
+const ID_MD5: &[u8] = b"MD5_";
+const ID_XFRM: &[u8] = b"XFRM";
+const ID_BSDIFF40: &[u8] = b"BSDIFF40";
+
+// PTCH, MD5_ and XFRM headers up to the start of the patch data
+const PATCH_HEADER_SIZE: usize = 0x44;
+const XFRM_HEADER_SIZE: usize = 12;
+const BSDIFF_HEADER_SIZE: usize = 32;
+
+// size of the patch info that precedes the data of a FILE_PATCH_FILE block
+pub(crate) const PATCH_INFO_SIZE: usize = 0x1C;
+
+// apply a PTCH patch, as stored in a FILE_PATCH_FILE block, to base. both the
+// base and the result are checked against the md5 digests in the patch
+pub(crate) fn apply_patch(base: &[u8], patch: &[u8]) -> Result<Vec<u8>, MpqError> {
+    if patch.len() < PATCH_HEADER_SIZE
+        || &patch[0x00..0x04] != ID_PTCH
+        || &patch[0x10..0x14] != ID_MD5
+        || &patch[0x38..0x3C] != ID_XFRM
+    {
+        return Err(MpqError::Corrupt("Invalid patch header"));
+    }
+
+    let size_before = LittleEndian::read_u32(&patch[0x08..]) as usize;
+    let size_after = LittleEndian::read_u32(&patch[0x0C..]) as usize;
+    let md5_before = &patch[0x18..0x28];
+    let md5_after = &patch[0x28..0x38];
+    let xfrm_size = LittleEndian::read_u32(&patch[0x3C..]) as usize;
+
+    if base.len() != size_before || md5_before != &Md5::digest(base)[..] {
+        return Err(MpqError::Md5Mismatch);
+    }
+
+    let data = xfrm_size
+        .checked_sub(XFRM_HEADER_SIZE)
+        .and_then(|len| patch.get(PATCH_HEADER_SIZE..PATCH_HEADER_SIZE + len))
+        .ok_or(MpqError::Corrupt(
+            "Patch data extends past the end of the patch",
+        ))?;
+
+    let out = match &patch[0x40..0x44] {
+        b"COPY" => data.to_vec(),
+        b"BSD0" if data.starts_with(ID_BSDIFF40) => bsdiff(base, data, size_after)?,
+        b"BSD0" => bsdiff(
+            base,
+            &unpack_rle(data, bsdiff_max_size(size_after))?,
+            size_after,
+        )?,
+        _ => return Err(MpqError::Corrupt("Unknown patch type")),
+    };
+
+    if out.len() != size_after || md5_after != &Md5::digest(&out)[..] {
+        return Err(MpqError::Md5Mismatch);
+    }
+
+    Ok(out)
+}
+
+// largest bsdiff data producing size_after bytes: at most a control triple and a
+// byte of diff or extra data for every byte of the result, and a last triple
+fn bsdiff_max_size(size_after: usize) -> usize {
+    size_after
+        .saturating_add(1)
+        .saturating_mul(12 + 1)
+        .saturating_add(BSDIFF_HEADER_SIZE)
+}
+
+// undo the run length encoding of BSD0 data, which starts with its unpacked size.
+// a set high bit is followed by that many literal bytes less one, otherwise the
+// byte counts zeros less one. an unpacked size over max_size is rejected before
+// anything is allocated
+fn unpack_rle(data: &[u8], max_size: usize) -> Result<Vec<u8>, MpqError> {
+    if data.len() < 4 {
+        return Err(MpqError::Corrupt("Patch data too short"));
+    }
+
+    let size = LittleEndian::read_u32(data) as usize;
+
+    if size > max_size {
+        return Err(MpqError::Corrupt(
+            "Patch data larger than the patched file allows",
+        ));
+    }
+    let mut out = Vec::with_capacity(size.min(data.len() * 128));
+    let mut pos = 4;
+
+    while pos < data.len() && out.len() < size {
+        let byte = data[pos];
+        pos += 1;
+
+        if byte & 0x80 != 0 {
+            let count = (usize::from(byte & 0x7F) + 1)
+                .min(data.len() - pos)
+                .min(size - out.len());
+
+            out.extend_from_slice(&data[pos..pos + count]);
+            pos += count;
+        } else {
+            let count = (usize::from(byte) + 1).min(size - out.len());
+
+            out.resize(out.len() + count, 0);
+        }
+    }
+
+    out.resize(size, 0);
+
+    Ok(out)
+}
+
+// apply bsdiff40 data as stored by blizzard, whose control block holds 32 bit
+// triples of the bytes to add to the base, the bytes to copy from the extra
+// block and how far to move in the base, negative values have the top bit set
+fn bsdiff(base: &[u8], data: &[u8], size_after: usize) -> Result<Vec<u8>, MpqError> {
+    let header = data
+        .get(..BSDIFF_HEADER_SIZE)
+        .ok_or(MpqError::Corrupt("Patch data too short"))?;
+    let ctrl_size = LittleEndian::read_u64(&header[0x08..]) as usize;
+    let data_size = LittleEndian::read_u64(&header[0x10..]) as usize;
+    let new_size = LittleEndian::read_u64(&header[0x18..]) as usize;
+
+    if new_size != size_after {
+        return Err(MpqError::Corrupt("Patch data size mismatch"));
+    }
+
+    let section = |start: usize, len: usize| {
+        start
+            .checked_add(len)
+            .and_then(|end| data.get(start..end))
+            .ok_or(MpqError::Corrupt("Patch data too short"))
+    };
+    let ctrl = section(BSDIFF_HEADER_SIZE, ctrl_size)?;
+    let mut diff = section(BSDIFF_HEADER_SIZE + ctrl_size, data_size)?;
+    let mut extra = &data[BSDIFF_HEADER_SIZE + ctrl_size + data_size..];
+
+    let mut out: Vec<u8> = Vec::with_capacity(new_size.min(data.len() + base.len()));
+    let mut old_offset: i64 = 0;
+
+    for triple in ctrl.chunks_exact(12) {
+        if out.len() >= new_size {
+            break;
+        }
+
+        let add = LittleEndian::read_u32(triple) as usize;
+        let copy = LittleEndian::read_u32(&triple[4..]) as usize;
+        let seek = LittleEndian::read_u32(&triple[8..]);
+        let seek = if seek & 0x80000000 != 0 {
+            -i64::from(seek & 0x7FFFFFFF)
+        } else {
+            i64::from(seek)
+        };
+
+        if add > diff.len() || copy > extra.len() || out.len() + add + copy > new_size {
+            return Err(MpqError::Corrupt("Patch control data out of range"));
+        }
+
+        for (i, &byte) in diff[..add].iter().enumerate() {
+            let old = usize::try_from(old_offset + i as i64)
+                .ok()
+                .and_then(|i| base.get(i))
+                .copied()
+                .unwrap_or(0);
+
+            out.push(byte.wrapping_add(old));
+        }
+
+        out.extend_from_slice(&extra[..copy]);
+
+        diff = &diff[add..];
+        extra = &extra[copy..];
+        old_offset += add as i64 + seek;
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_patch, unpack_rle, PATCH_HEADER_SIZE};
+    use crate::error::MpqError;
+    use crate::fixture;
+
+    #[test]
+    fn rle() {
+        let data = [9, 0, 0, 0, 0x81, b'a', b'b', 0x02, 0x80, b'c'];
+
+        assert_eq!(b"ab\0\0\0c\0\0\0".to_vec(), unpack_rle(&data, 9).unwrap());
+        assert!(matches!(unpack_rle(&data, 8), Err(MpqError::Corrupt(_))));
+    }
+
+    #[test]
+    fn apply() {
+        let base = b"the quick brown fox".to_vec();
+        let new = b"the quick red fox jumps over".to_vec();
+
+        for &bsdiff in &[false, true] {
+            let patch = fixture::patch(&base, &new, bsdiff);
+
+            assert_eq!(new, apply_patch(&base, &patch).unwrap());
+            assert!(apply_patch(&new, &patch).is_err());
+        }
+    }
+
+    #[test]
+    fn rle_size_too_large() {
+        let base = b"the quick brown fox".to_vec();
+        let mut patch = fixture::patch(&base, b"the quick red fox", true);
+
+        // four gigabytes claimed by a few bytes of run length encoded data
+        let rle = [0xF0, 0xFF, 0xFF, 0xFF, 0x81, b'a', b'b', 0x7F];
+
+        patch.truncate(PATCH_HEADER_SIZE);
+        patch.extend_from_slice(&rle);
+        patch[0x3C..0x40].copy_from_slice(&(12 + rle.len() as u32).to_le_bytes());
+
+        assert!(matches!(
+            apply_patch(&base, &patch),
+            Err(MpqError::Corrupt(e)) if e.contains("larger")
+        ));
+    }
+}
@@ -0,0 +1,55 @@
+use md5::{Digest, Md5};
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+
+// modulus of the 512-bit key blizzard signs (signature) files with
+const BLIZZARD_WEAK_PUBLIC_KEY: [u8; 64] = [
+    0x92, 0x62, 0x77, 0x04, 0xbf, 0xb8, 0x82, 0xcc, 0x05, 0x23, 0xb9, 0x0c, 0xb1, 0xac, 0x04, 0x59,
+    0x27, 0x21, 0x75, 0x96, 0x8d, 0x02, 0x5e, 0xda, 0x47, 0xdd, 0x7c, 0x49, 0x37, 0x1b, 0xf8, 0xfa,
+    0xeb, 0x0e, 0x0a, 0x92, 0x16, 0x75, 0x57, 0xad, 0x51, 0xb7, 0x8c, 0xcb, 0x68, 0xc5, 0x42, 0x62,
+    0x90, 0xee, 0x9f, 0xb1, 0x4b, 0xc1, 0x18, 0xe4, 0x30, 0x34, 0x9e, 0xa4, 0xed, 0x6a, 0xd8, 0x37,
+];
+
+pub const WEAK_SIGNATURE_SIZE: usize = 64;
+
+/// Outcome of verifying an archive's digital signature
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signature {
+    /// archive carries no signature
+    Unsigned,
+    /// signature matches the archive contents
+    Valid,
+    /// signature does not match the archive contents
+    Invalid,
+}
+
+pub fn blizzard_weak_key() -> RsaPublicKey {
+    RsaPublicKey::new(
+        BigUint::from_bytes_be(&BLIZZARD_WEAK_PUBLIC_KEY),
+        BigUint::from(0x10001u32),
+    )
+    .unwrap()
+}
+
+// verify a weak signature over archive data, the bytes of the (signature) file
+// itself are hashed as zeros
+pub fn verify_weak(
+    key: &RsaPublicKey,
+    archive: &[u8],
+    exclude: (usize, usize),
+    signature: &[u8],
+) -> Signature {
+    let mut md5 = Md5::new();
+
+    md5.update(&archive[..exclude.0]);
+    md5.update(vec![0; exclude.1 - exclude.0]);
+    md5.update(&archive[exclude.1..]);
+
+    // signature is stored little endian
+    let mut signature = signature.to_vec();
+    signature.reverse();
+
+    match key.verify(Pkcs1v15Sign::new::<Md5>(), &md5.finalize(), &signature) {
+        Ok(_) => Signature::Valid,
+        Err(_) => Signature::Invalid,
+    }
+}
@@ -0,0 +1,164 @@
+use crate::error::MpqError;
+use byteorder::{ByteOrder, LittleEndian};
+use std::convert::TryFrom;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+
+// sample layout taken from a fmt chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct WaveFormat {
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+}
+
+// find the fmt and data chunks of a RIFF/WAVE file
+fn parse(src: &[u8]) -> Result<(WaveFormat, &[u8]), MpqError> {
+    if src.len() < 12 || &src[0..4] != b"RIFF" || &src[8..12] != b"WAVE" {
+        return Err(MpqError::Corrupt("Not a RIFF/WAVE file"));
+    }
+
+    let mut format = None;
+    let mut data = None;
+    let mut pos = 12;
+
+    while pos + 8 <= src.len() {
+        let id = &src[pos..pos + 4];
+        let len = LittleEndian::read_u32(&src[pos + 4..]) as usize;
+        let start = pos + 8;
+
+        // the last chunk is often truncated by its writer
+        let end = start.saturating_add(len).min(src.len());
+        let chunk = &src[start..end];
+
+        if id == b"fmt " && chunk.len() >= 16 {
+            format = Some(WaveFormat {
+                channels: LittleEndian::read_u16(&chunk[2..]),
+                sample_rate: LittleEndian::read_u32(&chunk[4..]),
+                bits_per_sample: LittleEndian::read_u16(&chunk[14..]),
+            });
+        } else if id == b"data" {
+            data = Some(chunk);
+        }
+
+        // chunks are padded to an even length
+        pos = end + (len & 1);
+    }
+
+    match (format, data) {
+        (Some(format), Some(data)) => Ok((format, data)),
+        (None, _) => Err(MpqError::Corrupt("WAVE file has no fmt chunk")),
+        (_, None) => Err(MpqError::Corrupt("WAVE file has no data chunk")),
+    }
+}
+
+// rewrite a decoded WAVE file as plain PCM with a minimal header, dropping any
+// chunk other than fmt and data. adpcm_channels is the channel count of a file
+// whose samples were packed with adpcm, which decodes to 16 bit samples whatever
+// the stored header says
+pub(crate) fn to_wav(src: &[u8], adpcm_channels: Option<u16>) -> Result<Vec<u8>, MpqError> {
+    let (mut format, data) = parse(src)?;
+
+    if let Some(channels) = adpcm_channels {
+        format.channels = channels;
+        format.bits_per_sample = 16;
+    }
+
+    // the fmt chunk is the file's to claim, sizes that overflow are rejected
+    let invalid = || MpqError::Corrupt("Invalid WAVE format");
+    let block_align = format
+        .channels
+        .checked_mul(format.bits_per_sample / 8)
+        .ok_or_else(invalid)?;
+    let byte_rate = format
+        .sample_rate
+        .checked_mul(u32::from(block_align))
+        .ok_or_else(invalid)?;
+    let data_size = u32::try_from(data.len()).map_err(|_| invalid())?;
+    let riff_size = data_size.checked_add(36).ok_or_else(invalid)?;
+
+    let mut out = vec![0; 44];
+    out[0..4].copy_from_slice(b"RIFF");
+    LittleEndian::write_u32(&mut out[4..], riff_size);
+    out[8..12].copy_from_slice(b"WAVE");
+    out[12..16].copy_from_slice(b"fmt ");
+    LittleEndian::write_u32(&mut out[16..], 16);
+    LittleEndian::write_u16(&mut out[20..], WAVE_FORMAT_PCM);
+    LittleEndian::write_u16(&mut out[22..], format.channels);
+    LittleEndian::write_u32(&mut out[24..], format.sample_rate);
+    LittleEndian::write_u32(&mut out[28..], byte_rate);
+    LittleEndian::write_u16(&mut out[32..], block_align);
+    LittleEndian::write_u16(&mut out[34..], format.bits_per_sample);
+    out[36..40].copy_from_slice(b"data");
+    LittleEndian::write_u32(&mut out[40..], data_size);
+    out.extend_from_slice(data);
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, to_wav, WaveFormat};
+    use crate::fixture::wave;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    #[test]
+    fn parse_wave() {
+        let wave = wave(b"samples!");
+        let (format, data) = parse(&wave).unwrap();
+
+        assert_eq!(
+            WaveFormat {
+                channels: 2,
+                sample_rate: 22050,
+                bits_per_sample: 16
+            },
+            format
+        );
+        assert_eq!(b"samples!", data);
+        assert!(parse(b"RIFF\0\0\0\0WAVEdata\0\0\0\0").is_err());
+        assert!(parse(b"not a wave file").is_err());
+    }
+
+    #[test]
+    fn canonical_header() {
+        let out = to_wav(&wave(b"samples!"), None).unwrap();
+
+        assert_eq!(44 + 8, out.len());
+        assert_eq!(44, LittleEndian::read_u32(&out[4..]));
+        assert_eq!(16, LittleEndian::read_u32(&out[16..]));
+        assert_eq!(22050 * 4, LittleEndian::read_u32(&out[28..]));
+        assert_eq!(4, LittleEndian::read_u16(&out[32..]));
+        assert_eq!(b"samples!", &out[44..]);
+    }
+
+    #[test]
+    fn adpcm_channels() {
+        // a header rewritten to 8 bit mono
+        let mut src = wave(b"samples!");
+        LittleEndian::write_u16(&mut src[22..], 1);
+        LittleEndian::write_u16(&mut src[34..], 8);
+
+        let out = to_wav(&src, Some(2)).unwrap();
+
+        assert_eq!(2, LittleEndian::read_u16(&out[22..]));
+        assert_eq!(22050 * 4, LittleEndian::read_u32(&out[28..]));
+        assert_eq!(4, LittleEndian::read_u16(&out[32..]));
+        assert_eq!(16, LittleEndian::read_u16(&out[34..]));
+        assert_eq!(b"samples!", &out[44..]);
+    }
+
+    #[test]
+    fn invalid_format() {
+        let mut src = wave(b"samples!");
+        LittleEndian::write_u16(&mut src[22..], 0x100);
+        LittleEndian::write_u16(&mut src[34..], 0x1000);
+
+        assert!(to_wav(&src, None).is_err());
+
+        let mut src = wave(b"samples!");
+        LittleEndian::write_u32(&mut src[24..], u32::MAX);
+
+        assert!(to_wav(&src, None).is_err());
+    }
+}
@@ -0,0 +1,604 @@
+use crate::archive::{FILE_COMPRESS, FILE_ENCRYPTED, FILE_EXISTS, FILE_FIX_KEY, FILE_IMPLODE};
+use crate::compression::{implode, CompressionMethod};
+use crate::crypt::{encrypt, hash_string};
+use crate::error::MpqError;
+use byteorder::{ByteOrder, LittleEndian};
+#[cfg(feature = "zlib")]
+use flate2::write::ZlibEncoder;
+#[cfg(feature = "zlib")]
+use flate2::Compression;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Error, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_SIZE_V1: u32 = 0x20;
+const HASH_ENTRY_EMPTY: u32 = 0xFFFFFFFF;
+#[cfg(feature = "zlib")]
+const COMPRESSION_ZLIB: u8 = 0x02;
+
+/// Settings for a file added to an [`ArchiveWriter`]
+#[derive(Debug, Clone, Copy)]
+pub struct FileOptions {
+    method: CompressionMethod,
+    encrypt: bool,
+    fix_key: bool,
+}
+
+impl FileOptions {
+    pub fn new() -> FileOptions {
+        FileOptions {
+            method: CompressionMethod::Zlib,
+            encrypt: false,
+            fix_key: false,
+        }
+    }
+
+    // zlib compress each sector, or store the file when false
+    pub fn compress(mut self, compress: bool) -> FileOptions {
+        self.method = if compress {
+            CompressionMethod::Zlib
+        } else {
+            CompressionMethod::None
+        };
+        self
+    }
+
+    pub fn method(mut self, method: CompressionMethod) -> FileOptions {
+        self.method = method;
+        self
+    }
+
+    // encrypt the sectors and sector offset table with a key derived from the
+    // basename of the file
+    pub fn encrypt(mut self, encrypt: bool) -> FileOptions {
+        self.encrypt = encrypt;
+        self
+    }
+
+    // alter the key of an encrypted file by its offset and size, so files sharing
+    // a basename get different keys
+    pub fn fix_key(mut self, fix_key: bool) -> FileOptions {
+        self.fix_key = fix_key;
+        self
+    }
+}
+
+impl Default for FileOptions {
+    fn default() -> FileOptions {
+        FileOptions::new()
+    }
+}
+
+struct Entry {
+    name: Option<String>,
+    // hashed into the table by name, otherwise kept_hashes has its entries
+    hashed: bool,
+    offset: u32,
+    packed_size: u32,
+    unpacked_size: u32,
+    flags: u32,
+}
+
+// hash table entry copied from another archive into the same position of a table
+// of the same size, see add_raw
+pub(crate) struct KeptHash {
+    pub slot: usize,
+    pub hash_a: u32,
+    pub hash_b: u32,
+    pub locale: u16,
+    pub platform: u16,
+    // HASH_ENTRY_DELETED keeps the slot in use without a file
+    pub block_index: u32,
+}
+
+/// Creates a new MPQ archive
+///
+/// File data is written as soon as it is added, the hash and block tables
+/// together with a generated `(listfile)` are written by
+/// [`finish`](ArchiveWriter::finish).
+///
+/// Calling `finish` is required. A writer dropped without it leaves the header
+/// zeroed, so the incomplete archive fails to open instead of reading as an
+/// empty one.
+#[must_use = "the archive is incomplete until finish is called"]
+pub struct ArchiveWriter<W: Write + Seek> {
+    writer: W,
+    start: u64,
+    sector_size_shift: u16,
+    entries: Vec<Entry>,
+    names: HashSet<String>,
+    // size of the hash table kept_hashes are positioned in, see keep_hash_table
+    hash_count: Option<usize>,
+    kept_hashes: Vec<KeptHash>,
+}
+
+impl ArchiveWriter<fs::File> {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<ArchiveWriter<fs::File>, MpqError> {
+        ArchiveWriter::new(fs::File::create(path)?)
+    }
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    // start an archive at the current position of writer
+    pub fn new(mut writer: W) -> Result<ArchiveWriter<W>, MpqError> {
+        let start = writer.stream_position()?;
+
+        // header is filled in once the tables are written
+        writer.write_all(&[0; HEADER_SIZE_V1 as usize])?;
+
+        Ok(ArchiveWriter {
+            writer,
+            start,
+            sector_size_shift: 3,
+            entries: Vec::new(),
+            names: HashSet::new(),
+            hash_count: None,
+            kept_hashes: Vec::new(),
+        })
+    }
+
+    // sector size of files added from now on is 512 << shift
+    pub(crate) fn set_sector_size_shift(&mut self, shift: u16) {
+        self.sector_size_shift = shift;
+    }
+
+    // write a hash table of count entries holding kept, the entries of another
+    // archive's table at their positions, so probing finds them as it did there.
+    // files added by name are hashed into the remaining free entries
+    pub(crate) fn keep_hash_table(&mut self, count: usize, kept: Vec<KeptHash>) {
+        self.hash_count = Some(count);
+        self.kept_hashes = kept;
+    }
+
+    // offset the next file is written at, relative to the start of the archive
+    pub(crate) fn next_offset(&mut self) -> Result<u32, MpqError> {
+        Ok((self.writer.stream_position()? - self.start) as u32)
+    }
+
+    // add a block copied from another archive as it was stored there, returns its
+    // index in the block table. a file without a name is only found through
+    // keep_hash_table, a named one is also hashed by name unless hashed is false
+    pub(crate) fn add_raw(
+        &mut self,
+        name: Option<&str>,
+        hashed: bool,
+        data: &[u8],
+        unpacked_size: u32,
+        flags: u32,
+    ) -> Result<u32, MpqError> {
+        let offset = self.next_offset()?;
+
+        if let Some(name) = name {
+            self.names.insert(name.to_uppercase());
+        }
+
+        self.writer.write_all(data)?;
+        self.entries.push(Entry {
+            name: name.map(String::from),
+            hashed: hashed && name.is_some(),
+            offset,
+            packed_size: data.len() as u32,
+            unpacked_size,
+            flags,
+        });
+
+        Ok(self.entries.len() as u32 - 1)
+    }
+
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        data: &[u8],
+        options: FileOptions,
+    ) -> Result<(), MpqError> {
+        if name.is_empty() {
+            return Err(MpqError::InvalidFileName(String::from(name)));
+        }
+
+        // only zlib and implode have encoders to write with
+        match options.method {
+            CompressionMethod::None | CompressionMethod::Zlib | CompressionMethod::Implode => {}
+            method => return Err(MpqError::UnsupportedCompression(method.mask())),
+        }
+
+        // the key is derived from the basename, an empty one can't be looked up
+        let basename = name.split(&['\\', '/'][..]).next_back().unwrap_or("");
+
+        if options.encrypt && basename.is_empty() {
+            return Err(MpqError::InvalidFileName(String::from(name)));
+        }
+
+        // names are case insensitive
+        if !self.names.insert(name.to_uppercase()) {
+            return Err(MpqError::AlreadyExists(String::from(name)));
+        }
+
+        let offset = (self.writer.stream_position()? - self.start) as u32;
+        let mut flags = FILE_EXISTS;
+        let mut packed_size = 0;
+        let mut key = 0;
+
+        if options.encrypt {
+            flags |= FILE_ENCRYPTED;
+            key = hash_string(basename, 0x300);
+
+            if options.fix_key {
+                flags |= FILE_FIX_KEY;
+                key = key.wrapping_add(offset) ^ data.len() as u32;
+            }
+        }
+
+        // empty files have no sectors at all
+        if !data.is_empty() {
+            let sector_size = 512usize << self.sector_size_shift;
+            let mut sectors: Vec<Vec<u8>> = data
+                .chunks(sector_size)
+                .map(|chunk| encode_sector(chunk, options.method))
+                .collect();
+
+            // a file without a single packed sector is stored
+            let packed = sectors
+                .iter()
+                .zip(data.chunks(sector_size))
+                .any(|(sector, chunk)| sector.len() < chunk.len());
+            let method_flag = match options.method {
+                CompressionMethod::None => 0,
+                CompressionMethod::Implode => FILE_IMPLODE,
+                _ => FILE_COMPRESS,
+            };
+
+            if packed && method_flag != 0 {
+                flags |= method_flag;
+
+                let mut offset_table = vec![0; (sectors.len() + 1) * 4];
+                let mut position = offset_table.len() as u32;
+
+                for (i, sector) in sectors.iter().enumerate() {
+                    LittleEndian::write_u32(&mut offset_table[i * 4..], position);
+                    position += sector.len() as u32;
+                }
+
+                LittleEndian::write_u32(&mut offset_table[sectors.len() * 4..], position);
+
+                if options.encrypt {
+                    encrypt(&mut offset_table, key.wrapping_sub(1));
+                }
+
+                self.writer.write_all(&offset_table)?;
+                packed_size += offset_table.len() as u32;
+            }
+
+            for (i, sector) in sectors.iter_mut().enumerate() {
+                if options.encrypt {
+                    encrypt(sector, key.wrapping_add(i as u32));
+                }
+
+                self.writer.write_all(sector)?;
+                packed_size += sector.len() as u32;
+            }
+        }
+
+        self.entries.push(Entry {
+            name: Some(String::from(name)),
+            hashed: true,
+            offset,
+            packed_size,
+            unpacked_size: data.len() as u32,
+            flags,
+        });
+
+        Ok(())
+    }
+
+    // write the (listfile), hash and block tables and the header, returns the
+    // underlying writer
+    pub fn finish(mut self) -> Result<W, MpqError> {
+        // a name kept in several locales is listed once
+        let mut listed = HashSet::new();
+        let names: Vec<&str> = self
+            .entries
+            .iter()
+            .filter_map(|e| e.name.as_deref())
+            .filter(|name| listed.insert(name.to_uppercase()))
+            .collect();
+        let listfile = names.join("\r\n").into_bytes();
+
+        if !self.names.contains("(LISTFILE)") {
+            self.add_file("(listfile)", &listfile, FileOptions::new())?;
+        }
+
+        let hashed = self.entries.iter().filter(|e| e.hashed).count();
+        let hash_count = match self.hash_count {
+            Some(v) => v,
+            None => (hashed * 2).next_power_of_two().max(4),
+        };
+        let mut hash_table = vec![0xFF; hash_count * 16];
+
+        for kept in &self.kept_hashes {
+            let slot = &mut hash_table[kept.slot * 16..(kept.slot + 1) * 16];
+            LittleEndian::write_u32(&mut slot[0..], kept.hash_a);
+            LittleEndian::write_u32(&mut slot[4..], kept.hash_b);
+            LittleEndian::write_u16(&mut slot[8..], kept.locale);
+            LittleEndian::write_u16(&mut slot[10..], kept.platform);
+            LittleEndian::write_u32(&mut slot[12..], kept.block_index);
+        }
+
+        let mut free = (0..hash_count)
+            .filter(|&i| LittleEndian::read_u32(&hash_table[i * 16 + 12..]) == HASH_ENTRY_EMPTY)
+            .count();
+
+        for (block_index, entry) in self.entries.iter().enumerate() {
+            let name = match &entry.name {
+                Some(v) if entry.hashed => v,
+                _ => continue,
+            };
+
+            // a full table would leave every lookup of a missing name probing forever
+            if free <= 1 {
+                return Err(Error::other("Hash table is full").into());
+            }
+
+            let mut index = hash_string(name, 0) as usize & (hash_count - 1);
+
+            while LittleEndian::read_u32(&hash_table[index * 16 + 12..]) != HASH_ENTRY_EMPTY {
+                index = (index + 1) & (hash_count - 1);
+            }
+
+            let slot = &mut hash_table[index * 16..(index + 1) * 16];
+            LittleEndian::write_u32(&mut slot[0..], hash_string(name, 0x100));
+            LittleEndian::write_u32(&mut slot[4..], hash_string(name, 0x200));
+            LittleEndian::write_u16(&mut slot[8..], 0);
+            LittleEndian::write_u16(&mut slot[10..], 0);
+            LittleEndian::write_u32(&mut slot[12..], block_index as u32);
+            free -= 1;
+        }
+
+        let mut block_table = vec![0; self.entries.len() * 16];
+
+        for (i, entry) in self.entries.iter().enumerate() {
+            let block = &mut block_table[i * 16..(i + 1) * 16];
+            LittleEndian::write_u32(&mut block[0..], entry.offset);
+            LittleEndian::write_u32(&mut block[4..], entry.packed_size);
+            LittleEndian::write_u32(&mut block[8..], entry.unpacked_size);
+            LittleEndian::write_u32(&mut block[12..], entry.flags);
+        }
+
+        encrypt(&mut hash_table, hash_string("(hash table)", 0x300));
+        encrypt(&mut block_table, hash_string("(block table)", 0x300));
+
+        let hash_table_offset = (self.writer.stream_position()? - self.start) as u32;
+        self.writer.write_all(&hash_table)?;
+        let block_table_offset = (self.writer.stream_position()? - self.start) as u32;
+        self.writer.write_all(&block_table)?;
+        let archive_size = (self.writer.stream_position()? - self.start) as u32;
+
+        let mut header = [0; HEADER_SIZE_V1 as usize];
+        header[0..4].copy_from_slice(b"MPQ\x1A");
+        LittleEndian::write_u32(&mut header[0x04..], HEADER_SIZE_V1);
+        LittleEndian::write_u32(&mut header[0x08..], archive_size);
+        LittleEndian::write_u16(&mut header[0x0C..], 0);
+        LittleEndian::write_u16(&mut header[0x0E..], self.sector_size_shift);
+        LittleEndian::write_u32(&mut header[0x10..], hash_table_offset);
+        LittleEndian::write_u32(&mut header[0x14..], block_table_offset);
+        LittleEndian::write_u32(&mut header[0x18..], hash_count as u32);
+        LittleEndian::write_u32(&mut header[0x1C..], self.entries.len() as u32);
+
+        self.writer.seek(SeekFrom::Start(self.start))?;
+        self.writer.write_all(&header)?;
+        self.writer
+            .seek(SeekFrom::Start(self.start + u64::from(archive_size)))?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+// compress a sector, keeping the raw bytes if compression doesn't save space
+fn encode_sector(data: &[u8], method: CompressionMethod) -> Vec<u8> {
+    let packed = match method {
+        CompressionMethod::Zlib => deflate(data),
+        CompressionMethod::Implode => Some(implode(data)),
+        _ => None,
+    };
+
+    match packed {
+        Some(packed) if packed.len() < data.len() => packed,
+        _ => data.to_vec(),
+    }
+}
+
+// zlib stream following its mask byte
+#[cfg(feature = "zlib")]
+fn deflate(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(vec![COMPRESSION_ZLIB], Compression::default());
+
+    encoder.write_all(data).ok()?;
+    encoder.finish().ok()
+}
+
+#[cfg(not(feature = "zlib"))]
+fn deflate(_data: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ArchiveWriter, CompressionMethod, FileOptions};
+    use crate::archive::Archive;
+    use crate::error::MpqError;
+    use crate::fixture;
+    use std::io::Cursor;
+
+    fn read(archive: &mut Archive, name: &str) -> Vec<u8> {
+        let file = archive.open_file(name).unwrap();
+
+        file.read_to_vec(archive).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let large: Vec<u8> = (0..20000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let path = fixture::temp_dir("writer-round-trip").join("out.mpq");
+
+        let mut writer = ArchiveWriter::create(&path).unwrap();
+        writer
+            .add_file("readme.txt", b"hello world", FileOptions::new())
+            .unwrap();
+        writer
+            .add_file("Units\\large.dat", &large, FileOptions::new())
+            .unwrap();
+        writer
+            .add_file(
+                "raw.bin",
+                &large[..1000],
+                FileOptions::new().compress(false),
+            )
+            .unwrap();
+        writer.finish().unwrap();
+
+        let mut archive = Archive::open(&path).unwrap();
+
+        assert_eq!(b"hello world".to_vec(), read(&mut archive, "readme.txt"));
+        assert_eq!(large, read(&mut archive, "Units\\large.dat"));
+        assert_eq!(large[..1000].to_vec(), read(&mut archive, "raw.bin"));
+        assert_eq!(
+            b"readme.txt\r\nUnits\\large.dat\r\nraw.bin".to_vec(),
+            read(&mut archive, "(listfile)")
+        );
+    }
+
+    #[test]
+    fn compression_methods() {
+        let text = b"the quick brown fox jumps over the lazy dog. ".repeat(300);
+        let mut state = 0x2545F491u32;
+        let noise: Vec<u8> = (0..3000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect();
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new())).unwrap();
+
+        for &(name, method) in &[
+            ("none.txt", CompressionMethod::None),
+            ("zlib.txt", CompressionMethod::Zlib),
+            ("implode.txt", CompressionMethod::Implode),
+        ] {
+            let options = FileOptions::new().method(method);
+
+            writer.add_file(name, &text, options).unwrap();
+            writer
+                .add_file(&format!("noise.{}", name), &noise, options)
+                .unwrap();
+        }
+
+        assert!(matches!(
+            writer.add_file(
+                "bzip2.txt",
+                &text,
+                FileOptions::new().method(CompressionMethod::Bzip2)
+            ),
+            Err(MpqError::UnsupportedCompression(0x10))
+        ));
+
+        let buf = writer.finish().unwrap().into_inner();
+        let mut archive = Archive::load(buf).unwrap();
+
+        for name in &["none.txt", "zlib.txt", "implode.txt"] {
+            let noise_name = format!("noise.{}", name);
+
+            assert_eq!(text, read(&mut archive, name));
+            assert_eq!(noise, read(&mut archive, &noise_name));
+            assert!(!archive.open_file(&noise_name).unwrap().is_compressed());
+        }
+
+        assert!(!archive.open_file("none.txt").unwrap().is_compressed());
+        assert!(archive.open_file("implode.txt").unwrap().is_compressed());
+        assert!(!archive.contains("bzip2.txt"));
+        #[cfg(feature = "zlib")]
+        assert!(archive.open_file("zlib.txt").unwrap().is_compressed());
+    }
+
+    #[test]
+    fn encrypted() {
+        let text = b"the quick brown fox jumps over the lazy dog. ".repeat(300);
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new())).unwrap();
+
+        for &(name, fix_key) in &[("Units\\a.txt", false), ("Units\\b.txt", true)] {
+            for &compress in &[false, true] {
+                let name = format!("{}{}", name, if compress { ".z" } else { "" });
+                let options = FileOptions::new()
+                    .compress(compress)
+                    .encrypt(true)
+                    .fix_key(fix_key);
+
+                writer.add_file(&name, &text, options).unwrap();
+            }
+        }
+
+        assert!(writer
+            .add_file("Units\\", b"a", FileOptions::new().encrypt(true))
+            .is_err());
+
+        let buf = writer.finish().unwrap().into_inner();
+
+        // no plaintext is left in the archive
+        assert!(!buf.windows(9).any(|w| w == b"quick bro"));
+
+        let mut archive = Archive::load(buf).unwrap();
+
+        for name in &[
+            "Units\\a.txt",
+            "Units\\a.txt.z",
+            "Units\\b.txt",
+            "Units\\b.txt.z",
+        ] {
+            let file = archive.open_file(name).unwrap();
+
+            assert!(file.is_encrypted());
+            assert_eq!(text, file.read_to_vec(&mut archive).unwrap());
+        }
+    }
+
+    #[test]
+    fn drop_without_finish() {
+        let path = fixture::temp_dir("writer-drop").join("out.mpq");
+
+        let mut writer = ArchiveWriter::create(&path).unwrap();
+        writer
+            .add_file("readme.txt", b"hello world", FileOptions::new())
+            .unwrap();
+        drop(writer);
+
+        assert!(matches!(Archive::open(&path), Err(MpqError::InvalidMagic)));
+
+        let mut writer = ArchiveWriter::create(&path).unwrap();
+        writer
+            .add_file("readme.txt", b"hello world", FileOptions::new())
+            .unwrap();
+        writer.finish().unwrap();
+
+        let mut archive = Archive::open(&path).unwrap();
+
+        assert_eq!(b"hello world".to_vec(), read(&mut archive, "readme.txt"));
+    }
+
+    #[test]
+    fn duplicate_name() {
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new())).unwrap();
+        writer.add_file("a.txt", b"a", FileOptions::new()).unwrap();
+
+        assert!(matches!(
+            writer.add_file("A.TXT", b"b", FileOptions::new()),
+            Err(MpqError::AlreadyExists(_))
+        ));
+
+        let buf = writer.finish().unwrap().into_inner();
+        let mut archive = Archive::load(buf).unwrap();
+
+        assert_eq!(b"a".to_vec(), read(&mut archive, "a.txt"));
+    }
+}
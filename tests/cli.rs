@@ -0,0 +1,191 @@
+use std::env;
+use std::fs;
+use std::process::{self, Command};
+
+const MPQ: &str = env!("CARGO_BIN_EXE_mpq");
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn run(args: &[&str]) -> (bool, String) {
+    let output = Command::new(MPQ).args(args).output().unwrap();
+
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+#[test]
+fn list_long() {
+    let (success, stdout) = run(&["--list-long", &fixture("sample.mpq")]);
+    let lines: Vec<&str> = stdout.lines().collect();
+
+    assert!(success);
+    assert_eq!(4, lines.len());
+    assert_eq!(
+        vec!["Size", "Packed", "Ratio", "Flags", "Name"],
+        lines[0].split_whitespace().collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec!["3000", "109", "3.6%", "ce-", "Units\\Human\\Footman.mdx"],
+        lines[2].split_whitespace().collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec!["600", "600", "100.0%", "--s", "data.bin"],
+        lines[3].split_whitespace().collect::<Vec<_>>()
+    );
+}
+
+#[test]
+#[cfg(feature = "bzip2")]
+fn list_bzip2_listfile() {
+    let (success, stdout) = run(&["--list", &fixture("bzip2list.mpq")]);
+    let names: Vec<String> = (0..12)
+        .map(|i| format!("Units\\Human\\Footman{}.mdx", i))
+        .collect();
+
+    assert!(success);
+    assert_eq!(names, stdout.lines().collect::<Vec<_>>());
+}
+
+#[test]
+fn list_corrupt_header() {
+    let path = env::temp_dir().join(format!("mpq-cli-{}-corrupt-header.mpq", process::id()));
+    let mut buf = fs::read(fixture("sample.mpq")).unwrap();
+
+    // hash table size that isn't a power of two
+    buf[0x18..0x1C].copy_from_slice(&3u32.to_le_bytes());
+    fs::write(&path, buf).unwrap();
+
+    let (success, stdout) = run(&["--list", path.to_str().unwrap()]);
+
+    assert!(!success);
+    assert!(stdout.starts_with("Corrupt archive"));
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn list_long_without_listfile() {
+    let (success, stdout) = run(&["-L", &fixture("nolist.mpq")]);
+    let names: Vec<&str> = stdout
+        .lines()
+        .skip(1)
+        .map(|line| line.split_whitespace().last().unwrap())
+        .collect();
+
+    assert!(success);
+    assert_eq!(
+        vec!["File00000000.xxx", "File00000001.xxx", "File00000002.xxx"],
+        names
+    );
+}
+
+#[test]
+#[cfg(feature = "zlib")]
+fn extract_all() {
+    let dest = env::temp_dir().join(format!("mpq-cli-{}-extract-all", process::id()));
+    let _ = fs::remove_dir_all(&dest);
+
+    let (success, stdout) = run(&[
+        "--extract-all",
+        dest.to_str().unwrap(),
+        &fixture("sample.mpq"),
+    ]);
+
+    assert!(success);
+    assert_eq!("extracted 3 files, 0 failed\n", stdout);
+    assert_eq!(1880, fs::read(dest.join("readme.txt")).unwrap().len());
+    assert_eq!(
+        (0..3000u32).map(|i| (i % 7) as u8).collect::<Vec<u8>>(),
+        fs::read(dest.join("Units").join("Human").join("Footman.mdx")).unwrap()
+    );
+    assert_eq!(600, fs::read(dest.join("data.bin")).unwrap().len());
+
+    fs::remove_dir_all(&dest).unwrap();
+}
+
+#[test]
+#[cfg(feature = "zlib")]
+fn verify() {
+    let (success, stdout) = run(&["--verify", &fixture("checked.mpq")]);
+
+    assert!(success);
+    assert!(stdout.contains("OK      a.txt\n"));
+    assert!(stdout.ends_with("3 files ok, 0 failed\n"));
+}
+
+#[test]
+#[cfg(feature = "zlib")]
+fn verify_corrupted() {
+    let path = env::temp_dir().join(format!("mpq-cli-{}-corrupted.mpq", process::id()));
+    let mut buf = fs::read(fixture("checked.mpq")).unwrap();
+
+    // first data sector of a.txt
+    buf[0x30] ^= 0xFF;
+    fs::write(&path, buf).unwrap();
+
+    let (success, stdout) = run(&["--verify", path.to_str().unwrap()]);
+
+    assert!(!success);
+    assert!(stdout.contains("FAILED  a.txt"));
+    assert!(stdout.ends_with("2 files ok, 1 failed\n"));
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+#[cfg(feature = "zlib")]
+fn verify_tables() {
+    let path = env::temp_dir().join(format!("mpq-cli-{}-tables.mpq", process::id()));
+    let mut buf = fs::read(fixture("v4.mpq")).unwrap();
+
+    let (success, stdout) = run(&["--verify", &fixture("v4.mpq")]);
+
+    assert!(success);
+    assert!(stdout.ends_with("2 files ok, 0 failed\n"));
+
+    // md5 of the HET table in the v4 header
+    buf[0xB0] ^= 0xFF;
+    fs::write(&path, buf).unwrap();
+
+    let (success, stdout) = run(&["--verify", path.to_str().unwrap()]);
+
+    assert!(!success);
+    assert!(stdout.contains("FAILED  (tables): MD5 mismatch of the HET table\n"));
+    assert!(stdout.ends_with("2 files ok, 1 failed\n"));
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn info() {
+    let (success, stdout) = run(&["--info", &fixture("sample.mpq")]);
+    let fields: Vec<(&str, &str)> = stdout
+        .lines()
+        .map(|line| {
+            let (key, value) = line.split_at(line.find(':').unwrap());
+            (key, value[1..].trim())
+        })
+        .collect();
+
+    assert!(success);
+    assert_eq!(
+        vec![
+            ("Format version", "0"),
+            ("Header size", "32"),
+            ("Archive offset", "0"),
+            ("Archive size", "1140"),
+            ("Sector size", "1024"),
+            ("Hash table", "8 entries at 948"),
+            ("Block table", "4 entries at 1076"),
+            ("User data", "none"),
+            ("Files", "4 (4 named, 3 compressed, 1 encrypted)"),
+            ("Packed size", "916"),
+            ("Unpacked size", "5525"),
+        ],
+        fields
+    );
+}